@@ -0,0 +1,5 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // prost-build ships its own protoc, so nothing has to be installed
+    tonic_build::compile_protos("proto/racher.proto")?;
+    Ok(())
+}
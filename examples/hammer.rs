@@ -1,48 +1,78 @@
+use libracher::encoding::Encoding;
 use libracher::responses::Response;
 
 use futures::prelude::*;
 use futures::stream::FuturesUnordered;
 use rand::random;
+use reqwest::header::{ACCEPT, CONTENT_TYPE};
 use serde_json::json;
+use std::time::Instant;
 
 const URL: &'static str = "http://127.0.0.1:8085";
+const REQUESTS: usize = 10000;
 
-async fn get(client: &reqwest::Client) -> reqwest::Result<Response> {
-    client
+async fn decode(
+    response: reqwest::Response,
+    encoding: Encoding,
+) -> Result<Response, Box<dyn std::error::Error>> {
+    let bytes = response.bytes().await?;
+    Ok(encoding.decode(&bytes)?)
+}
+
+async fn get(
+    client: &reqwest::Client,
+    encoding: Encoding,
+) -> Result<Response, Box<dyn std::error::Error>> {
+    let response = client
         .post(format!("{}/get/testing", URL))
+        .header(ACCEPT, encoding.content_type())
         .send()
-        .await?
-        .json()
-        .await
+        .await?;
+    decode(response, encoding).await
 }
 
-async fn set(client: &reqwest::Client) -> reqwest::Result<Response> {
-    client
+async fn set(
+    client: &reqwest::Client,
+    encoding: Encoding,
+) -> Result<Response, Box<dyn std::error::Error>> {
+    let value = json!({"override": random::<usize>(), "list": [1, 2, 3], "name": "hammer"});
+    let response = client
         .post(format!("{}/set/testing", URL))
-        .json(&json!({"override": random::<usize>()}))
+        .header(CONTENT_TYPE, encoding.content_type())
+        .body(encoding.encode(&value)?)
         .send()
-        .await?
-        .json()
-        .await
+        .await?;
+    // the status response is always json
+    decode(response, Encoding::Json).await
 }
 
+/// `cargo run --example hammer -- msgpack` sends the values as messagepack,
+/// `json` (the default) and `cbor` are also supported
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let encoding = match std::env::args().nth(1).as_deref() {
+        None | Some("json") => Encoding::Json,
+        Some("msgpack") => Encoding::MessagePack,
+        Some("cbor") => Encoding::Cbor,
+        Some(other) => return Err(format!("unknown encoding '{}'", other).into()),
+    };
+
     let client = reqwest::Client::new();
     let reqs = FuturesUnordered::new();
     let reqs2 = FuturesUnordered::new();
     let reqs3 = FuturesUnordered::new();
 
-    for _ in 0..10000 {
-        reqs.push(get(&client));
+    let start = Instant::now();
+    for _ in 0..REQUESTS {
+        reqs.push(get(&client, encoding).boxed());
     }
 
-    for _ in 0..10000 {
-        reqs2.push(set(&client));
+    for _ in 0..REQUESTS {
+        reqs2.push(set(&client, encoding).boxed());
     }
 
-    for _ in 0..10000 {
-        reqs3.push(get(&client));
+    for _ in 0..REQUESTS {
+        reqs3.push(get(&client, encoding).boxed());
     }
 
     let responses = reqs
@@ -50,12 +80,20 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .chain(reqs3)
         .try_collect::<Vec<_>>()
         .await?;
+    let elapsed = start.elapsed();
 
     let mut a = None;
     for item in responses {
         a = Some(item);
     }
     println!("{:?}", a);
+    println!(
+        "{:?}: {} requests in {:.2?}, {:.0} requests/s",
+        encoding,
+        REQUESTS * 3,
+        elapsed,
+        (REQUESTS * 3) as f64 / elapsed.as_secs_f64()
+    );
 
     // futures::join!(reqs, reqs2);
     Ok(())
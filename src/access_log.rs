@@ -0,0 +1,159 @@
+use crate::responses::SlowLogEntry;
+use crate::sync::Arc;
+use crate::telemetry;
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use tower::{Layer, Service};
+use tracing::{info, info_span, Instrument};
+use warp::hyper::body::HttpBody;
+use warp::hyper::{Body, Request, Response};
+
+pub type SlowLogArc = Arc<Mutex<SlowLog>>;
+
+/// ring buffer with the most recent requests that took longer than the threshold
+#[derive(Debug)]
+pub struct SlowLog {
+    threshold: Duration,
+    capacity: usize,
+    entries: VecDeque<SlowLogEntry>,
+}
+
+impl SlowLog {
+    pub fn new(threshold: Duration, capacity: usize) -> Self {
+        SlowLog {
+            threshold,
+            capacity,
+            entries: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    pub fn to_arc(self) -> SlowLogArc {
+        Arc::new(Mutex::new(self))
+    }
+
+    pub fn record(&mut self, entry: SlowLogEntry, latency: Duration) {
+        if latency < self.threshold || self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+
+    /// the recorded requests, slowest first
+    pub fn slowest(&self) -> Vec<SlowLogEntry> {
+        let mut entries: Vec<SlowLogEntry> = self.entries.iter().cloned().collect();
+        entries.sort_by(|a, b| b.latency_ms.total_cmp(&a.latency_ms));
+        entries
+    }
+}
+
+impl Default for SlowLog {
+    fn default() -> Self {
+        SlowLog::new(Duration::from_millis(100), 128)
+    }
+}
+
+#[derive(Clone)]
+pub struct AccessLogLayer {
+    peer: SocketAddr,
+    slow_log: SlowLogArc,
+}
+
+impl AccessLogLayer {
+    pub fn new(peer: SocketAddr, slow_log: SlowLogArc) -> Self {
+        AccessLogLayer { peer, slow_log }
+    }
+}
+
+impl<S> Layer<S> for AccessLogLayer {
+    type Service = AccessLog<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        AccessLog {
+            inner,
+            peer: self.peer,
+            slow_log: self.slow_log.clone(),
+        }
+    }
+}
+
+/// logs every request with the `access` target and records slow ones in the slow log
+#[derive(Clone)]
+pub struct AccessLog<S> {
+    inner: S,
+    peer: SocketAddr,
+    slow_log: SlowLogArc,
+}
+
+impl<S> Service<Request<Body>> for AccessLog<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let start = Instant::now();
+        let method = req.method().to_string();
+        let path = req.uri().path().to_string();
+        let peer = self.peer;
+        let slow_log = self.slow_log.clone();
+
+        let span = info_span!(target: telemetry::TARGET, "request", method = %method, path = %path);
+        telemetry::follow_headers(&span, req.headers());
+        let future = span.in_scope(|| self.inner.call(req)).instrument(span);
+
+        Box::pin(async move {
+            let response = future.await?;
+            let latency = start.elapsed();
+            let latency_ms = latency.as_secs_f64() * 1000.0;
+            let status = response.status().as_u16();
+            // streamed bodies, like compressed ones, have no known size
+            let bytes = match response.body().size_hint().exact() {
+                Some(bytes) => bytes.to_string(),
+                None => String::from("-"),
+            };
+
+            info!(
+                target: "access",
+                method = %method,
+                path = %path,
+                status,
+                bytes = %bytes,
+                latency_us = latency.as_micros() as u64,
+                peer = %peer,
+                "request"
+            );
+
+            let entry = SlowLogEntry {
+                time: Utc::now(),
+                method,
+                path,
+                status,
+                latency_ms,
+                peer,
+            };
+            if let Ok(mut slow_log) = slow_log.lock() {
+                slow_log.record(entry, latency);
+            }
+
+            Ok(response)
+        })
+    }
+}
@@ -1,11 +1,19 @@
-use crate::config::RuntimeConfig;
+use crate::config::{ConfigFile, RuntimeConfig};
+use crate::rate_limit::PrefixLimit;
+use crate::routes::Consistency;
+use crate::telemetry;
 
+use std::collections::HashSet;
+use std::env;
+use std::error::Error;
 use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::str::FromStr;
 
 use structopt::StructOpt;
 use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
 use tracing_subscriber::EnvFilter;
 use url::Url;
 
@@ -49,11 +57,106 @@ fn parse_vec(input: &str) -> Result<Vec<Url>, url::ParseError> {
     Ok(addresses)
 }
 
+fn parse_prefix_limits(input: &str) -> Result<Vec<PrefixLimit>, String> {
+    input.split(',').map(PrefixLimit::from_str).collect()
+}
+
+/// the settings of the config file as (name in the file, name of the argument, env var)
+const FILE_SETTINGS: &[(&str, &str, &str)] = &[
+    ("address", "address", "RACHER_ADDRESS"),
+    ("neighbours", "neighbours", "RACHER_NEIGHBOURS"),
+    ("consensus", "consensus", "RACHER_CONSENSUS"),
+    ("admin_token", "admin-token", "RACHER_ADMIN_TOKEN"),
+    ("cluster_secret", "cluster-secret", "RACHER_CLUSTER_SECRET"),
+    ("resp_address", "resp-address", "RACHER_RESP_ADDRESS"),
+    (
+        "memcache_address",
+        "memcache-address",
+        "RACHER_MEMCACHE_ADDRESS",
+    ),
+    ("grpc_address", "grpc-address", "RACHER_GRPC_ADDRESS"),
+    (
+        "broadcast_concurrency",
+        "broadcast-concurrency",
+        "RACHER_BROADCAST_CONCURRENCY",
+    ),
+    (
+        "broadcast_timeout",
+        "broadcast-timeout",
+        "RACHER_BROADCAST_TIMEOUT",
+    ),
+    ("retry_attempts", "retry-attempts", "RACHER_RETRY_ATTEMPTS"),
+    ("retry_backoff", "retry-backoff", "RACHER_RETRY_BACKOFF"),
+    (
+        "max_concurrent_requests",
+        "max-concurrent-requests",
+        "RACHER_MAX_CONCURRENT_REQUESTS",
+    ),
+    (
+        "request_timeout",
+        "request-timeout",
+        "RACHER_REQUEST_TIMEOUT",
+    ),
+    ("backup.dir", "backup-dir", "RACHER_BACKUP_DIR"),
+    (
+        "backup.interval",
+        "backup-interval",
+        "RACHER_BACKUP_INTERVAL",
+    ),
+    ("backup.amount", "backup-amount", "RACHER_BACKUP_AMOUNT"),
+    (
+        "backup.skip_loading",
+        "backup-skip-loading",
+        "RACHER_BACKUP_SKIP_LOADING",
+    ),
+    ("logging.level", "logger-level", "RACHER_LOGGER_LEVEL"),
+    ("logging.output", "output", "RACHER_OUTPUT_FORMAT"),
+    ("logging.development", "development", "RACHER_DEVELOPMENT"),
+    (
+        "logging.otlp_endpoint",
+        "otlp-endpoint",
+        "RACHER_OTLP_ENDPOINT",
+    ),
+    (
+        "logging.slow_log_threshold",
+        "slow-log-threshold",
+        "RACHER_SLOW_LOG_THRESHOLD",
+    ),
+    (
+        "logging.slow_log_size",
+        "slow-log-size",
+        "RACHER_SLOW_LOG_SIZE",
+    ),
+    ("rate_limit.per_second", "rate-limit", "RACHER_RATE_LIMIT"),
+    (
+        "rate_limit.burst",
+        "rate-limit-burst",
+        "RACHER_RATE_LIMIT_BURST",
+    ),
+    (
+        "rate_limit.prefixes",
+        "rate-limit-prefixes",
+        "RACHER_RATE_LIMIT_PREFIXES",
+    ),
+    (
+        "rate_limit.api_keys",
+        "rate-limit-api-keys",
+        "RACHER_RATE_LIMIT_API_KEYS",
+    ),
+];
+
+/// replaces the target with the value from the file, unless it was given as a flag or env var
+fn merge<T>(fixed: &HashSet<&str>, key: &str, value: Option<T>, target: &mut T) {
+    if let Some(value) = value.filter(|_| !fixed.contains(key)) {
+        *target = value;
+    }
+}
+
 ///
 ///
 ///
 ///
-#[derive(Debug, StructOpt)]
+#[derive(Debug, Clone, StructOpt)]
 #[structopt(about)]
 pub struct Args {
     #[structopt(flatten)]
@@ -62,6 +165,12 @@ pub struct Args {
     pub logger_args: LoggerArgs,
     #[structopt(flatten)]
     pub backup_args: BackupArgs,
+    /// toml or yaml file with settings, env vars and flags take precedence over it
+    #[structopt(long, env = "RACHER_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// settings of the config file that were given as flags or env vars, the file does not change them
+    #[structopt(skip)]
+    pub fixed: HashSet<&'static str>,
     #[structopt(subcommand)]
     pub sub_cmd: Option<SubArg>,
 }
@@ -89,6 +198,15 @@ pub struct LoggerArgs {
     /// set the output format
     #[structopt(short, long, env = "RACHER_OUTPUT_FORMAT", default_value="compact", possible_values = &["compact", "json", "pretty"])]
     pub output: String,
+    /// export spans to this OpenTelemetry collector, e.g. http://localhost:4317
+    #[structopt(long, env = "RACHER_OTLP_ENDPOINT")]
+    pub otlp_endpoint: Option<String>,
+    /// requests that take longer than this many milliseconds end up in the slow log
+    #[structopt(long, default_value = "100", env = "RACHER_SLOW_LOG_THRESHOLD")]
+    pub slow_log_threshold: u64,
+    /// amount of requests kept in the slow log
+    #[structopt(long, default_value = "128", env = "RACHER_SLOW_LOG_SIZE")]
+    pub slow_log_size: usize,
 }
 
 #[derive(Debug, Clone, StructOpt)]
@@ -115,27 +233,161 @@ pub struct DefaultArgs {
     /// address to bind to
     #[structopt(short, long, default_value = "127.0.0.1:9226", env = "RACHER_ADDRESS")]
     address: SocketAddr,
-    ///
+    /// comma separated seed list, nodes started with the same list find each other and form a cluster
     #[structopt(long, env = "RACHER_NEIGHBOURS", parse(try_from_str = parse_vec))]
     pub neighbours: Vec<Vec<Url>>,
+    /// replicate writes made with `consistency=strong` through a raft log
+    #[structopt(long, env = "RACHER_CONSENSUS")]
+    pub consensus: bool,
+    /// bearer token for the `/admin` endpoints, they are disabled without one
+    #[structopt(long, env = "RACHER_ADMIN_TOKEN", hide_env_values = true)]
+    pub admin_token: Option<String>,
+    /// shared by all nodes, requests between them are not rate limited when it is set
+    #[structopt(long, env = "RACHER_CLUSTER_SECRET", hide_env_values = true)]
+    pub cluster_secret: Option<String>,
+    /// also serve the redis protocol on this address, e.g. 127.0.0.1:6379
+    #[structopt(long, env = "RACHER_RESP_ADDRESS")]
+    pub resp_address: Option<SocketAddr>,
+    /// also serve the memcached text protocol on this address, e.g. 127.0.0.1:11211
+    #[structopt(long, env = "RACHER_MEMCACHE_ADDRESS")]
+    pub memcache_address: Option<SocketAddr>,
+    /// also serve the grpc api on this address, e.g. 127.0.0.1:50051
+    #[structopt(long, env = "RACHER_GRPC_ADDRESS")]
+    pub grpc_address: Option<SocketAddr>,
+    /// requests that are sent to the neighbours at the same time
+    #[structopt(long, default_value = "16", env = "RACHER_BROADCAST_CONCURRENCY")]
+    pub broadcast_concurrency: usize,
+    /// milliseconds that requests to all neighbours together may take
+    #[structopt(long, default_value = "5000", env = "RACHER_BROADCAST_TIMEOUT")]
+    pub broadcast_timeout: u64,
+    /// times a failed request to a neighbour is tried again
+    #[structopt(long, default_value = "5", env = "RACHER_RETRY_ATTEMPTS")]
+    pub retry_attempts: usize,
+    /// milliseconds to wait at most before the first retry, it doubles with every retry
+    #[structopt(long, default_value = "50", env = "RACHER_RETRY_BACKOFF")]
+    pub retry_backoff: u64,
+    /// http requests that are handled at the same time, the others wait
+    #[structopt(long, default_value = "10000", env = "RACHER_MAX_CONCURRENT_REQUESTS")]
+    pub max_concurrent_requests: usize,
+    /// milliseconds an http request may take before it is answered with an error
+    #[structopt(long, default_value = "15000", env = "RACHER_REQUEST_TIMEOUT")]
+    pub request_timeout: u64,
+    /// requests per second of a client, by its ip or a known `x-api-key` header. 0 turns it off
+    #[structopt(long, default_value = "0", env = "RACHER_RATE_LIMIT")]
+    pub rate_limit: u32,
+    /// requests a client can make at once after being idle, by default the rate limit
+    #[structopt(long, default_value = "0", env = "RACHER_RATE_LIMIT_BURST")]
+    pub rate_limit_burst: u32,
+    /// comma separated limits per client for keys with a prefix, e.g. sessions:=50,users:=10
+    #[structopt(long, env = "RACHER_RATE_LIMIT_PREFIXES", parse(try_from_str = parse_prefix_limits))]
+    pub rate_limit_prefixes: Vec<Vec<PrefixLimit>>,
+    /// comma separated `x-api-key` values that are limited on their own instead of by ip
+    #[structopt(
+        long,
+        env = "RACHER_RATE_LIMIT_API_KEYS",
+        hide_env_values = true,
+        use_delimiter = true
+    )]
+    pub rate_limit_api_keys: Vec<String>,
+}
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct ClientArgs {
+    /// address of a running racher
+    #[structopt(
+        short,
+        long,
+        default_value = "http://127.0.0.1:9226",
+        env = "RACHER_CLIENT_ADDRESS"
+    )]
+    pub address: Url,
+}
+
+#[derive(Debug, Clone, StructOpt)]
+pub struct JoinArgs {
+    #[structopt(flatten)]
+    pub default_args: DefaultArgs,
+    #[structopt(flatten)]
+    pub logger_args: LoggerArgs,
+    #[structopt(flatten)]
+    pub backup_args: BackupArgs,
+    /// address to join
+    #[structopt(short, long, env = "RACHER_JOIN_ADDRESS")]
+    pub join_address: Url,
+    /// address where the other racher can reach this instance, by default it takes the value from the address argument
+    #[structopt(short, long, env = "RACHER_EXTERNAL_ADDRESS")]
+    pub external_address: Option<Url>,
 }
 
 #[derive(Debug, Clone, StructOpt)]
 pub enum SubArg {
     /// join already running racher
-    Join {
+    Join(Box<JoinArgs>),
+    /// prints the status of every member of the cluster and exits
+    Status {
         #[structopt(flatten)]
-        default_args: DefaultArgs,
+        client_args: ClientArgs,
+        /// output format
+        #[structopt(short, long, default_value = "table", possible_values = &["table", "json"])]
+        format: String,
+    },
+    /// prints the value of a key, exits with 1 if the key does not exist and 2 on errors
+    Get {
         #[structopt(flatten)]
-        logger_args: LoggerArgs,
+        client_args: ClientArgs,
+        key: String,
+        /// one, quorum, all or strong
+        #[structopt(short, long, default_value = "one")]
+        consistency: Consistency,
+    },
+    /// sets a key to a json value, read from stdin when neither a value nor a file is given
+    Set {
+        #[structopt(flatten)]
+        client_args: ClientArgs,
+        key: String,
+        value: Option<String>,
+        /// read the value from a file
+        #[structopt(short, long, conflicts_with = "value")]
+        file: Option<PathBuf>,
+        /// store the input as a json string instead of parsing it
+        #[structopt(short, long)]
+        string: bool,
+        /// one, quorum, all or strong
+        #[structopt(short, long, default_value = "one")]
+        consistency: Consistency,
+    },
+    /// deletes a key, exits with 1 if the key did not exist
+    Del {
         #[structopt(flatten)]
-        backup_args: BackupArgs,
-        /// address to join
-        #[structopt(short, long, env = "RACHER_JOIN_ADDRESS")]
-        join_address: Url,
-        /// address where the other racher can reach this instance, by default it takes the value from the address argument
-        #[structopt(short, long, env = "RACHER_EXTERNAL_ADDRESS")]
-        external_address: Option<Url>,
+        client_args: ClientArgs,
+        key: String,
+    },
+    /// prints all keys
+    Keys {
+        #[structopt(flatten)]
+        client_args: ClientArgs,
+    },
+    /// deletes every key
+    Purge {
+        #[structopt(flatten)]
+        client_args: ClientArgs,
+    },
+    /// checks if racher is reachable
+    Ping {
+        #[structopt(flatten)]
+        client_args: ClientArgs,
+    },
+    /// interactive prompt with history and tab completion
+    Shell {
+        /// addresses of running rachers, the first one is used until switched with `use`
+        #[structopt(
+            short = "a",
+            long = "address",
+            default_value = "http://127.0.0.1:9226",
+            env = "RACHER_CLIENT_ADDRESS",
+            use_delimiter = true
+        )]
+        addresses: Vec<Url>,
     },
     /// removes all the backup files and exits
     RemoveBackup {
@@ -143,26 +395,285 @@ pub enum SubArg {
         #[structopt(long, default_value, env = "RACHER_BACKUP_DIR")]
         backup_dir: TempPathBuf,
     },
+    /// works with configuration files
+    Config(ConfigCmd),
+}
+
+#[derive(Debug, Clone, StructOpt)]
+pub enum ConfigCmd {
+    /// validates a configuration file and prints its settings
+    Check {
+        /// file to check, by default the one given with --config
+        path: Option<PathBuf>,
+    },
+}
+
+impl SubArg {
+    /// commands that talk to a running racher and exit, instead of starting one
+    pub fn is_client(&self) -> bool {
+        !matches!(
+            self,
+            SubArg::Join(_) | SubArg::RemoveBackup { .. } | SubArg::Config(_)
+        )
+    }
 }
 
 impl Args {
-    pub fn as_runtime_config(&self) -> RuntimeConfig {
-        let (default_args, backup_args, external_address) = if let Some(SubArg::Join {
-            default_args,
-            backup_args,
-            external_address,
-            ..
-        }) = self.sub_cmd.clone()
-        {
-            let external_address = external_address.unwrap_or(addr_to_url(&default_args.address));
-            (default_args, backup_args, external_address)
-        } else {
-            (
-                self.default_args.clone(),
-                self.backup_args.clone(),
-                addr_to_url(&self.default_args.address),
-            )
+    /// merges the settings of the config file into the arguments.
+    /// flags take precedence over env vars, env vars over the file and the file over the defaults
+    pub fn with_config_file(self) -> Result<Args, Box<dyn Error>> {
+        let file = match self.config.as_ref() {
+            Some(path) => ConfigFile::load(path)?,
+            None => return Ok(self),
         };
+        let matches = Args::clap().get_matches_safe()?;
+        Ok(self.merge_config_file(&file, &matches))
+    }
+
+    /// the arguments with the settings of the file, `matches` tells which flags were given
+    pub fn merge_config_file(
+        mut self,
+        file: &ConfigFile,
+        matches: &structopt::clap::ArgMatches<'_>,
+    ) -> Args {
+        let matches = match self.sub_cmd {
+            Some(SubArg::Join(_)) => matches.subcommand_matches("join").unwrap_or(matches),
+            _ => matches,
+        };
+        self.fixed = FILE_SETTINGS
+            .iter()
+            .filter(|(_, arg, env)| matches.occurrences_of(arg) > 0 || env::var_os(env).is_some())
+            .map(|(key, _, _)| *key)
+            .collect();
+
+        let fixed = &self.fixed;
+        let (default_args, backup_args, logger_args) = match self.sub_cmd.as_mut() {
+            Some(SubArg::Join(join)) => (
+                &mut join.default_args,
+                &mut join.backup_args,
+                &mut join.logger_args,
+            ),
+            _ => (
+                &mut self.default_args,
+                &mut self.backup_args,
+                &mut self.logger_args,
+            ),
+        };
+
+        merge(fixed, "address", file.address, &mut default_args.address);
+        merge(
+            fixed,
+            "neighbours",
+            file.neighbours.clone().map(|x| vec![x]),
+            &mut default_args.neighbours,
+        );
+        merge(
+            fixed,
+            "consensus",
+            file.consensus,
+            &mut default_args.consensus,
+        );
+        merge(
+            fixed,
+            "admin_token",
+            file.admin_token.clone().map(Some),
+            &mut default_args.admin_token,
+        );
+        merge(
+            fixed,
+            "cluster_secret",
+            file.cluster_secret.clone().map(Some),
+            &mut default_args.cluster_secret,
+        );
+        merge(
+            fixed,
+            "resp_address",
+            file.resp_address.map(Some),
+            &mut default_args.resp_address,
+        );
+        merge(
+            fixed,
+            "memcache_address",
+            file.memcache_address.map(Some),
+            &mut default_args.memcache_address,
+        );
+        merge(
+            fixed,
+            "grpc_address",
+            file.grpc_address.map(Some),
+            &mut default_args.grpc_address,
+        );
+        merge(
+            fixed,
+            "broadcast_concurrency",
+            file.broadcast_concurrency,
+            &mut default_args.broadcast_concurrency,
+        );
+        merge(
+            fixed,
+            "broadcast_timeout",
+            file.broadcast_timeout,
+            &mut default_args.broadcast_timeout,
+        );
+        merge(
+            fixed,
+            "retry_attempts",
+            file.retry_attempts,
+            &mut default_args.retry_attempts,
+        );
+        merge(
+            fixed,
+            "retry_backoff",
+            file.retry_backoff,
+            &mut default_args.retry_backoff,
+        );
+        merge(
+            fixed,
+            "max_concurrent_requests",
+            file.max_concurrent_requests,
+            &mut default_args.max_concurrent_requests,
+        );
+        merge(
+            fixed,
+            "request_timeout",
+            file.request_timeout,
+            &mut default_args.request_timeout,
+        );
+
+        merge(
+            fixed,
+            "backup.dir",
+            file.backup.dir.clone().map(|path| TempPathBuf { path }),
+            &mut backup_args.backup_dir,
+        );
+        merge(
+            fixed,
+            "backup.interval",
+            file.backup.interval,
+            &mut backup_args.backup_interval,
+        );
+        merge(
+            fixed,
+            "backup.amount",
+            file.backup.amount,
+            &mut backup_args.backup_amount,
+        );
+        merge(
+            fixed,
+            "backup.skip_loading",
+            file.backup.skip_loading,
+            &mut backup_args.backup_skip_loading,
+        );
+
+        merge(
+            fixed,
+            "logging.level",
+            file.logging
+                .level
+                .as_ref()
+                .and_then(|x| x.parse().ok())
+                .map(Some),
+            &mut logger_args.logger_level,
+        );
+        merge(
+            fixed,
+            "logging.output",
+            file.logging.output.clone(),
+            &mut logger_args.output,
+        );
+        merge(
+            fixed,
+            "logging.development",
+            file.logging.development,
+            &mut logger_args.development,
+        );
+        merge(
+            fixed,
+            "logging.otlp_endpoint",
+            file.logging
+                .otlp_endpoint
+                .as_ref()
+                .map(|x| Some(x.to_string())),
+            &mut logger_args.otlp_endpoint,
+        );
+        merge(
+            fixed,
+            "logging.slow_log_threshold",
+            file.logging.slow_log_threshold,
+            &mut logger_args.slow_log_threshold,
+        );
+        merge(
+            fixed,
+            "logging.slow_log_size",
+            file.logging.slow_log_size,
+            &mut logger_args.slow_log_size,
+        );
+
+        merge(
+            fixed,
+            "rate_limit.per_second",
+            file.rate_limit.per_second,
+            &mut default_args.rate_limit,
+        );
+        merge(
+            fixed,
+            "rate_limit.burst",
+            file.rate_limit.burst,
+            &mut default_args.rate_limit_burst,
+        );
+        let prefixes = file.rate_limit.prefixes.as_ref().map(|prefixes| {
+            let prefixes = prefixes
+                .iter()
+                .map(|(prefix, per_second)| PrefixLimit {
+                    prefix: prefix.clone(),
+                    per_second: *per_second,
+                })
+                .collect();
+            vec![prefixes]
+        });
+        merge(
+            fixed,
+            "rate_limit.prefixes",
+            prefixes,
+            &mut default_args.rate_limit_prefixes,
+        );
+        merge(
+            fixed,
+            "rate_limit.api_keys",
+            file.rate_limit.api_keys.clone(),
+            &mut default_args.rate_limit_api_keys,
+        );
+        self
+    }
+
+    pub fn as_runtime_config(&self) -> RuntimeConfig {
+        let (default_args, backup_args, logger_args, external_address) =
+            if let Some(SubArg::Join(join)) = self.sub_cmd.clone() {
+                let JoinArgs {
+                    default_args,
+                    backup_args,
+                    logger_args,
+                    external_address,
+                    ..
+                } = *join;
+                let external_address =
+                    external_address.unwrap_or(addr_to_url(&default_args.address));
+                (default_args, backup_args, logger_args, external_address)
+            } else {
+                (
+                    self.default_args.clone(),
+                    self.backup_args.clone(),
+                    self.logger_args.clone(),
+                    addr_to_url(&self.default_args.address),
+                )
+            };
+        let seeds: HashSet<Url> = default_args
+            .neighbours
+            .clone()
+            .into_iter()
+            .flatten()
+            .filter(|seed| seed != &external_address)
+            .collect();
         RuntimeConfig {
             address: default_args.address.clone(),
             backup_dir: backup_args.backup_dir.path.clone(),
@@ -170,19 +681,39 @@ impl Args {
             backup_amount: backup_args.backup_amount,
             backup_skip_loading: backup_args.backup_skip_loading,
             external_address: external_address,
-            neighbours: default_args
-                .neighbours
-                .clone()
-                .into_iter()
+            neighbours: seeds.clone(),
+            members: seeds.clone(),
+            seeds,
+            consensus: default_args.consensus,
+            slow_log_threshold: logger_args.slow_log_threshold,
+            slow_log_size: logger_args.slow_log_size,
+            admin_token: default_args.admin_token.clone(),
+            cluster_secret: default_args.cluster_secret.clone(),
+            resp_address: default_args.resp_address,
+            memcache_address: default_args.memcache_address,
+            grpc_address: default_args.grpc_address,
+            broadcast_concurrency: default_args.broadcast_concurrency.max(1),
+            broadcast_timeout: default_args.broadcast_timeout,
+            retry_attempts: default_args.retry_attempts,
+            retry_backoff: default_args.retry_backoff,
+            max_concurrent_requests: default_args.max_concurrent_requests.max(1),
+            request_timeout: default_args.request_timeout,
+            rate_limit: default_args.rate_limit,
+            rate_limit_burst: default_args.rate_limit_burst,
+            rate_limit_prefixes: default_args
+                .rate_limit_prefixes
+                .iter()
                 .flatten()
+                .cloned()
                 .collect(),
+            rate_limit_api_keys: default_args.rate_limit_api_keys.iter().cloned().collect(),
             ..Default::default()
         }
     }
 
     pub fn set_logger(&self) {
-        let args = if let Some(SubArg::Join { logger_args, .. }) = self.sub_cmd.clone() {
-            logger_args
+        let args = if let Some(SubArg::Join(join)) = self.sub_cmd.clone() {
+            join.logger_args
         } else {
             self.logger_args.clone()
         };
@@ -198,19 +729,41 @@ impl Args {
             args.logger_level.unwrap_or(LevelFilter::INFO)
         };
 
+        // without RUST_LOG the level also keeps the spans that carry the trace context
+        let filter = EnvFilter::try_from_default_env()
+            .unwrap_or_else(|_| EnvFilter::default().add_directive(level.into()));
         let filter = if args.development {
-            EnvFilter::from_default_env().add_directive("libracher=debug".parse().unwrap())
+            filter.add_directive("libracher=debug".parse().unwrap())
         } else {
-            EnvFilter::from_default_env()
+            filter
+        };
+        let tracer = match telemetry::tracer(args.otlp_endpoint.as_deref()) {
+            Ok(tracer) => tracer,
+            Err(e) => {
+                eprintln!("could not start the otlp exporter: {}", e);
+                telemetry::tracer(None).expect("the tracer without exporter always starts")
+            }
         };
 
         let builder = tracing_subscriber::fmt()
             .with_max_level(level)
             .with_env_filter(filter);
         match args.output.as_ref() {
-            "json" => builder.json().init(),
-            "compact" => builder.compact().init(),
-            "pretty" => builder.pretty().init(),
+            "json" => builder
+                .json()
+                .finish()
+                .with(tracing_opentelemetry::layer().with_tracer(tracer))
+                .init(),
+            "compact" => builder
+                .compact()
+                .finish()
+                .with(tracing_opentelemetry::layer().with_tracer(tracer))
+                .init(),
+            "pretty" => builder
+                .pretty()
+                .finish()
+                .with(tracing_opentelemetry::layer().with_tracer(tracer))
+                .init(),
             _ => unreachable!(),
         };
     }
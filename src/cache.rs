@@ -0,0 +1,115 @@
+use std::io;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use dashmap::DashMap;
+use serde::{Serialize, Serializer};
+use serde_value::Value;
+
+/// counts the bytes written to it, so values can be measured without allocating
+struct ByteCounter(u64);
+
+impl io::Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn value_size(value: &Value) -> u64 {
+    match value {
+        Value::Bytes(x) => x.len() as u64,
+        value => {
+            let mut counter = ByteCounter(0);
+            serde_json::to_writer(&mut counter, value).ok();
+            counter.0
+        }
+    }
+}
+
+/// the bytes a key takes up, its name and its value as json. raw values count with their length
+pub fn size(key: &str, value: &Value) -> u64 {
+    key.len() as u64 + value_size(value)
+}
+
+/// the values of all keys, with a running count of the bytes they take up.
+/// reads go to the map, `insert`, `remove` and `clear` keep the count up to date
+#[derive(Debug, Default)]
+pub struct Cache {
+    values: DashMap<String, Value>,
+    bytes: AtomicU64,
+}
+
+impl Cache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// the size of all keys and values, see `size`
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn insert(&self, key: String, value: Value) -> Option<Value> {
+        let length = key.len() as u64;
+        self.bytes.fetch_add(size(&key, &value), Ordering::Relaxed);
+        let old = self.values.insert(key, value);
+        if let Some(old) = old.as_ref() {
+            self.bytes
+                .fetch_sub(length + value_size(old), Ordering::Relaxed);
+        }
+        old
+    }
+
+    pub fn remove(&self, key: &str) -> Option<(String, Value)> {
+        let removed = self.values.remove(key);
+        if let Some((key, value)) = removed.as_ref() {
+            self.bytes.fetch_sub(size(key, value), Ordering::Relaxed);
+        }
+        removed
+    }
+
+    pub fn clear(&self) {
+        self.values.retain(|key, value| {
+            self.bytes.fetch_sub(size(key, value), Ordering::Relaxed);
+            false
+        });
+    }
+
+    /// records that a value changed in place, through `entry` or `get_mut`
+    pub fn resized(&self, before: u64, after: u64) {
+        self.bytes.fetch_add(after, Ordering::Relaxed);
+        self.bytes.fetch_sub(before, Ordering::Relaxed);
+    }
+}
+
+impl Deref for Cache {
+    type Target = DashMap<String, Value>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.values
+    }
+}
+
+impl From<DashMap<String, Value>> for Cache {
+    fn from(values: DashMap<String, Value>) -> Self {
+        let bytes = values
+            .iter()
+            .map(|item| size(item.key(), item.value()))
+            .sum();
+        Cache {
+            values,
+            bytes: AtomicU64::new(bytes),
+        }
+    }
+}
+
+impl Serialize for Cache {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.values.serialize(serializer)
+    }
+}
@@ -1,8 +1,25 @@
-use crate::config::RuntimeConfigArc;
+use crate::access_log::{AccessLogLayer, SlowLog};
+use crate::arguments::{Args, SubArg};
+use crate::cache::Cache;
+use crate::client::{ClusterClient, HostResult};
+use crate::config::{BackupInfo, ClusterState, ConfigFile, RuntimeConfigArc};
+use crate::encoding::Encoding;
+use crate::grpc::{self, RacherService};
+use crate::memcache;
+use crate::metadata::{self, Metadata};
+use crate::metrics;
+use crate::raft::{self, Raft, RaftArc};
+use crate::rate_limit::{RateLimitLayer, RateLimiter};
+use crate::resp;
+use crate::responses::{InfoResponse, JoinResponse};
+use crate::sync::Arc;
+use crate::telemetry;
 use crate::transport;
 use crate::transport::Message;
-use crate::Db;
+use crate::{Db, MetaDb};
 
+use std::cmp::Reverse;
+use std::collections::HashSet;
 use std::convert::Infallible;
 use std::error::Error;
 use std::path::PathBuf;
@@ -10,24 +27,68 @@ use std::time::Duration;
 
 use chrono::Utc;
 use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
 use serde_value::Value;
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::broadcast::error::RecvError;
 use tokio::{fs, io, signal, time};
 use tower::ServiceBuilder;
-use tracing::{debug, info};
+use tracing::{debug, error, info, info_span, warn, Instrument};
 use url::Url;
 use warp::hyper::server::Server;
 
+const BOOTSTRAP_ATTEMPTS: usize = 5;
+const BOOTSTRAP_DELAY: Duration = Duration::from_secs(2);
+/// microseconds, changes this much older than the newest local version are fetched again
+/// on a restart, so writes from nodes with a clock that is a bit behind are not missed
+const SYNC_OVERLAP: u64 = 60_000_000;
+const BACKUP_FORMAT: u32 = 2;
+const BACKUP_ENCODING: Encoding = Encoding::MessagePack;
+const BACKUP_EXTENSION: &str = "msgpack";
+
+/// contents of a backup file, the metadata keeps the versions and deleted keys
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Backup<T, M> {
+    format: u32,
+    data: T,
+    meta: M,
+}
+
+/// backups written before the metadata was added only contain the data
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum BackupFile {
+    Versioned(Backup<DashMap<String, Value>, DashMap<String, Metadata>>),
+    Plain(DashMap<String, Value>),
+}
+
 pub async fn http_server(
     cfg: RuntimeConfigArc,
     cache: Db,
+    meta: MetaDb,
     tx: transport::Sender,
+    raft: RaftArc,
 ) -> Result<(), Box<dyn Error>> {
-    let address = { cfg.read().await.address.clone() };
+    let (address, slow_log, limiter, max_concurrent_requests, request_timeout) = {
+        let read_cfg = cfg.read().await;
+        let slow_log = SlowLog::new(
+            Duration::from_millis(read_cfg.slow_log_threshold),
+            read_cfg.slow_log_size,
+        );
+        let limiter = RateLimiter::from_config(&read_cfg).map(RateLimiter::to_arc);
+        (
+            read_cfg.address,
+            slow_log.to_arc(),
+            limiter,
+            read_cfg.max_concurrent_requests,
+            Duration::from_millis(read_cfg.request_timeout),
+        )
+    };
     // let (addr, server) = warp::serve(api).bind_with_graceful_shutdown(address, async {
     //     signal::ctrl_c().await.expect("failed to listen for event")
     // });
-    let api = crate::create_api(cache.clone(), cfg.clone(), tx);
+    let api = crate::create_api(cache.clone(), meta, cfg.clone(), tx, raft, slow_log.clone());
 
     let warp_svc = warp::service(api);
     // let make_svc = warp::hyper::service::make_service_fn(move |_| {
@@ -41,19 +102,34 @@ pub async fn http_server(
     //     }
     // });
 
-    let f = move |_: &warp::hyper::server::conn::AddrStream| {
-        let svc = warp_svc.clone();
-        async move { Ok::<_, Infallible>(svc) }
+    let f = move |stream: &warp::hyper::server::conn::AddrStream| {
+        let peer = stream.remote_addr();
+        let (cfg, slow_log, limiter) = (cfg.clone(), slow_log.clone(), limiter.clone());
+        let warp_svc = warp_svc.clone();
+        async move {
+            let secret = cfg.read().await.cluster_secret.clone();
+            let secret = match (limiter.as_ref(), secret) {
+                (Some(limiter), Some(secret)) if limiter.is_neighbour(&cfg, peer.ip()).await => {
+                    Some(secret)
+                }
+                _ => None,
+            };
+            let svc = ServiceBuilder::new()
+                .layer(AccessLogLayer::new(peer, slow_log))
+                .layer(RateLimitLayer::new(peer, limiter).with_secret(secret))
+                .service(warp_svc);
+            Ok::<_, Infallible>(svc)
+        }
     };
 
     let x = ServiceBuilder::new()
         // .buffer(100)
         // .layer(tower::buffer::BufferLayer::new(100))
         // .concurrency_limit(10)
-        .layer(tower::limit::ConcurrencyLimitLayer::new(10000))
-        .layer(tower::timeout::TimeoutLayer::new(Duration::from_millis(
-            15000,
-        )))
+        .layer(tower::limit::ConcurrencyLimitLayer::new(
+            max_concurrent_requests,
+        ))
+        .layer(tower::timeout::TimeoutLayer::new(request_timeout))
         .service_fn(f);
 
     let server = Server::bind(&address).serve(x);
@@ -71,46 +147,132 @@ pub async fn server_sender(
     cfg: RuntimeConfigArc,
     mut rx: transport::Receiver,
 ) -> Result<(), std::convert::Infallible> {
-    let mut client = crate::client::Client::new();
+    let mut client = crate::client::Client::from_config(&*cfg.read().await);
     loop {
-        if let Ok(message) = rx.recv().await {
-            let (key, value) = match message {
-                Message::Created(key, value) => (key, value),
-                Message::Deleted(key) => (key, Value::Unit),
-            };
-            let neighbours = {
-                let read_cfg = cfg.read().await;
-                read_cfg.neighbours.clone()
-            };
+        let message = match rx.recv().await {
+            Ok(message) => message,
+            Err(RecvError::Lagged(amount)) => {
+                warn!("dropped {} updates for the neighbours", amount);
+                metrics::REPLICATIONS_DROPPED.inc_by(amount);
+                continue;
+            }
+            Err(RecvError::Closed) => continue,
+        };
+        let (key, value, metadata, trace) = match message {
+            Message::Created(key, value, metadata, trace) => (key, value, metadata, trace),
+            Message::Deleted(key, version, trace) => (
+                key,
+                Value::Unit,
+                Metadata {
+                    version,
+                    deleted: true,
+                    ..Default::default()
+                },
+                trace,
+            ),
+            Message::Updated(..) => continue,
+        };
+        let neighbours = {
+            let read_cfg = cfg.read().await;
+            read_cfg.neighbours.clone()
+        };
+
+        let span = info_span!(target: telemetry::TARGET, "replicate", key = %key);
+        telemetry::follow(&span, &trace);
+        async {
             for host in neighbours {
-                client.internal_update(host, &key, &value).await;
+                // errors are already logged by the client
+                let result = match client.internal_update(host, &key, &value, &metadata).await {
+                    Ok(()) => "ok",
+                    Err(_) => "error",
+                };
+                metrics::REPLICATIONS.with_label_values(&[result]).inc();
             }
         }
+        .instrument(span)
+        .await;
     }
 }
 
-pub async fn fs_loop(cfg: RuntimeConfigArc, cache: Db) -> io::Result<()> {
-    let mut interval = {
-        let read_cfg = cfg.read().await;
-        time::interval(time::Duration::from_secs(read_cfg.backup_interval))
+pub(crate) async fn write_backup(
+    cfg: &RuntimeConfigArc,
+    cache: &Db,
+    meta: &MetaDb,
+) -> io::Result<()> {
+    let (mut file, path) = {
+        let mut file = {
+            let read_cfg = cfg.read().await;
+            read_cfg.backup_dir.clone()
+        };
+        fs::create_dir_all(&file).await?;
+
+        file.push(format!("racher-{}", Utc::now().format("%Y%m%dT%H%M%S%6f")));
+        file.set_extension(BACKUP_EXTENSION);
+
+        (fs::File::create(&file).await?, file)
     };
+    debug!("writing to file: {:?}", path);
+    let bytes = BACKUP_ENCODING
+        .encode(&Backup {
+            format: BACKUP_FORMAT,
+            data: &**cache,
+            meta: &**meta,
+        })
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    file.write_all(&bytes).await?;
+    metrics::BACKUP_SIZE.set(bytes.len() as i64);
+    {
+        let mut write_cfg = cfg.write().await;
+        write_cfg.last_backup = Some(BackupInfo {
+            time: Utc::now(),
+            path,
+            size: bytes.len() as u64,
+        });
+    }
+    Ok(())
+}
+
+/// sleeps until `backup_interval` seconds passed since `since`.
+/// the interval is read again every second, so changes made while running apply right away
+async fn wait_backup_interval(cfg: &RuntimeConfigArc, since: time::Instant) {
     loop {
-        interval.tick().await;
-        let (mut file, path) = {
-            let mut file = {
-                let read_cfg = cfg.read().await;
-                read_cfg.backup_dir.clone()
-            };
-            fs::create_dir_all(&file).await?;
+        let interval = Duration::from_secs(cfg.read().await.backup_interval);
+        let elapsed = since.elapsed();
+        if elapsed >= interval {
+            return;
+        }
+        time::sleep((interval - elapsed).min(Duration::from_secs(1))).await;
+    }
+}
 
-            file.push(format!("racher-{}", Utc::now().format("%Y%m%dT%H%M%S%6f")));
-            file.set_extension("json");
+async fn write_cluster_state(cfg: &RuntimeConfigArc) -> io::Result<()> {
+    let (state, backup_dir) = {
+        let read_cfg = cfg.read().await;
+        (
+            ClusterState::from_config(&read_cfg),
+            read_cfg.backup_dir.clone(),
+        )
+    };
+    state.save(&backup_dir).await
+}
 
-            (fs::File::create(&file).await?, file)
-        };
-        debug!("writing to file: {:?}", path);
-        let bytes = serde_json::to_vec(&*cache)?;
-        file.write_all(&bytes).await?;
+pub async fn fs_loop(cfg: RuntimeConfigArc, cache: Db, meta: MetaDb) -> io::Result<()> {
+    loop {
+        let started = time::Instant::now();
+        let timer = metrics::BACKUP_DURATION.start_timer();
+        // a full disk or a missing folder can be fixed while running, so try again next time
+        match write_backup(&cfg, &cache, &meta).await {
+            Ok(()) => timer.observe_duration(),
+            Err(e) => {
+                timer.stop_and_discard();
+                metrics::BACKUP_FAILURES.inc();
+                error!(%e, "could not write the backup");
+            }
+        }
+        if let Err(e) = write_cluster_state(&cfg).await {
+            error!(%e, "could not write the cluster state");
+        }
+        wait_backup_interval(&cfg, started).await;
     }
 }
 
@@ -137,12 +299,8 @@ pub async fn fetch_data_dir_filenames(backup_dir: &PathBuf) -> io::Result<Vec<st
 }
 
 pub async fn clean_data_dir(cfg: RuntimeConfigArc) -> io::Result<()> {
-    let mut interval = {
-        let read_cfg = cfg.read().await;
-        time::interval(time::Duration::from_secs(read_cfg.backup_interval))
-    };
     loop {
-        interval.tick().await;
+        let started = time::Instant::now();
 
         let (backup_amount, backup_dir) = {
             let read_cfg = cfg.read().await;
@@ -161,45 +319,412 @@ pub async fn clean_data_dir(cfg: RuntimeConfigArc) -> io::Result<()> {
         }
 
         debug!("done clean up old backups");
+        wait_backup_interval(&cfg, started).await;
     }
 }
 
-pub async fn sync_neighbours(cfg: RuntimeConfigArc) -> Result<(), Box<dyn Error>> {
-    time::interval(time::Duration::from_secs(10)).tick().await;
+pub async fn sync_neighbours(
+    cfg: RuntimeConfigArc,
+    cache: Db,
+    meta: MetaDb,
+) -> Result<(), Box<dyn Error>> {
+    let mut bootstrapped = match bootstrap(cfg.clone(), cache.clone(), meta.clone()).await {
+        Ok(()) => true,
+        Err(e) => {
+            error!("bootstrap failed, trying again in the next round: {}", e);
+            false
+        }
+    };
 
     let mut interval = { time::interval(time::Duration::from_secs(60)) };
     loop {
-        let (neighbours, me) = {
+        let (neighbours, me, mut client) = {
             let read_cfg = cfg.read().await;
             let mut neighbours = read_cfg.neighbours.clone();
             let me = read_cfg.external_address.clone();
             neighbours.remove(&me);
-            (neighbours, me)
+            (
+                neighbours,
+                me,
+                crate::client::Client::from_config(&read_cfg),
+            )
         };
-        let mut client = crate::client::Client::new();
-        let neighbours = client.ping_all(neighbours).await?;
+        let mut reachable = HashSet::new();
+        for (neighbour, result) in client.ping_all(neighbours).await {
+            match result {
+                Ok(_) => {
+                    reachable.insert(neighbour);
+                }
+                Err(e) => warn!("neighbour '{}' is unreachable: {}", neighbour, e),
+            }
+        }
+        let neighbours = reachable;
         {
             let mut write_cfg = cfg.write().await;
+            let now = Utc::now();
+            for neighbour in neighbours.iter() {
+                write_cfg.last_seen.insert(neighbour.clone(), now);
+            }
             write_cfg.neighbours = neighbours.clone();
+            write_cfg.forget_members(now);
+        }
+        // seeds that were down before get a new chance here, a node that could not
+        // bootstrap still needs the sync of a starting node
+        match converge(cfg.clone(), cache.clone(), meta.clone(), !bootstrapped).await {
+            Ok(reached) => bootstrapped |= reached,
+            Err(e) => error!(%e),
         }
-        client.join_all(me, neighbours).await?;
+        let neighbours = { cfg.read().await.neighbours.clone() };
+        log_join_failures(client.join_all(me, neighbours).await);
 
         interval.tick().await;
     }
 }
 
-pub async fn sync_to_fs(cfg: RuntimeConfigArc, cache: Db) -> Result<(), Box<dyn Error>> {
+/// asks the seeds and neighbours for their cluster code and adopts the code of the
+/// most up-to-date node (the lowest code if they are equal), so nodes started with the
+/// same seed list end up in the same cluster. data is synced from that node when the
+/// code changes, or on startup. returns false if no-one could be reached.
+pub async fn converge(
+    cfg: RuntimeConfigArc,
+    cache: Db,
+    meta: MetaDb,
+    startup: bool,
+) -> Result<bool, Box<dyn Error>> {
+    let (me, candidates, my_code) = {
+        let read_cfg = cfg.read().await;
+        let mut candidates: HashSet<Url> = read_cfg
+            .seeds
+            .union(&read_cfg.neighbours)
+            .cloned()
+            .collect();
+        candidates.remove(&read_cfg.external_address);
+        (
+            read_cfg.external_address.clone(),
+            candidates,
+            read_cfg.base_code.clone(),
+        )
+    };
+
+    let mut client = crate::client::Client::from_config(&*cfg.read().await);
+    let peers = client.info_all(candidates).await;
+    if peers.is_empty() {
+        return Ok(false);
+    }
+
+    let (address, best) = best_peer(&peers).expect("checked that there are peers");
+    let mine = (
+        metadata::latest_version(&meta),
+        cache.len(),
+        Reverse(my_code.clone()),
+    );
+
+    let mut code = my_code.clone();
+    if (best.version, best.keys, Reverse(best.code.clone())) > mine {
+        let since = metadata::latest_version(&meta);
+        // deletes older than the tombstones are not part of the changes anymore
+        let recent = metadata::now().saturating_sub(since) < metadata::TOMBSTONE_TTL;
+        if best.code == my_code && startup && since > 0 && recent {
+            // a restarted member only needs what changed while it was down
+            info!("catching up with '{}'", address);
+            let since = since.saturating_sub(SYNC_OVERLAP);
+            let response = client.changes(address.clone(), &best.code, since).await?;
+            for change in response.changes {
+                metadata::apply(&cache, &meta, change.key, change.data, change.meta);
+            }
+        } else if best.code != my_code || startup {
+            info!("syncing with '{}'", address);
+            let received = client
+                .sync(address.clone(), &best.code, &cache, &meta)
+                .await?;
+            info!("synced {} keys", received);
+        }
+        code = best.code.clone();
+    }
+
+    let mut write_cfg = cfg.write().await;
+    if write_cfg.base_code != code {
+        info!("joined cluster through seeds");
+        write_cfg.base_code = code;
+    }
+    for (address, info) in peers {
+        write_cfg.add_neighbour(address);
+        for neighbour in info.neighbours.into_iter().filter(|x| x != &me) {
+            write_cfg.add_neighbour(neighbour);
+        }
+    }
+
+    Ok(true)
+}
+
+/// the most up-to-date peer: the one with the newest write, then the most keys,
+/// then the lowest code
+pub(crate) fn best_peer(peers: &[(Url, InfoResponse)]) -> Option<&(Url, InfoResponse)> {
+    peers
+        .iter()
+        .max_by_key(|(_, info)| (info.version, info.keys, Reverse(info.code.clone())))
+}
+
+fn log_join_failures(results: Vec<HostResult<JoinResponse>>) {
+    for (neighbour, result) in results {
+        if let Err(e) = result {
+            warn!("could not join '{}': {}", neighbour, e);
+        }
+    }
+}
+
+/// forms a cluster from the seed list, waits a bit for seeds that are still starting
+pub async fn bootstrap(
+    cfg: RuntimeConfigArc,
+    cache: Db,
+    meta: MetaDb,
+) -> Result<(), Box<dyn Error>> {
+    // neighbours are only known at this point when they were restored from the cluster file
+    let (me, has_seeds) = {
+        let read_cfg = cfg.read().await;
+        (
+            read_cfg.external_address.clone(),
+            !read_cfg.seeds.is_empty() || !read_cfg.neighbours.is_empty(),
+        )
+    };
+    if !has_seeds {
+        return Ok(());
+    }
+
+    let mut failure = None;
+    for attempt in 1..=BOOTSTRAP_ATTEMPTS {
+        // the error is not Send, so it cannot be held while joining
+        let result = converge(cfg.clone(), cache.clone(), meta.clone(), true)
+            .await
+            .map_err(|e| e.to_string());
+        match result {
+            Ok(true) => {
+                let (neighbours, mut client) = {
+                    let read_cfg = cfg.read().await;
+                    (
+                        read_cfg.neighbours.clone(),
+                        crate::client::Client::from_config(&read_cfg),
+                    )
+                };
+                let results = client.join_all(me, neighbours).await;
+                log_join_failures(results);
+                return Ok(());
+            }
+            Ok(false) => debug!(
+                "no seeds reachable, attempt {} of {}",
+                attempt, BOOTSTRAP_ATTEMPTS
+            ),
+            Err(e) => {
+                warn!("bootstrap attempt {} failed: {}", attempt, e);
+                failure = Some(e);
+            }
+        }
+        time::sleep(BOOTSTRAP_DELAY).await;
+    }
+
+    if let Some(e) = failure {
+        return Err(e.into());
+    }
+    warn!("no seeds reachable, starting on my own");
+    Ok(())
+}
+
+pub async fn load_raft(config: RuntimeConfigArc) -> Result<RaftArc, Box<dyn Error>> {
+    let read_cfg = config.read().await;
+    if !read_cfg.consensus {
+        return Ok(Raft::default().to_arc());
+    }
+
+    Ok(Raft::load(&read_cfg.backup_dir).await?.to_arc())
+}
+
+pub async fn consensus(
+    cfg: RuntimeConfigArc,
+    raft: RaftArc,
+    cache: Db,
+    meta: MetaDb,
+) -> Result<(), Box<dyn Error>> {
+    let enabled = { cfg.read().await.consensus };
+    if enabled {
+        raft::run(raft, cfg, cache, meta).await;
+    } else {
+        futures::future::pending::<()>().await;
+    }
+
+    Ok(())
+}
+
+pub async fn sync_to_fs(
+    cfg: RuntimeConfigArc,
+    cache: Db,
+    meta: MetaDb,
+) -> Result<(), Box<dyn Error>> {
     tokio::select! {
         Ok(()) = signal::ctrl_c() => {}
-        Ok(()) = fs_loop(cfg.clone(), cache) => {}
+        Ok(()) = fs_loop(cfg.clone(), cache, meta) => {}
         Ok(()) = clean_data_dir(cfg.clone()) => {}
     };
 
     Ok(())
 }
 
+/// serves the redis protocol when `--resp-address` is given
+pub async fn resp_server(
+    cfg: RuntimeConfigArc,
+    cache: Db,
+    meta: MetaDb,
+    tx: transport::Sender,
+) -> Result<(), Box<dyn Error>> {
+    let address = { cfg.read().await.resp_address };
+    match address {
+        Some(address) => {
+            if let Err(e) = resp::serve(address, cache, meta, tx).await {
+                error!(%e, "could not serve the redis protocol on '{}'", address);
+                return Err(e.into());
+            }
+            Ok(())
+        }
+        None => {
+            futures::future::pending::<()>().await;
+            Ok(())
+        }
+    }
+}
+
+/// serves the memcached text protocol when `--memcache-address` is given
+pub async fn memcache_server(
+    cfg: RuntimeConfigArc,
+    cache: Db,
+    meta: MetaDb,
+    tx: transport::Sender,
+) -> Result<(), Box<dyn Error>> {
+    let address = { cfg.read().await.memcache_address };
+    match address {
+        Some(address) => {
+            if let Err(e) = memcache::serve(address, cache, meta, tx).await {
+                error!(%e, "could not serve the memcached protocol on '{}'", address);
+                return Err(e.into());
+            }
+            Ok(())
+        }
+        None => {
+            futures::future::pending::<()>().await;
+            Ok(())
+        }
+    }
+}
+
+/// serves the grpc api when `--grpc-address` is given
+pub async fn grpc_server(
+    cfg: RuntimeConfigArc,
+    cache: Db,
+    meta: MetaDb,
+    tx: transport::Sender,
+    raft: RaftArc,
+) -> Result<(), Box<dyn Error>> {
+    let address = { cfg.read().await.grpc_address };
+    match address {
+        Some(address) => {
+            let service = RacherService::new(cache, meta, cfg, tx, raft);
+            if let Err(e) = grpc::serve(address, service).await {
+                error!(%e, "could not serve grpc on '{}'", address);
+                return Err(e.into());
+            }
+            Ok(())
+        }
+        None => {
+            futures::future::pending::<()>().await;
+            Ok(())
+        }
+    }
+}
+
+/// removes the values of keys with an expiry that passed, and once a minute
+/// the metadata of keys that were deleted a long time ago
+pub async fn expire_keys(cache: Db, meta: MetaDb) -> Result<(), Infallible> {
+    let mut interval = time::interval(Duration::from_secs(1));
+    for tick in 1u64.. {
+        interval.tick().await;
+        let expired = metadata::remove_expired(&cache, &meta);
+        if expired > 0 {
+            debug!("{} keys expired", expired);
+        }
+        if tick % 60 == 0 {
+            let removed = metadata::remove_tombstones(&cache, &meta);
+            if removed > 0 {
+                debug!("removed {} tombstones", removed);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// reloads the config file on SIGHUP and applies the settings that can change while running
+#[cfg(unix)]
+pub async fn reload_config(cfg: RuntimeConfigArc, args: Args) -> Result<(), Box<dyn Error>> {
+    use structopt::StructOpt;
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = signal(SignalKind::hangup())?;
+    // the settings without the file, for the ones that are removed from it
+    let (plain, matches) = (Args::from_args_safe()?, Args::clap().get_matches_safe()?);
+    let defaults = plain.as_runtime_config();
+    while hangup.recv().await.is_some() {
+        let path = match args.config.as_ref() {
+            Some(path) => path,
+            None => {
+                warn!("received SIGHUP, but racher was started without --config");
+                continue;
+            }
+        };
+        // a broken file keeps the current settings
+        let file = match ConfigFile::load(path) {
+            Ok(x) => x,
+            Err(e) => {
+                error!(%e, "could not reload the config file");
+                continue;
+            }
+        };
+        let reloaded = plain.clone().merge_config_file(&file, &matches);
+        let update = {
+            let mut write_cfg = cfg.write().await;
+            if !write_cfg.same_rate_limits(&reloaded.as_runtime_config()) {
+                error!(
+                    "the rate limits can not be changed while running, the file was not reloaded"
+                );
+                continue;
+            }
+            write_cfg.reload(&file, &args.fixed, &defaults)
+        };
+        info!(?update, "config file reloaded");
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub async fn reload_config(_cfg: RuntimeConfigArc, _args: Args) -> Result<(), Box<dyn Error>> {
+    futures::future::pending().await
+}
+
+/// restores the identity and last known neighbours of a previous run
+pub async fn load_cluster_state(config: RuntimeConfigArc) -> Result<(), Box<dyn Error>> {
+    let backup_dir = { config.read().await.backup_dir.clone() };
+    if let Some(state) = ClusterState::load(&backup_dir).await? {
+        let mut write_cfg = config.write().await;
+        state.restore(&mut write_cfg);
+        info!(
+            identifier = %write_cfg.identifier,
+            neighbours = write_cfg.neighbours.len(),
+            "restored cluster state"
+        );
+    }
+    Ok(())
+}
+
+/// loads the newest backup, the versions and deleted keys end up in `meta`
 pub async fn load_from_backup(
     config: RuntimeConfigArc,
+    meta: MetaDb,
 ) -> Result<DashMap<String, Value>, Box<dyn Error>> {
     let read_cfg = config.read().await;
     if read_cfg.backup_skip_loading {
@@ -212,8 +737,21 @@ pub async fn load_from_backup(
         Some(x) => {
             let mut file = read_cfg.backup_dir.clone();
             file.push(x);
-            let contents = fs::read(file).await?;
-            Ok(serde_json::from_slice(&contents)?)
+            let contents = fs::read(&file).await?;
+            // backups written before messagepack was used are json
+            let encoding = match file.extension().and_then(|x| x.to_str()) {
+                Some("json") => Encoding::Json,
+                _ => BACKUP_ENCODING,
+            };
+            match encoding.decode(&contents)? {
+                BackupFile::Versioned(backup) => {
+                    for (key, metadata) in backup.meta {
+                        meta.insert(key, metadata);
+                    }
+                    Ok(backup.data)
+                }
+                BackupFile::Plain(data) => Ok(data),
+            }
         }
         None => Ok(DashMap::new()),
     }
@@ -231,8 +769,12 @@ pub async fn remove_backups(backup_dir: &PathBuf) -> Result<(), Box<dyn Error>>
     Ok(())
 }
 
-pub async fn join_cache(join_address: Url, config: RuntimeConfigArc) -> Result<Db, Box<dyn Error>> {
-    let mut client = crate::client::Client::new();
+pub async fn join_cache(
+    join_address: Url,
+    config: RuntimeConfigArc,
+    meta: MetaDb,
+) -> Result<Db, Box<dyn Error>> {
+    let mut client = crate::client::Client::from_config(&*config.read().await);
     client.ping(join_address.clone()).await?;
     let addr = {
         let read_config = config.read().await;
@@ -241,10 +783,187 @@ pub async fn join_cache(join_address: Url, config: RuntimeConfigArc) -> Result<D
     let response = client.join(addr, join_address.clone()).await?;
     let code = response.code;
     let neighbours = response.neighbours;
-    let response = client.sync(join_address.clone(), &code).await?;
+    let cache: Db = Arc::new(Cache::new());
+    let received = client
+        .sync(join_address.clone(), &code, &cache, &meta)
+        .await?;
+    info!("synced {} keys", received);
     let mut write_config = config.write().await;
-    write_config.neighbours.extend(neighbours);
-    write_config.neighbours.insert(join_address);
+    for neighbour in neighbours {
+        write_config.add_neighbour(neighbour);
+    }
+    write_config.add_neighbour(join_address);
     write_config.base_code = code;
-    Ok(response)
+    Ok(cache)
+}
+
+fn display_or_dash<T: std::fmt::Display>(value: Option<T>) -> String {
+    value
+        .map(|x| x.to_string())
+        .unwrap_or_else(|| String::from("-"))
+}
+
+/// reads the value for `racher set` from the argument, the file or stdin
+async fn read_value(
+    value: Option<&String>,
+    file: Option<&PathBuf>,
+    string: bool,
+) -> Result<Value, Box<dyn Error>> {
+    let input = match (value, file) {
+        (Some(value), _) => value.clone(),
+        (None, Some(file)) => fs::read_to_string(file).await?,
+        (None, None) => {
+            let mut input = String::new();
+            io::stdin().read_to_string(&mut input).await?;
+            input
+        }
+    };
+
+    if string {
+        return Ok(Value::String(input));
+    }
+    let value: Value = serde_json::from_str(&input).map_err(|e| {
+        format!(
+            "value is not valid json, use --string to store it as text: {}",
+            e
+        )
+    })?;
+    Ok(value)
+}
+
+/// runs a command against a running racher, returns false when the key did not exist
+pub async fn client_command(sub_cmd: &SubArg) -> Result<bool, Box<dyn Error>> {
+    let mut client = crate::client::Client::new();
+    match sub_cmd {
+        SubArg::Status {
+            client_args,
+            format,
+        } => {
+            print_status(client_args.address.clone(), format).await?;
+        }
+        SubArg::Get {
+            client_args,
+            key,
+            consistency,
+        } => {
+            // the rest route tells a stored null apart from a missing key
+            let mut client = ClusterClient::new(vec![client_args.address.clone()])?
+                .with_consistency(*consistency);
+            match client.get::<Value>(key).await? {
+                Some(value) => println!("{}", serde_json::to_string_pretty(&value)?),
+                None => return Ok(false),
+            }
+        }
+        SubArg::Set {
+            client_args,
+            key,
+            value,
+            file,
+            string,
+            consistency,
+        } => {
+            let value = read_value(value.as_ref(), file.as_ref(), *string).await?;
+            client
+                .set(client_args.address.clone(), key, &value, *consistency)
+                .await?;
+        }
+        SubArg::Del { client_args, key } => {
+            let response = client.del(client_args.address.clone(), key).await?;
+            return Ok(response.deleted);
+        }
+        SubArg::Keys { client_args } => {
+            let mut keys = client.keys(client_args.address.clone()).await?.keys;
+            keys.sort();
+            for key in keys {
+                println!("{}", key);
+            }
+        }
+        SubArg::Purge { client_args } => {
+            client.purge(client_args.address.clone()).await?;
+        }
+        SubArg::Ping { client_args } => {
+            client.ping(client_args.address.clone()).await?;
+            println!("pong");
+        }
+        SubArg::Shell { addresses } => {
+            crate::shell::run(addresses.clone()).await?;
+        }
+        SubArg::Join(_) | SubArg::RemoveBackup { .. } | SubArg::Config(_) => {
+            return Err(String::from("not a client command").into());
+        }
+    }
+
+    Ok(true)
+}
+
+/// validates the config file and prints which settings are overridden by env vars
+pub fn check_config(path: Option<&PathBuf>) -> Result<(), Box<dyn Error>> {
+    let path = path.ok_or("no config file given, pass a path or use --config")?;
+    let config = ConfigFile::load(path)?;
+
+    println!("{} is valid", path.display());
+    for (key, env, value) in config.settings() {
+        // the token and api keys should not end up in terminal scrollback or ci logs
+        let hide = |value: String| {
+            if key == "admin_token" || key == "cluster_secret" || key == "rate_limit.api_keys" {
+                String::from("<hidden>")
+            } else {
+                value
+            }
+        };
+        let value = hide(value);
+        match std::env::var(env).map(hide) {
+            Ok(overridden) => {
+                println!("{} = {} (overridden by {}={})", key, value, env, overridden)
+            }
+            Err(_) => println!("{} = {}", key, value),
+        }
+    }
+    Ok(())
+}
+
+pub async fn print_status(address: Url, format: &str) -> Result<(), Box<dyn Error>> {
+    let status = crate::client::Client::new().cluster_status(address).await?;
+    if format == "json" {
+        println!("{}", serde_json::to_string_pretty(&status)?);
+        return Ok(());
+    }
+
+    let mut rows = vec![[
+        String::from("IDENTIFIER"),
+        String::from("ADDRESS"),
+        String::from("STATUS"),
+        String::from("LAST SEEN"),
+        String::from("KEYS"),
+        String::from("VERSION GAP (ms)"),
+        String::from("BACKUP AGE (s)"),
+    ]];
+    for member in status.members {
+        rows.push([
+            member.identifier.unwrap_or_else(|| String::from("-")),
+            member.address.to_string(),
+            String::from(if member.up { "up" } else { "down" }),
+            display_or_dash(member.last_seen.map(|x| x.format("%Y-%m-%d %H:%M:%S"))),
+            display_or_dash(member.keys),
+            display_or_dash(member.version_gap_ms),
+            display_or_dash(member.backup_age_secs),
+        ]);
+    }
+
+    let mut widths = [0; 7];
+    for row in rows.iter() {
+        for (width, column) in widths.iter_mut().zip(row.iter()) {
+            *width = column.len().max(*width);
+        }
+    }
+    for row in rows {
+        let line: Vec<String> = row
+            .iter()
+            .zip(widths.iter())
+            .map(|(column, width)| format!("{:width$}", column, width = width))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    }
+
+    Ok(())
 }
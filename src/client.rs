@@ -1,28 +1,168 @@
-use crate::responses::{FanoutResponse, JoinResponse, PingResponse};
-use crate::Db;
-use futures::future;
-use reqwest::{Error, Request, Response};
+use crate::config::RuntimeConfig;
+use crate::encoding::{self, Encoding};
+use crate::metadata::{self, Metadata};
+use crate::raft::{AppendRequest, VoteRequest};
+use crate::rate_limit;
+use crate::responses::{
+    AppendResponse, Change, ChangesResponse, ClusterStatusResponse, DelResponse, ErrorCode,
+    ErrorResponse, FanoutResponse, GetResponse, InfoResponse, JoinResponse, KeysResponse,
+    PingResponse, PurgeResponse, SetResponse, VersionedResponse, VoteResponse,
+};
+use crate::routes::Consistency;
+use crate::sync::Arc;
+use crate::telemetry;
+use crate::{Db, MetaDb};
+use dashmap::DashMap;
+use futures::{future, stream, Future, StreamExt};
+use reqwest::header::{HeaderMap, HeaderValue, ACCEPT, CONTENT_TYPE};
+use reqwest::{Method, Request, Response, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 use serde_json::json;
 use serde_value::Value;
 use std::collections::HashSet;
 use std::error::Error as ErrorTrait;
+use std::time::{Duration, Instant};
+use tokio::time;
 use tower::util::BoxService;
 use tower::Service;
 use tower::ServiceExt;
-use tracing::{debug, error};
+use tracing::{debug, error, warn};
 use url::Url;
 
+const RAFT_TIMEOUT: Duration = Duration::from_millis(500);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+/// updates and reads of single keys on neighbours, a neighbour that takes longer
+/// must not hold up the replication or a quorum
+const INTERNAL_TIMEOUT: Duration = Duration::from_secs(3);
+/// format of the values sent between nodes
+const INTERNAL_ENCODING: Encoding = Encoding::MessagePack;
+const SYNC_ATTEMPTS: usize = 5;
+const SYNC_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// how far a sync got, so it can be resumed
+#[derive(Debug, Default)]
+struct SyncProgress {
+    after: Option<String>,
+    received: usize,
+}
+
+/// how often and how fast a failed request is tried again. requests that did not reach
+/// the other node are always retried, timeouts and 5xx answers only for requests that
+/// can be repeated safely
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// attempts after the first one
+    pub retries: usize,
+    /// the longest wait before the first retry, it doubles with every retry
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// no more retries once this much time passed since the first failure
+    pub max_elapsed: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            retries: 5,
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(2),
+            max_elapsed: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn from_config(cfg: &RuntimeConfig) -> Self {
+        RetryPolicy {
+            retries: cfg.retry_attempts,
+            initial_backoff: Duration::from_millis(cfg.retry_backoff),
+            ..Default::default()
+        }
+    }
+
+    /// a random wait between zero and the exponential backoff, so nodes that failed
+    /// at the same time do not retry at the same time
+    fn backoff(&self, attempt: u32) -> Duration {
+        let limit = self
+            .initial_backoff
+            .checked_mul(2u32.saturating_pow(attempt))
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff);
+        limit.mul_f64(rand::random::<f64>())
+    }
+}
+
+/// the paths of posts that can be sent twice without changing the outcome, the ones ending
+/// with a slash are prefixes. writes without a version, like `/set`, are left out, a repeated
+/// one could overwrite a newer write. so are joins, they are announced to the whole cluster
+const IDEMPOTENT_ROUTES: [&str; 11] = [
+    "/get/",
+    "/keys",
+    "/ping",
+    "/stats",
+    "/cluster/",
+    "/_internal/update/",
+    "/_internal/get/",
+    "/_internal/sync",
+    "/_internal/changes",
+    "/_internal/info",
+    "/_internal/config",
+];
+
+fn idempotent(request: &Request) -> bool {
+    match *request.method() {
+        Method::GET | Method::HEAD | Method::OPTIONS => true,
+        Method::POST => {
+            let path = request.url().path();
+            IDEMPOTENT_ROUTES
+                .iter()
+                .any(|route| path == *route || route.ends_with('/') && path.starts_with(route))
+        }
+        // `PUT` and `DELETE` on `/keys/:name` write without a version
+        _ => false,
+    }
+}
+
+/// the state of the retries of one request
 #[derive(Clone)]
-struct Limit(usize);
+struct Retry {
+    policy: RetryPolicy,
+    attempt: u32,
+    first_failure: Option<Instant>,
+}
 
-impl tower::retry::Policy<Request, Response, Error> for Limit {
-    type Future = future::Ready<Self>;
-    fn retry(&self, _: &Request, result: Result<&Response, &Error>) -> Option<Self::Future> {
-        if result.is_err() && self.0 > 0 {
-            Some(future::ready(Limit(self.0 - 1)))
-        } else {
-            None
+impl tower::retry::Policy<Request, Response, ClientError> for Retry {
+    type Future = future::BoxFuture<'static, Self>;
+    fn retry(
+        &self,
+        req: &Request,
+        result: Result<&Response, &ClientError>,
+    ) -> Option<Self::Future> {
+        let retry = match result {
+            Err(ClientError::Http(e)) => e.is_connect() || (e.is_timeout() && idempotent(req)),
+            Err(_) => false,
+            Ok(response) => response.status().is_server_error() && idempotent(req),
+        };
+        let first_failure = self.first_failure.unwrap_or_else(Instant::now);
+        if !retry
+            || self.attempt as usize >= self.policy.retries
+            || first_failure.elapsed() >= self.policy.max_elapsed
+        {
+            return None;
         }
+
+        crate::metrics::CLIENT_RETRIES.inc();
+        let delay = self.policy.backoff(self.attempt);
+        let next = Retry {
+            policy: self.policy,
+            attempt: self.attempt + 1,
+            first_failure: Some(first_failure),
+        };
+        Some(Box::pin(async move {
+            time::sleep(delay).await;
+            next
+        }))
     }
 
     fn clone_request(&self, req: &reqwest::Request) -> Option<reqwest::Request> {
@@ -30,35 +170,190 @@ impl tower::retry::Policy<Request, Response, Error> for Limit {
     }
 }
 
+/// failures in a row after which requests to a node fail right away
+const BREAKER_THRESHOLD: u32 = 5;
+/// how long a node is skipped before one request is let through to see if it is back
+const BREAKER_COOLDOWN: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Default)]
+struct Breaker {
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+/// a circuit breaker per node, shared by a client and its clones so a dead node does not
+/// hold up the requests to the others
+type Breakers = Arc<DashMap<String, Breaker>>;
+
+/// whether a request to the node may be sent, after the cooldown one request is let through
+/// and the others keep failing until it answered
+fn breaker_allows(breakers: &Breakers, origin: &str) -> bool {
+    let mut breaker = match breakers.get_mut(origin) {
+        Some(breaker) => breaker,
+        None => return true,
+    };
+    match breaker.open_until {
+        Some(until) if until > Instant::now() => false,
+        Some(_) => {
+            breaker.open_until = Some(Instant::now() + BREAKER_COOLDOWN);
+            true
+        }
+        None => true,
+    }
+}
+
+fn breaker_record(breakers: &Breakers, origin: &str, failed: bool) {
+    if !failed {
+        breakers.remove(origin);
+        return;
+    }
+    let mut breaker = breakers.entry(origin.to_string()).or_default();
+    breaker.failures += 1;
+    if breaker.failures >= BREAKER_THRESHOLD {
+        if breaker.open_until.is_none() {
+            warn!(
+                "'{}' failed {} times, skipping it for {:?}",
+                origin, breaker.failures, BREAKER_COOLDOWN
+            );
+        }
+        breaker.open_until = Some(Instant::now() + BREAKER_COOLDOWN);
+    }
+}
+
+/// sends a request unless the circuit of its node is open. only answers of a node that is
+/// broken count as failures, a `503` is also sent by healthy nodes that miss a quorum
+async fn execute(
+    client: reqwest::Client,
+    breakers: Breakers,
+    request: Request,
+) -> Result<Response, ClientError> {
+    let origin = request.url().origin().ascii_serialization();
+    if !breaker_allows(&breakers, &origin) {
+        return Err(ClientError::CircuitOpen(origin));
+    }
+    let result = client.execute(request).await;
+    let failed = match &result {
+        Ok(response) => matches!(
+            response.status(),
+            StatusCode::INTERNAL_SERVER_ERROR
+                | StatusCode::BAD_GATEWAY
+                | StatusCode::GATEWAY_TIMEOUT
+        ),
+        Err(e) => e.is_connect() || e.is_timeout(),
+    };
+    breaker_record(&breakers, &origin, failed);
+    Ok(result?)
+}
+
+/// how requests to many nodes at once are sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BroadcastPolicy {
+    /// requests that run at the same time
+    pub concurrency: usize,
+    /// the time all requests together may take
+    pub deadline: Duration,
+}
+
+impl Default for BroadcastPolicy {
+    fn default() -> Self {
+        BroadcastPolicy {
+            concurrency: 16,
+            deadline: Duration::from_secs(5),
+        }
+    }
+}
+
+impl BroadcastPolicy {
+    pub fn from_config(cfg: &RuntimeConfig) -> Self {
+        BroadcastPolicy {
+            concurrency: cfg.broadcast_concurrency,
+            deadline: Duration::from_millis(cfg.broadcast_timeout),
+        }
+    }
+}
+
+/// the outcome of a request to one of many nodes
+pub type HostResult<T> = (Url, Result<T, ClientError>);
+
 #[derive(Debug)]
 pub struct Client {
     pub client: reqwest::Client,
-    pub service: BoxService<Request, Response, Error>,
+    pub service: BoxService<Request, Response, ClientError>,
+    retry: RetryPolicy,
+    broadcast: BroadcastPolicy,
+    breakers: Breakers,
 }
 
 impl Client {
     pub fn new() -> Self {
-        let client = reqwest::Client::new();
+        Self::with_retry(RetryPolicy::default())
+    }
+
+    pub fn with_retry(retry: RetryPolicy) -> Self {
+        Self::with_headers(retry, HeaderMap::new())
+    }
+
+    fn with_headers(retry: RetryPolicy, headers: HeaderMap) -> Self {
+        // requests only have a timeout of their own, syncs of large caches take a while
+        let client = reqwest::Client::builder()
+            .connect_timeout(CONNECT_TIMEOUT)
+            .default_headers(headers)
+            .build()
+            .unwrap_or_default();
+        let breakers = Breakers::default();
         Client {
-            service: Self::build_client(client.clone()),
-            client: client,
+            service: Self::build_client(client.clone(), breakers.clone(), retry),
+            client,
+            retry,
+            broadcast: BroadcastPolicy::default(),
+            breakers,
         }
     }
 
-    pub async fn call(&mut self, req: Request) -> Result<Response, Error> {
+    /// a client with the retries and broadcasts of the config, for requests to the neighbours
+    pub fn from_config(cfg: &RuntimeConfig) -> Self {
+        let client = match cfg.cluster_secret.as_deref() {
+            Some(secret) => Self::with_secret(RetryPolicy::from_config(cfg), secret),
+            None => Self::with_retry(RetryPolicy::from_config(cfg)),
+        };
+        client.with_broadcast(BroadcastPolicy::from_config(cfg))
+    }
+
+    /// sends the cluster secret with every request, so the neighbours do not rate limit them
+    pub fn with_secret(retry: RetryPolicy, secret: &str) -> Self {
+        let mut headers = HeaderMap::new();
+        if let Ok(mut secret) = HeaderValue::from_str(secret) {
+            secret.set_sensitive(true);
+            headers.insert(rate_limit::SECRET_HEADER, secret);
+        }
+        Self::with_headers(retry, headers)
+    }
+
+    pub fn with_broadcast(mut self, broadcast: BroadcastPolicy) -> Self {
+        self.broadcast = broadcast;
+        self
+    }
+
+    pub async fn call(&mut self, req: Request) -> Result<Response, ClientError> {
         self.service.ready().await?.call(req).await
     }
 
-    pub async fn call_owned(mut self, req: Request) -> Result<Response, Error> {
+    pub async fn call_owned(mut self, req: Request) -> Result<Response, ClientError> {
         self.service.ready().await?.call(req).await
     }
 
-    pub async fn internal_update(&mut self, mut send_to: Url, key: &str, value: &Value) -> () {
+    pub async fn internal_update(
+        &mut self,
+        mut send_to: Url,
+        key: &str,
+        value: &Value,
+        metadata: &Metadata,
+    ) -> Result<(), Box<dyn ErrorTrait>> {
         debug!("update other host '{}' of key '{}'", send_to, key);
 
         if send_to.cannot_be_a_base() {
             error!("invalid url '{}'", send_to.as_str());
-            return;
+            return Err(String::from("invalid url").into());
         }
         send_to
             .path_segments_mut()
@@ -66,67 +361,305 @@ impl Client {
             .push("_internal")
             .push("update")
             .push(key);
+        {
+            let mut query = send_to.query_pairs_mut();
+            query.append_pair("version", &metadata.version.to_string());
+            if let Some(content_type) = &metadata.content_type {
+                query.append_pair("content_type", content_type);
+            }
+            if let Some(expires_at) = metadata.expires_at {
+                query.append_pair("expires_at", &expires_at.to_string());
+            }
+            if let Some(flags) = metadata.flags {
+                query.append_pair("flags", &flags.to_string());
+            }
+            if metadata.deleted {
+                query.append_pair("deleted", "true");
+            }
+        }
 
-        match self.client.post(send_to).json(value).build() {
-            Ok(request) => match self.call(request).await {
-                Ok(_) => (),
-                Err(e) => error!(%e),
-            },
+        let mut encoding = INTERNAL_ENCODING;
+        let result = loop {
+            let body = encoding.encode(&encoding::raw_to_json(value.clone(), encoding))?;
+            let request = self
+                .client
+                .post(send_to.clone())
+                .header(CONTENT_TYPE, encoding.content_type())
+                .body(body)
+                .timeout(INTERNAL_TIMEOUT)
+                .build();
+            let result = match request {
+                Ok(mut request) => {
+                    telemetry::inject_headers(request.headers_mut());
+                    self.call(request).await
+                }
+                Err(e) => Err(e.into()),
+            };
+            match result {
+                // nodes that do not know the binary format only take json
+                Ok(response)
+                    if response.status() == StatusCode::UNSUPPORTED_MEDIA_TYPE
+                        && encoding != Encoding::Json =>
+                {
+                    debug!("'{}' does not take {:?}, sending json", send_to, encoding);
+                    encoding = Encoding::Json;
+                }
+                result => break result.and_then(|response| Ok(response.error_for_status()?)),
+            }
+        };
+        match &result {
+            // the node failed before, that was logged already
+            Err(ClientError::CircuitOpen(_)) => debug!("skipped update of '{}'", key),
             Err(e) => error!(%e),
+            Ok(_) => {}
         }
+        result?;
+        Ok(())
     }
 
-    pub async fn ping(&mut self, mut address: Url) -> Result<PingResponse, Box<dyn ErrorTrait>> {
-        debug!("ping address '{}'", address);
+    pub async fn internal_get(
+        &mut self,
+        mut address: Url,
+        key: &str,
+    ) -> Result<VersionedResponse, Box<dyn ErrorTrait>> {
+        debug!("get key '{}' from other host '{}'", key, address);
+
         address
             .path_segments_mut()
             .map_err(|_| String::from("invalid url"))?
-            .push("ping");
+            .push("_internal")
+            .push("get")
+            .push(key);
 
-        let request = self.client.post(address).build()?;
-        let response: PingResponse = self.call(request).await?.json().await?;
+        let request = self
+            .client
+            .post(address)
+            .header(ACCEPT, INTERNAL_ENCODING.content_type())
+            .timeout(INTERNAL_TIMEOUT)
+            .build()?;
+        let response = self.call(request).await?.error_for_status()?;
+        let (mut response, encoding): (VersionedResponse, _) = Self::decode(response).await?;
+        let content_type = response.meta.content_type.as_deref();
+        response.data = encoding::raw_from_json(response.data, content_type, encoding);
         Ok(response)
     }
 
-    pub async fn ping_all(
-        &mut self,
-        addresses: HashSet<Url>,
-    ) -> Result<HashSet<Url>, Box<dyn ErrorTrait>> {
-        let mut requests = Vec::new();
-        for address in addresses {
-            debug!("ping address '{}'", address);
-            let mut endpoint = address.clone();
-            endpoint
-                .path_segments_mut()
-                .map_err(|_| String::from("invalid url"))?
-                .push("ping");
-
-            let request = self.client.post(endpoint).build()?;
-            requests.push((address, self.clone().call_owned(request)));
-        }
-
-        let mut valid_urls = HashSet::new();
-        for (address, request) in requests {
-            if let Ok(_) = request.await {
-                valid_urls.insert(address);
-            }
+    /// decodes the body in the format given by its content type, the format is returned
+    /// as well so raw values in json can be restored
+    async fn decode<T: DeserializeOwned>(
+        response: Response,
+    ) -> Result<(T, Encoding), Box<dyn ErrorTrait>> {
+        let encoding = Self::encoding_of(&response)?;
+        Ok((encoding.decode(&response.bytes().await?)?, encoding))
+    }
+
+    fn encoding_of(response: &Response) -> Result<Encoding, String> {
+        let content_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|x| x.to_str().ok());
+        Encoding::from_stream_content_type(content_type)
+            .ok_or_else(|| format!("unsupported content type {:?}", content_type))
+    }
+
+    /// parses the body of a successful response, otherwise returns the `ErrorResponse`
+    /// of the server
+    async fn parse<T: DeserializeOwned>(response: Response) -> Result<T, Box<dyn ErrorTrait>> {
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response.json().await?);
         }
 
-        Ok(valid_urls)
+        match response.json::<ErrorResponse>().await {
+            Ok(error) => Err(error.into()),
+            Err(_) => Err(status.to_string().into()),
+        }
     }
 
-    pub async fn join(
+    pub async fn get(
         &mut self,
-        me: Url,
-        mut join_with: Url,
-    ) -> Result<JoinResponse, Box<dyn ErrorTrait>> {
-        debug!("joining host '{}' with my address '{}'", join_with, me);
+        mut address: Url,
+        key: &str,
+        consistency: Consistency,
+    ) -> Result<GetResponse, Box<dyn ErrorTrait>> {
+        debug!("get key '{}' from '{}'", key, address);
+        address
+            .path_segments_mut()
+            .map_err(|_| String::from("invalid url"))?
+            .push("get")
+            .push(key);
 
-        join_with
+        let request = self
+            .client
+            .post(address)
+            .query(&[("consistency", consistency)])
+            .build()?;
+        Self::parse(self.call(request).await?).await
+    }
+
+    pub async fn set(
+        &mut self,
+        mut address: Url,
+        key: &str,
+        value: &Value,
+        consistency: Consistency,
+    ) -> Result<SetResponse, Box<dyn ErrorTrait>> {
+        debug!("set key '{}' on '{}'", key, address);
+        address
             .path_segments_mut()
             .map_err(|_| String::from("invalid url"))?
-            .push("_internal")
-            .push("join");
+            .push("set")
+            .push(key);
+
+        let request = self
+            .client
+            .post(address)
+            .query(&[("consistency", consistency)])
+            .json(value)
+            .build()?;
+        Self::parse(self.call(request).await?).await
+    }
+
+    pub async fn del(
+        &mut self,
+        mut address: Url,
+        key: &str,
+    ) -> Result<DelResponse, Box<dyn ErrorTrait>> {
+        debug!("delete key '{}' on '{}'", key, address);
+        address
+            .path_segments_mut()
+            .map_err(|_| String::from("invalid url"))?
+            .push("del")
+            .push(key);
+
+        let request = self.client.post(address).build()?;
+        Self::parse(self.call(request).await?).await
+    }
+
+    pub async fn keys(&mut self, mut address: Url) -> Result<KeysResponse, Box<dyn ErrorTrait>> {
+        debug!("keys of '{}'", address);
+        address
+            .path_segments_mut()
+            .map_err(|_| String::from("invalid url"))?
+            .push("keys");
+
+        let request = self.client.post(address).build()?;
+        Self::parse(self.call(request).await?).await
+    }
+
+    pub async fn purge(&mut self, mut address: Url) -> Result<PurgeResponse, Box<dyn ErrorTrait>> {
+        debug!("purge '{}'", address);
+        address
+            .path_segments_mut()
+            .map_err(|_| String::from("invalid url"))?
+            .push("purge");
+
+        let request = self.client.post(address).build()?;
+        Self::parse(self.call(request).await?).await
+    }
+
+    /// the address with the segments appended to its path
+    fn endpoint(mut address: Url, segments: &[&str]) -> Result<Url, ClientError> {
+        if address.cannot_be_a_base() {
+            return Err(ClientError::InvalidUrl(address));
+        }
+        address
+            .path_segments_mut()
+            .expect("checked this before")
+            .extend(segments);
+        Ok(address)
+    }
+
+    /// sends a request to every address, at most `concurrency` at the same time. requests
+    /// that did not finish before the deadline fail with `ClientError::Timeout`
+    async fn broadcast<T, F, Fut>(
+        &mut self,
+        addresses: HashSet<Url>,
+        request: F,
+    ) -> Vec<HostResult<T>>
+    where
+        F: Fn(Client, Url) -> Fut,
+        Fut: Future<Output = Result<T, ClientError>>,
+    {
+        let deadline = time::Instant::now() + self.broadcast.deadline;
+        let requests: Vec<_> = addresses
+            .into_iter()
+            .map(|address| {
+                let request = request(self.clone(), address.clone());
+                async move {
+                    let result = match time::timeout_at(deadline, request).await {
+                        Ok(result) => result,
+                        Err(_) => Err(ClientError::Timeout(address.clone())),
+                    };
+                    (address, result)
+                }
+            })
+            .collect();
+
+        stream::iter(requests)
+            .buffer_unordered(self.broadcast.concurrency.max(1))
+            .collect()
+            .await
+    }
+
+    pub async fn ping(&mut self, address: Url) -> Result<PingResponse, ClientError> {
+        debug!("ping address '{}'", address);
+        let address = Self::endpoint(address, &["ping"])?;
+
+        let request = self.client.post(address).build()?;
+        let response: PingResponse = self.call(request).await?.json().await?;
+        Ok(response)
+    }
+
+    /// pings every address, see `broadcast`
+    pub async fn ping_all(&mut self, addresses: HashSet<Url>) -> Vec<HostResult<PingResponse>> {
+        self.broadcast(addresses, |mut client, address| async move {
+            client.ping(address).await
+        })
+        .await
+    }
+
+    pub async fn info(&mut self, address: Url) -> Result<InfoResponse, ClientError> {
+        debug!("info of address '{}'", address);
+        let address = Self::endpoint(address, &["_internal", "info"])?;
+
+        let request = self.client.post(address).build()?;
+        let response: InfoResponse = self.call(request).await?.error_for_status()?.json().await?;
+        Ok(response)
+    }
+
+    /// fetches the info of every address, addresses that do not respond are left out
+    pub async fn info_all(&mut self, addresses: HashSet<Url>) -> Vec<(Url, InfoResponse)> {
+        let results = self
+            .broadcast(addresses, |mut client, address| async move {
+                client.info(address).await
+            })
+            .await;
+        results
+            .into_iter()
+            .filter_map(|(address, result)| Some((address, result.ok()?)))
+            .collect()
+    }
+
+    pub async fn cluster_status(
+        &mut self,
+        mut address: Url,
+    ) -> Result<ClusterStatusResponse, Box<dyn ErrorTrait>> {
+        address
+            .path_segments_mut()
+            .map_err(|_| String::from("invalid url"))?
+            .push("cluster")
+            .push("status");
+
+        let request = self.client.post(address).build()?;
+        let response: ClusterStatusResponse =
+            self.call(request).await?.error_for_status()?.json().await?;
+        Ok(response)
+    }
+
+    pub async fn join(&mut self, me: Url, join_with: Url) -> Result<JoinResponse, ClientError> {
+        debug!("joining host '{}' with my address '{}'", join_with, me);
+        let join_with = Self::endpoint(join_with, &["_internal", "join"])?;
 
         let value = json!({"host": me.as_str()});
 
@@ -135,51 +668,143 @@ impl Client {
         Ok(response)
     }
 
+    /// joins every address, see `broadcast`
     pub async fn join_all(
         &mut self,
         me: Url,
         addresses: HashSet<Url>,
-    ) -> Result<(), Box<dyn ErrorTrait>> {
-        let mut requests = Vec::new();
-        for mut join_with in addresses {
-            debug!("joining host '{}' with my address '{}'", join_with, me);
+    ) -> Vec<HostResult<JoinResponse>> {
+        self.broadcast(addresses, |mut client, address| {
+            let me = me.clone();
+            async move { client.join(me, address).await }
+        })
+        .await
+    }
 
-            join_with
-                .path_segments_mut()
-                .map_err(|_| String::from("invalid url"))?
-                .push("_internal")
-                .push("join");
+    /// streams the data of `sync_with` into the cache, a broken transfer is resumed after
+    /// the last key that was received. returns the amount of keys that were received
+    pub async fn sync(
+        &mut self,
+        mut sync_with: Url,
+        code: &str,
+        cache: &Db,
+        meta: &MetaDb,
+    ) -> Result<usize, Box<dyn ErrorTrait>> {
+        debug!("syncing with host '{}'", sync_with);
 
-            let value = json!({"host": me.as_str()});
+        sync_with
+            .path_segments_mut()
+            .map_err(|_| String::from("invalid url"))?
+            .push("_internal")
+            .push("sync");
 
-            let request = self.client.post(join_with).json(&value).build()?;
-            requests.push(self.clone().call_owned(request));
+        let mut progress = SyncProgress::default();
+        let mut failures = 0;
+        loop {
+            let received = progress.received;
+            // the error is not Send, so it has to be gone before the sleep
+            {
+                let result = self
+                    .sync_stream(sync_with.clone(), code, cache, meta, &mut progress)
+                    .await;
+                let e = match result {
+                    Ok(()) => return Ok(progress.received),
+                    Err(e) => e,
+                };
+                // a wrong code or a missing endpoint does not get better by trying again
+                if e.downcast_ref::<reqwest::Error>()
+                    .is_some_and(|e| e.is_status())
+                {
+                    return Err(e);
+                }
+                failures = if progress.received > received {
+                    1
+                } else {
+                    failures + 1
+                };
+                if failures >= SYNC_ATTEMPTS {
+                    return Err(e);
+                }
+                warn!(
+                    "sync with '{}' broke off after {} keys, resuming: {}",
+                    sync_with, progress.received, e
+                );
+            }
+            time::sleep(SYNC_RETRY_DELAY).await;
         }
+    }
 
-        for request in requests {
-            request.await.ok();
+    /// reads one sync response key by key, `progress` is updated after every key
+    async fn sync_stream(
+        &mut self,
+        sync_with: Url,
+        code: &str,
+        cache: &Db,
+        meta: &MetaDb,
+        progress: &mut SyncProgress,
+    ) -> Result<(), Box<dyn ErrorTrait>> {
+        let value = json!({ "code": code, "after": progress.after });
+        let request = self
+            .client
+            .post(sync_with)
+            .header(ACCEPT, INTERNAL_ENCODING.content_type())
+            .json(&value)
+            .build()?;
+        let mut response = self.call(request).await?.error_for_status()?;
+        // nodes that do not know the binary format answer with json
+        let encoding = Self::encoding_of(&response)?;
+
+        let mut buffer = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            buffer.extend_from_slice(&chunk);
+            let mut start = 0;
+            while let Some((change, used)) = encoding.read_frame::<Change>(&buffer[start..])? {
+                start += used;
+                let content_type = change.meta.content_type.as_deref();
+                let data = encoding::raw_from_json(change.data, content_type, encoding);
+                metadata::apply(cache, meta, change.key.clone(), data, change.meta);
+                progress.after = Some(change.key);
+                progress.received += 1;
+            }
+            buffer.drain(..start);
         }
 
+        if !buffer.is_empty() {
+            return Err(String::from("sync stream ended in the middle of a key").into());
+        }
         Ok(())
     }
 
-    pub async fn sync(
+    /// the keys that changed after `since`, including deleted ones
+    pub async fn changes(
         &mut self,
         mut sync_with: Url,
         code: &str,
-    ) -> Result<Db, Box<dyn ErrorTrait>> {
-        debug!("syncing with host '{}'", sync_with);
+        since: u64,
+    ) -> Result<ChangesResponse, Box<dyn ErrorTrait>> {
+        debug!("fetching changes since {} from host '{}'", since, sync_with);
 
         sync_with
             .path_segments_mut()
             .map_err(|_| String::from("invalid url"))?
             .push("_internal")
-            .push("sync");
+            .push("changes");
 
-        let value = json!({ "code": code });
+        let value = json!({ "code": code, "since": since });
 
-        let request = self.client.post(sync_with).json(&value).build()?;
-        let response: Db = self.call(request).await?.json().await?;
+        let request = self
+            .client
+            .post(sync_with)
+            .header(ACCEPT, INTERNAL_ENCODING.content_type())
+            .json(&value)
+            .build()?;
+        let response = self.call(request).await?.error_for_status()?;
+        let (mut response, encoding): (ChangesResponse, _) = Self::decode(response).await?;
+        for change in response.changes.iter_mut() {
+            let data = std::mem::replace(&mut change.data, Value::Unit);
+            let content_type = change.meta.content_type.as_deref();
+            change.data = encoding::raw_from_json(data, content_type, encoding);
+        }
         Ok(response)
     }
 
@@ -204,11 +829,114 @@ impl Client {
         Ok(response)
     }
 
-    pub fn build_client(client: reqwest::Client) -> BoxService<Request, Response, Error> {
+    /// raft rpcs are not retried, a missed vote or heartbeat is resent by the next round
+    pub async fn raft_vote(
+        &mut self,
+        mut address: Url,
+        vote: &VoteRequest,
+    ) -> Result<VoteResponse, Box<dyn ErrorTrait>> {
+        address
+            .path_segments_mut()
+            .map_err(|_| String::from("invalid url"))?
+            .push("_internal")
+            .push("raft")
+            .push("vote");
+
+        let response = self
+            .client
+            .post(address)
+            .json(vote)
+            .timeout(RAFT_TIMEOUT)
+            .send()
+            .await?
+            .json()
+            .await?;
+        Ok(response)
+    }
+
+    pub async fn raft_append(
+        &mut self,
+        mut address: Url,
+        append: &AppendRequest,
+    ) -> Result<AppendResponse, Box<dyn ErrorTrait>> {
+        address
+            .path_segments_mut()
+            .map_err(|_| String::from("invalid url"))?
+            .push("_internal")
+            .push("raft")
+            .push("append");
+
+        let response = self
+            .client
+            .post(address)
+            .json(append)
+            .timeout(RAFT_TIMEOUT)
+            .send()
+            .await?
+            .json()
+            .await?;
+        Ok(response)
+    }
+
+    /// forwards a strongly consistent write to the raft leader
+    pub async fn strong_set(
+        &mut self,
+        mut leader: Url,
+        key: &str,
+        value: &Value,
+    ) -> Result<SetResponse, Box<dyn ErrorTrait>> {
+        debug!("forwarding set of key '{}' to leader '{}'", key, leader);
+
+        leader
+            .path_segments_mut()
+            .map_err(|_| String::from("invalid url"))?
+            .push("set")
+            .push(key);
+        leader.set_query(Some("consistency=strong&forwarded=true"));
+
+        let request = self.client.post(leader).json(value).build()?;
+        let response: SetResponse = self.call(request).await?.json().await?;
+        Ok(response)
+    }
+
+    /// forwards a strongly consistent read to the raft leader
+    pub async fn strong_get(
+        &mut self,
+        mut leader: Url,
+        key: &str,
+    ) -> Result<VersionedResponse, Box<dyn ErrorTrait>> {
+        debug!("forwarding get of key '{}' to leader '{}'", key, leader);
+
+        leader
+            .path_segments_mut()
+            .map_err(|_| String::from("invalid url"))?
+            .push("get")
+            .push(key);
+        leader.set_query(Some("consistency=strong&forwarded=true"));
+
+        let request = self.client.post(leader).build()?;
+        let mut response: VersionedResponse = self.call(request).await?.json().await?;
+        let content_type = response.meta.content_type.as_deref();
+        response.data = encoding::raw_from_json(response.data, content_type, Encoding::Json);
+        Ok(response)
+    }
+
+    fn build_client(
+        client: reqwest::Client,
+        breakers: Breakers,
+        retry: RetryPolicy,
+    ) -> BoxService<Request, Response, ClientError> {
+        let retry = Retry {
+            policy: retry,
+            attempt: 0,
+            first_failure: None,
+        };
         let svc = tower::ServiceBuilder::new()
             // .rate_limit(100, Duration::new(10, 0)) // 100 requests every 10 seconds
-            .retry(Limit(50))
-            .service(tower::service_fn(move |req| client.execute(req)));
+            .retry(retry)
+            .service(tower::service_fn(move |req| {
+                execute(client.clone(), breakers.clone(), req)
+            }));
 
         // let mut req = Request::new(Method::POST, Url::parse("http://httpbin.org/post")?);
         // *req.body_mut() = Some(Body::from("the exact body that is sent"));
@@ -222,8 +950,262 @@ impl Clone for Client {
     fn clone(&self) -> Self {
         let client = self.client.clone();
         Client {
-            service: Self::build_client(client.clone()),
-            client: client,
+            service: Self::build_client(client.clone(), self.breakers.clone(), self.retry),
+            client,
+            retry: self.retry,
+            broadcast: self.broadcast,
+            breakers: self.breakers.clone(),
+        }
+    }
+}
+
+/// the errors of `ClusterClient`
+#[derive(Debug)]
+pub enum ClientError {
+    /// the request could not be sent or the response could not be read
+    Http(reqwest::Error),
+    /// the node answered with an error
+    Server(ErrorResponse),
+    /// the node answered with an error status but without an error body
+    Status(reqwest::StatusCode),
+    /// a value could not be serialized or deserialized
+    Encoding(String),
+    InvalidUrl(Url),
+    NoNodes,
+    /// the node failed too often and is skipped for a while
+    CircuitOpen(String),
+    /// the node did not answer before the deadline
+    Timeout(Url),
+}
+
+impl ClientError {
+    /// errors of a node that is down or overloaded, another node may be able to answer
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            ClientError::Http(_) | ClientError::CircuitOpen(_) | ClientError::Timeout(_) => true,
+            ClientError::Server(error) => error.code.status().is_server_error(),
+            ClientError::Status(status) => status.is_server_error(),
+            _ => false,
+        }
+    }
+
+    /// the request did not reach the node, so it can be sent to another one
+    pub fn is_unsent(&self) -> bool {
+        match self {
+            ClientError::Http(e) => e.is_connect(),
+            ClientError::CircuitOpen(_) => true,
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientError::Http(e) => write!(f, "{}", e),
+            ClientError::Server(e) => write!(f, "{}", e),
+            ClientError::Status(status) => write!(f, "{}", status),
+            ClientError::Encoding(e) => write!(f, "{}", e),
+            ClientError::InvalidUrl(url) => write!(f, "invalid url '{}'", url),
+            ClientError::NoNodes => write!(f, "no nodes to connect to"),
+            ClientError::CircuitOpen(node) => write!(f, "'{}' is skipped after failing", node),
+            ClientError::Timeout(node) => write!(f, "'{}' did not answer in time", node),
+        }
+    }
+}
+
+impl ErrorTrait for ClientError {
+    fn source(&self) -> Option<&(dyn ErrorTrait + 'static)> {
+        match self {
+            ClientError::Http(e) => Some(e),
+            ClientError::Server(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for ClientError {
+    fn from(e: reqwest::Error) -> Self {
+        ClientError::Http(e)
+    }
+}
+
+/// a client for applications, it sends every request to one node of the cluster
+/// and moves on to the next one when that node cannot answer
+pub struct ClusterClient {
+    client: Client,
+    nodes: Vec<Url>,
+    /// the node that answered last, requests start there
+    current: usize,
+    consistency: Consistency,
+}
+
+impl ClusterClient {
+    pub fn new(nodes: Vec<Url>) -> Result<Self, ClientError> {
+        if nodes.is_empty() {
+            return Err(ClientError::NoNodes);
+        }
+        if let Some(node) = nodes.iter().find(|node| node.cannot_be_a_base()) {
+            return Err(ClientError::InvalidUrl(node.clone()));
+        }
+        Ok(ClusterClient {
+            // a node that is down is skipped after a few tries, the others can answer instead
+            client: Client::with_retry(RetryPolicy {
+                retries: 2,
+                ..Default::default()
+            }),
+            nodes,
+            current: 0,
+            consistency: Consistency::default(),
+        })
+    }
+
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.client = Client::with_retry(retry);
+        self
+    }
+
+    /// the consistency of gets and sets, `one` by default
+    pub fn with_consistency(mut self, consistency: Consistency) -> Self {
+        self.consistency = consistency;
+        self
+    }
+
+    /// the node requests are sent to first
+    pub fn node(&self) -> &Url {
+        &self.nodes[self.current]
+    }
+
+    fn endpoint(node: &Url, segments: &[&str]) -> Url {
+        let mut url = node.clone();
+        url.path_segments_mut()
+            .expect("checked in new")
+            .pop_if_empty()
+            .extend(segments);
+        url
+    }
+
+    /// turns error statuses into `ClientError`
+    async fn check(response: Response) -> Result<Response, ClientError> {
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        match response.json::<ErrorResponse>().await {
+            Ok(error) => Err(ClientError::Server(error)),
+            Err(_) => Err(ClientError::Status(status)),
+        }
+    }
+
+    /// sends the request to the nodes in turn until one of them answers
+    async fn send<F>(&mut self, segments: &[&str], build: F) -> Result<Response, ClientError>
+    where
+        F: Fn(&reqwest::Client, Url) -> reqwest::RequestBuilder,
+    {
+        let mut last_error = ClientError::NoNodes;
+        for offset in 0..self.nodes.len() {
+            let index = (self.current + offset) % self.nodes.len();
+            let url = Self::endpoint(&self.nodes[index], segments);
+            let request = build(&self.client.client, url).build()?;
+            // a write that timed out or failed on the node may still be applied,
+            // sending it to another node could apply it twice
+            let idempotent = idempotent(&request);
+            let result = match self.client.call(request).await {
+                Ok(response) => Self::check(response).await,
+                Err(e) => Err(e),
+            };
+            match result {
+                Err(e) if e.is_retryable() && (idempotent || e.is_unsent()) => {
+                    warn!(%e, "node '{}' could not answer", self.nodes[index]);
+                    last_error = e;
+                }
+                result => {
+                    self.current = index;
+                    return result;
+                }
+            }
         }
+        Err(last_error)
+    }
+
+    async fn parse<T: DeserializeOwned>(response: Response) -> Result<T, ClientError> {
+        let bytes = response.bytes().await?;
+        serde_json::from_slice(&bytes).map_err(|e| ClientError::Encoding(e.to_string()))
+    }
+
+    /// the value of the key, `None` when it does not exist
+    pub async fn get<T: DeserializeOwned>(&mut self, key: &str) -> Result<Option<T>, ClientError> {
+        debug!("get key '{}'", key);
+        let consistency = self.consistency;
+        // the rest route tells missing keys and stored nulls apart
+        let result = self
+            .send(&["keys", key], |client, url| {
+                client
+                    .get(url)
+                    .header(ACCEPT, Encoding::Json.content_type())
+                    .query(&[("consistency", consistency)])
+            })
+            .await;
+        let response = match result {
+            Ok(response) => response,
+            Err(ClientError::Server(error)) if error.code == ErrorCode::NotFound => {
+                return Ok(None)
+            }
+            Err(e) => return Err(e),
+        };
+        if Self::content_type(&response) != Some(Encoding::Json.content_type()) {
+            return Err(ClientError::Encoding(format!(
+                "'{}' holds a raw value, it can not be deserialized",
+                key
+            )));
+        }
+        Ok(Some(Self::parse(response).await?))
+    }
+
+    fn content_type(response: &Response) -> Option<&str> {
+        response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|x| x.to_str().ok())
+    }
+
+    pub async fn set<T: Serialize + ?Sized>(
+        &mut self,
+        key: &str,
+        value: &T,
+    ) -> Result<SetResponse, ClientError> {
+        debug!("set key '{}'", key);
+        let consistency = self.consistency;
+        let body = serde_json::to_vec(value).map_err(|e| ClientError::Encoding(e.to_string()))?;
+        let response = self
+            .send(&["set", key], |client, url| {
+                client
+                    .post(url)
+                    .header(CONTENT_TYPE, Encoding::Json.content_type())
+                    .query(&[("consistency", consistency)])
+                    .body(body.clone())
+            })
+            .await?;
+        Self::parse(response).await
+    }
+
+    pub async fn del(&mut self, key: &str) -> Result<DelResponse, ClientError> {
+        debug!("delete key '{}'", key);
+        let response = self
+            .send(&["del", key], |client, url| client.post(url))
+            .await?;
+        Self::parse(response).await
+    }
+
+    pub async fn keys(&mut self) -> Result<KeysResponse, ClientError> {
+        let response = self.send(&["keys"], |client, url| client.post(url)).await?;
+        Self::parse(response).await
+    }
+
+    pub async fn purge(&mut self) -> Result<PurgeResponse, ClientError> {
+        let response = self
+            .send(&["purge"], |client, url| client.post(url))
+            .await?;
+        Self::parse(response).await
     }
 }
@@ -1,18 +1,27 @@
 // use rand::distributions::{Alphanumeric, Distribution};
 use crate::arguments::{Args, SubArg};
+use crate::rate_limit::PrefixLimit;
 use crate::sync::{Arc, RwLock};
+use chrono::{DateTime, Utc};
 use rand::thread_rng;
 use rand::Rng;
 use serde::{Deserialize, Serialize};
 use sha3::{Digest, Sha3_512};
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::error::Error;
 use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use tokio::{fs, io};
 use url::Url;
 
 pub type RuntimeConfigArc = Arc<RwLock<RuntimeConfig>>;
 const N: usize = 32;
 
+/// file in the backup dir where the identity and membership are persisted
+pub const CLUSTER_FILE: &str = "cluster.json";
+/// hours after which a member that is no longer a neighbour or seed is forgotten
+const FORGET_MEMBER_AFTER: i64 = 24;
+
 #[derive(Debug, PartialEq)]
 pub struct JoinCommand {
     pub address: Url,
@@ -20,18 +29,23 @@ pub struct JoinCommand {
 
 impl JoinCommand {
     pub fn from_args(args: &Args) -> Option<JoinCommand> {
-        if let Some(SubArg::Join {
-            join_address: address,
-            ..
-        }) = args.sub_cmd.clone()
-        {
-            Some(JoinCommand { address })
+        if let Some(SubArg::Join(join)) = args.sub_cmd.as_ref() {
+            Some(JoinCommand {
+                address: join.join_address.clone(),
+            })
         } else {
             None
         }
     }
 }
 
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct BackupInfo {
+    pub time: DateTime<Utc>,
+    pub path: PathBuf,
+    pub size: u64,
+}
+
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct RuntimeConfig {
     pub address: SocketAddr,
@@ -41,8 +55,53 @@ pub struct RuntimeConfig {
     pub backup_amount: usize,
     pub backup_skip_loading: bool,
     pub neighbours: HashSet<Url>,
+    /// addresses from `--neighbours`, kept around so seeds that were down are retried
+    pub seeds: HashSet<Url>,
+    /// every node that joined the cluster, reachable or not. consensus and quorums need a
+    /// majority of them, so a partition cannot shrink the cluster to the side it is on
+    pub members: HashSet<Url>,
     pub base_code: String,
     pub identifier: String,
+    pub consensus: bool,
+    /// last time a neighbour responded
+    pub last_seen: HashMap<Url, DateTime<Utc>>,
+    pub last_backup: Option<BackupInfo>,
+    pub started: DateTime<Utc>,
+    /// milliseconds
+    pub slow_log_threshold: u64,
+    pub slow_log_size: usize,
+    /// bearer token for the `/admin` endpoints, they are disabled without one
+    #[serde(skip)]
+    pub admin_token: Option<String>,
+    /// sent by every node to its neighbours, their requests are not rate limited
+    #[serde(skip)]
+    pub cluster_secret: Option<String>,
+    /// address of the redis protocol listener, disabled when not set
+    pub resp_address: Option<SocketAddr>,
+    /// address of the memcached protocol listener, disabled when not set
+    pub memcache_address: Option<SocketAddr>,
+    /// address of the grpc listener, disabled when not set
+    pub grpc_address: Option<SocketAddr>,
+    /// requests that are sent to the neighbours at the same time
+    pub broadcast_concurrency: usize,
+    /// milliseconds, the time requests to all neighbours together may take
+    pub broadcast_timeout: u64,
+    /// times a failed request to a neighbour is tried again
+    pub retry_attempts: usize,
+    /// milliseconds, the longest wait before the first retry, it doubles with every retry
+    pub retry_backoff: u64,
+    /// http requests that are handled at the same time
+    pub max_concurrent_requests: usize,
+    /// milliseconds an http request may take
+    pub request_timeout: u64,
+    /// requests per second of a client, 0 turns it off
+    pub rate_limit: u32,
+    /// requests a client can make at once, at least `rate_limit`
+    pub rate_limit_burst: u32,
+    pub rate_limit_prefixes: Vec<PrefixLimit>,
+    /// the `x-api-key` values that are limited on their own
+    #[serde(skip)]
+    pub rate_limit_api_keys: HashSet<String>,
     // pub join_subcommand: Option<JoinCommand>,
 }
 
@@ -58,6 +117,85 @@ impl RuntimeConfig {
         rng.fill(&mut buffer[..]);
         base64(buffer)
     }
+
+    /// adds a node to the neighbours and to the members of the cluster
+    pub fn add_neighbour(&mut self, address: Url) {
+        if address != self.external_address {
+            self.members.insert(address.clone());
+        }
+        self.neighbours.insert(address);
+    }
+
+    /// drops the members from `last_seen` that left the cluster a long time ago
+    pub fn forget_members(&mut self, now: DateTime<Utc>) {
+        let (neighbours, seeds) = (&self.neighbours, &self.seeds);
+        self.last_seen.retain(|address, seen| {
+            neighbours.contains(address)
+                || seeds.contains(address)
+                || now - *seen < chrono::Duration::hours(FORGET_MEMBER_AFTER)
+        });
+    }
+
+    /// applies the settings of a reloaded config file that can be changed while running.
+    /// settings in `fixed` were given as flags or env vars and stay the same,
+    /// settings that were removed from the file fall back to the ones in `defaults`
+    pub fn reload(
+        &mut self,
+        file: &ConfigFile,
+        fixed: &HashSet<&str>,
+        defaults: &RuntimeConfig,
+    ) -> ConfigUpdate {
+        let free = |key: &str| !fixed.contains(key);
+        let update = ConfigUpdate {
+            backup_interval: Some(file.backup.interval.unwrap_or(defaults.backup_interval))
+                .filter(|_| free("backup.interval")),
+            backup_amount: Some(file.backup.amount.unwrap_or(defaults.backup_amount))
+                .filter(|_| free("backup.amount")),
+        };
+        self.update(&update);
+        if free("admin_token") {
+            self.admin_token = file
+                .admin_token
+                .clone()
+                .or_else(|| defaults.admin_token.clone());
+        }
+        update
+    }
+
+    /// the rate limiter is built once at startup, so its settings can not be reloaded
+    pub fn same_rate_limits(&self, other: &RuntimeConfig) -> bool {
+        self.rate_limit == other.rate_limit
+            && self.rate_limit_burst == other.rate_limit_burst
+            && self.rate_limit_prefixes == other.rate_limit_prefixes
+            && self.rate_limit_api_keys == other.rate_limit_api_keys
+    }
+
+    /// applies the settings that can be changed while running
+    pub fn update(&mut self, update: &ConfigUpdate) {
+        if let Some(backup_interval) = update.backup_interval {
+            self.backup_interval = backup_interval;
+        }
+        if let Some(backup_amount) = update.backup_amount {
+            self.backup_amount = backup_amount;
+        }
+    }
+}
+
+/// the settings that can be changed while running, fields that are left out stay the same
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigUpdate {
+    pub backup_interval: Option<u64>,
+    pub backup_amount: Option<usize>,
+}
+
+impl ConfigUpdate {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.backup_interval == Some(0) {
+            return Err(String::from("backup_interval should be larger than 0"));
+        }
+        Ok(())
+    }
 }
 
 impl Default for RuntimeConfig {
@@ -71,13 +209,376 @@ impl Default for RuntimeConfig {
             backup_amount: 10,
             backup_skip_loading: false,
             neighbours: HashSet::new(),
+            seeds: HashSet::new(),
+            members: HashSet::new(),
             base_code: base64_sha3(&identifier),
             identifier,
+            consensus: false,
+            last_seen: HashMap::new(),
+            last_backup: None,
+            started: Utc::now(),
+            slow_log_threshold: 100,
+            slow_log_size: 128,
+            admin_token: None,
+            cluster_secret: None,
+            resp_address: None,
+            memcache_address: None,
+            grpc_address: None,
+            broadcast_concurrency: 16,
+            broadcast_timeout: 5000,
+            retry_attempts: 5,
+            retry_backoff: 50,
+            max_concurrent_requests: crate::MAX_CONCURRENT_REQUESTS,
+            request_timeout: crate::REQUEST_TIMEOUT.as_millis() as u64,
+            rate_limit: 0,
+            rate_limit_burst: 0,
+            rate_limit_prefixes: Vec::new(),
+            rate_limit_api_keys: HashSet::new(),
             // join_subcommand: None,
         }
     }
 }
 
+/// what a node needs to rejoin its cluster after a restart
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClusterState {
+    pub identifier: String,
+    pub base_code: String,
+    pub neighbours: HashSet<Url>,
+    /// missing in the files of older versions, their neighbours are the members then
+    #[serde(default)]
+    pub members: HashSet<Url>,
+}
+
+impl ClusterState {
+    pub fn from_config(cfg: &RuntimeConfig) -> Self {
+        ClusterState {
+            identifier: cfg.identifier.clone(),
+            base_code: cfg.base_code.clone(),
+            neighbours: cfg.neighbours.clone(),
+            members: cfg.members.clone(),
+        }
+    }
+
+    /// the persisted state in `backup_dir`, `None` on the first start
+    pub async fn load(backup_dir: &Path) -> io::Result<Option<ClusterState>> {
+        match fs::read(backup_dir.join(CLUSTER_FILE)).await {
+            Ok(contents) => Ok(Some(serde_json::from_slice(&contents)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// writes to a temporary file first, so a crash cannot leave a half written file behind
+    pub async fn save(&self, backup_dir: &Path) -> io::Result<()> {
+        fs::create_dir_all(backup_dir).await?;
+        let file = backup_dir.join(CLUSTER_FILE);
+        let tmp = file.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_vec(self)?).await?;
+        fs::rename(tmp, file).await
+    }
+
+    /// takes over the identity and adds the last known neighbours and members
+    pub fn restore(self, cfg: &mut RuntimeConfig) {
+        cfg.identifier = self.identifier;
+        cfg.base_code = self.base_code;
+        let me = cfg.external_address.clone();
+        cfg.members.extend(
+            self.members
+                .into_iter()
+                .chain(self.neighbours.iter().cloned())
+                .filter(|x| x != &me),
+        );
+        cfg.neighbours
+            .extend(self.neighbours.into_iter().filter(|x| x != &me));
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BackupSection {
+    pub dir: Option<PathBuf>,
+    pub interval: Option<u64>,
+    pub amount: Option<usize>,
+    pub skip_loading: Option<bool>,
+}
+
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitSection {
+    pub per_second: Option<u32>,
+    pub burst: Option<u32>,
+    /// requests per second of a client for the keys that start with the prefix
+    pub prefixes: Option<BTreeMap<String, u32>>,
+    pub api_keys: Option<Vec<String>>,
+}
+
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LoggingSection {
+    pub level: Option<String>,
+    pub output: Option<String>,
+    pub development: Option<bool>,
+    pub otlp_endpoint: Option<Url>,
+    pub slow_log_threshold: Option<u64>,
+    pub slow_log_size: Option<usize>,
+}
+
+/// contents of the `--config` file, every setting is optional.
+/// env vars and flags take precedence over the file, see `Args::with_config_file`.
+/// the largest value (`MAX_FILE_SIZE`) is fixed at compile time and can not be set here
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    pub address: Option<SocketAddr>,
+    pub neighbours: Option<Vec<Url>>,
+    pub consensus: Option<bool>,
+    pub admin_token: Option<String>,
+    pub cluster_secret: Option<String>,
+    pub resp_address: Option<SocketAddr>,
+    pub memcache_address: Option<SocketAddr>,
+    pub grpc_address: Option<SocketAddr>,
+    pub broadcast_concurrency: Option<usize>,
+    pub broadcast_timeout: Option<u64>,
+    pub retry_attempts: Option<usize>,
+    pub retry_backoff: Option<u64>,
+    pub max_concurrent_requests: Option<usize>,
+    pub request_timeout: Option<u64>,
+    #[serde(default)]
+    pub backup: BackupSection,
+    #[serde(default)]
+    pub logging: LoggingSection,
+    #[serde(default)]
+    pub rate_limit: RateLimitSection,
+}
+
+const LEVELS: &[&str] = &["ERROR", "WARN", "INFO", "DEBUG", "TRACE", "OFF"];
+const OUTPUTS: &[&str] = &["compact", "json", "pretty"];
+
+impl ConfigFile {
+    /// reads a `.toml`, `.yaml` or `.yml` file
+    pub fn load(path: &Path) -> Result<ConfigFile, Box<dyn Error>> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("could not read '{}': {}", path.display(), e))?;
+        let config: ConfigFile = match path.extension().and_then(|x| x.to_str()) {
+            Some("toml") => toml::from_str(&contents)?,
+            Some("yaml") | Some("yml") => serde_yaml::from_str(&contents)?,
+            _ => {
+                return Err(
+                    String::from("the config file should end with .toml, .yaml or .yml").into(),
+                )
+            }
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(level) = self.logging.level.as_ref() {
+            if !LEVELS.contains(&level.to_uppercase().as_str()) {
+                return Err(format!(
+                    "logging.level should be one of {}",
+                    LEVELS.join(", ")
+                ));
+            }
+        }
+        if let Some(output) = self.logging.output.as_ref() {
+            if !OUTPUTS.contains(&output.as_str()) {
+                return Err(format!(
+                    "logging.output should be one of {}",
+                    OUTPUTS.join(", ")
+                ));
+            }
+        }
+        if self.backup.interval == Some(0) {
+            return Err(String::from("backup.interval should be larger than 0"));
+        }
+        if self.broadcast_concurrency == Some(0) {
+            return Err(String::from(
+                "broadcast_concurrency should be larger than 0",
+            ));
+        }
+        if self.max_concurrent_requests == Some(0) || self.request_timeout == Some(0) {
+            return Err(String::from(
+                "max_concurrent_requests and request_timeout should be larger than 0",
+            ));
+        }
+        if let Some(prefixes) = self.rate_limit.prefixes.as_ref() {
+            if prefixes
+                .iter()
+                .any(|(prefix, limit)| prefix.is_empty() || *limit == 0)
+            {
+                return Err(String::from(
+                    "rate_limit.prefixes should have a prefix and a limit larger than 0",
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// the settings in the file as (name in the file, env var, value).
+    /// flags that are turned off are left out, an env var can only turn them on
+    pub fn settings(&self) -> Vec<(&'static str, &'static str, String)> {
+        fn flag(value: Option<bool>) -> Option<String> {
+            value.filter(|x| *x).map(|x| x.to_string())
+        }
+
+        let neighbours = self.neighbours.as_ref().map(|neighbours| {
+            neighbours
+                .iter()
+                .map(|x| x.as_str())
+                .collect::<Vec<_>>()
+                .join(",")
+        });
+        let prefixes = self.rate_limit.prefixes.as_ref().map(|prefixes| {
+            prefixes
+                .iter()
+                .map(|(prefix, per_second)| format!("{}={}", prefix, per_second))
+                .collect::<Vec<_>>()
+                .join(",")
+        });
+        let settings = vec![
+            (
+                "address",
+                "RACHER_ADDRESS",
+                self.address.map(|x| x.to_string()),
+            ),
+            ("neighbours", "RACHER_NEIGHBOURS", neighbours),
+            ("consensus", "RACHER_CONSENSUS", flag(self.consensus)),
+            (
+                "admin_token",
+                "RACHER_ADMIN_TOKEN",
+                self.admin_token.clone(),
+            ),
+            (
+                "cluster_secret",
+                "RACHER_CLUSTER_SECRET",
+                self.cluster_secret.clone(),
+            ),
+            (
+                "resp_address",
+                "RACHER_RESP_ADDRESS",
+                self.resp_address.map(|x| x.to_string()),
+            ),
+            (
+                "memcache_address",
+                "RACHER_MEMCACHE_ADDRESS",
+                self.memcache_address.map(|x| x.to_string()),
+            ),
+            (
+                "grpc_address",
+                "RACHER_GRPC_ADDRESS",
+                self.grpc_address.map(|x| x.to_string()),
+            ),
+            (
+                "broadcast_concurrency",
+                "RACHER_BROADCAST_CONCURRENCY",
+                self.broadcast_concurrency.map(|x| x.to_string()),
+            ),
+            (
+                "broadcast_timeout",
+                "RACHER_BROADCAST_TIMEOUT",
+                self.broadcast_timeout.map(|x| x.to_string()),
+            ),
+            (
+                "retry_attempts",
+                "RACHER_RETRY_ATTEMPTS",
+                self.retry_attempts.map(|x| x.to_string()),
+            ),
+            (
+                "retry_backoff",
+                "RACHER_RETRY_BACKOFF",
+                self.retry_backoff.map(|x| x.to_string()),
+            ),
+            (
+                "max_concurrent_requests",
+                "RACHER_MAX_CONCURRENT_REQUESTS",
+                self.max_concurrent_requests.map(|x| x.to_string()),
+            ),
+            (
+                "request_timeout",
+                "RACHER_REQUEST_TIMEOUT",
+                self.request_timeout.map(|x| x.to_string()),
+            ),
+            (
+                "backup.dir",
+                "RACHER_BACKUP_DIR",
+                self.backup.dir.as_ref().map(|x| x.display().to_string()),
+            ),
+            (
+                "backup.interval",
+                "RACHER_BACKUP_INTERVAL",
+                self.backup.interval.map(|x| x.to_string()),
+            ),
+            (
+                "backup.amount",
+                "RACHER_BACKUP_AMOUNT",
+                self.backup.amount.map(|x| x.to_string()),
+            ),
+            (
+                "backup.skip_loading",
+                "RACHER_BACKUP_SKIP_LOADING",
+                flag(self.backup.skip_loading),
+            ),
+            (
+                "logging.level",
+                "RACHER_LOGGER_LEVEL",
+                self.logging.level.as_ref().map(|x| x.to_uppercase()),
+            ),
+            (
+                "logging.output",
+                "RACHER_OUTPUT_FORMAT",
+                self.logging.output.clone(),
+            ),
+            (
+                "logging.development",
+                "RACHER_DEVELOPMENT",
+                flag(self.logging.development),
+            ),
+            (
+                "logging.otlp_endpoint",
+                "RACHER_OTLP_ENDPOINT",
+                self.logging.otlp_endpoint.as_ref().map(|x| x.to_string()),
+            ),
+            (
+                "logging.slow_log_threshold",
+                "RACHER_SLOW_LOG_THRESHOLD",
+                self.logging.slow_log_threshold.map(|x| x.to_string()),
+            ),
+            (
+                "logging.slow_log_size",
+                "RACHER_SLOW_LOG_SIZE",
+                self.logging.slow_log_size.map(|x| x.to_string()),
+            ),
+            (
+                "rate_limit.per_second",
+                "RACHER_RATE_LIMIT",
+                self.rate_limit.per_second.map(|x| x.to_string()),
+            ),
+            (
+                "rate_limit.burst",
+                "RACHER_RATE_LIMIT_BURST",
+                self.rate_limit.burst.map(|x| x.to_string()),
+            ),
+            (
+                "rate_limit.prefixes",
+                "RACHER_RATE_LIMIT_PREFIXES",
+                prefixes,
+            ),
+            (
+                "rate_limit.api_keys",
+                "RACHER_RATE_LIMIT_API_KEYS",
+                self.rate_limit.api_keys.as_ref().map(|x| x.join(",")),
+            ),
+        ];
+
+        settings
+            .into_iter()
+            .filter_map(|(key, env, value)| value.map(|value| (key, env, value)))
+            .collect()
+    }
+}
+
 pub fn base64_sha3(input: &str) -> String {
     base64(Sha3_512::digest(input.as_bytes()))
 }
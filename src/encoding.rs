@@ -0,0 +1,226 @@
+use crate::responses::{ErrorCode, ErrorResponse};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_value::Value;
+use std::convert::TryFrom;
+use warp::http::header;
+use warp::reply::{self, Reply, Response};
+
+pub const JSON: &str = "application/json";
+pub const MSGPACK: &str = "application/msgpack";
+pub const CBOR: &str = "application/cbor";
+/// json values separated by newlines, used to stream json
+pub const NDJSON: &str = "application/x-ndjson";
+/// binary values written through the redis or memcached protocol are stored as raw values
+/// with this content type
+pub const BINARY: &str = "application/octet-stream";
+
+/// the formats values can be sent and received in, json is the default
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    #[default]
+    Json,
+    MessagePack,
+    Cbor,
+}
+
+/// the media type without parameters like `charset`
+fn media_type(value: &str) -> String {
+    value
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_lowercase()
+}
+
+impl Encoding {
+    fn from_media_type(media_type: &str) -> Option<Encoding> {
+        match media_type {
+            "application/json" => Some(Encoding::Json),
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => {
+                Some(Encoding::MessagePack)
+            }
+            "application/cbor" => Some(Encoding::Cbor),
+            _ => None,
+        }
+    }
+
+    /// the encoding of a request body, a missing content type is treated as json.
+    /// `None` when the content type is not supported
+    pub fn from_content_type(content_type: Option<&str>) -> Option<Encoding> {
+        match content_type {
+            Some(content_type) => Self::from_media_type(&media_type(content_type)),
+            None => Some(Encoding::Json),
+        }
+    }
+
+    /// the first supported type in the `Accept` header, json if there is none
+    pub fn from_accept(accept: Option<&str>) -> Encoding {
+        accept
+            .into_iter()
+            .flat_map(|accept| accept.split(','))
+            .find_map(|x| Self::from_media_type(&media_type(x)))
+            .unwrap_or_default()
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Encoding::Json => JSON,
+            Encoding::MessagePack => MSGPACK,
+            Encoding::Cbor => CBOR,
+        }
+    }
+
+    pub fn encode<T: Serialize>(self, value: &T) -> Result<Vec<u8>, String> {
+        match self {
+            Encoding::Json => serde_json::to_vec(value).map_err(|e| e.to_string()),
+            // structs are written as maps, so fields can be added without breaking older nodes
+            Encoding::MessagePack => rmp_serde::to_vec_named(value).map_err(|e| e.to_string()),
+            Encoding::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::ser::into_writer(value, &mut bytes).map_err(|e| e.to_string())?;
+                Ok(bytes)
+            }
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T, String> {
+        match self {
+            Encoding::Json => serde_json::from_slice(bytes).map_err(|e| e.to_string()),
+            Encoding::MessagePack => rmp_serde::from_slice(bytes).map_err(|e| e.to_string()),
+            Encoding::Cbor => ciborium::de::from_reader(bytes).map_err(|e| e.to_string()),
+        }
+    }
+
+    /// the content type of a stream of values written with `write_frame`
+    pub fn stream_content_type(self) -> &'static str {
+        match self {
+            Encoding::Json => NDJSON,
+            encoding => encoding.content_type(),
+        }
+    }
+
+    pub fn from_stream_content_type(content_type: Option<&str>) -> Option<Encoding> {
+        match content_type.map(media_type).as_deref() {
+            Some(NDJSON) => Some(Encoding::Json),
+            _ => Self::from_content_type(content_type),
+        }
+    }
+
+    /// appends one value of a stream to the buffer, json values end with a newline,
+    /// binary values are prefixed with their length as a big endian u32
+    pub fn write_frame<T: Serialize>(self, buffer: &mut Vec<u8>, value: &T) -> Result<(), String> {
+        // the value is encoded on its own, so a failure leaves no half frame in the buffer
+        let bytes = self.encode(value)?;
+        match self {
+            Encoding::Json => {
+                buffer.extend_from_slice(&bytes);
+                buffer.push(b'\n');
+            }
+            _ => {
+                let length = u32::try_from(bytes.len())
+                    .map_err(|_| String::from("the value is too large for a frame"))?;
+                buffer.extend_from_slice(&length.to_be_bytes());
+                buffer.extend_from_slice(&bytes);
+            }
+        }
+        Ok(())
+    }
+
+    /// reads the first value of a stream from the buffer together with the amount of bytes
+    /// it took up, `None` when the buffer does not contain a whole value yet
+    pub fn read_frame<T: DeserializeOwned>(
+        self,
+        buffer: &[u8],
+    ) -> Result<Option<(T, usize)>, String> {
+        let (start, end) = match self {
+            Encoding::Json => match buffer.iter().position(|x| *x == b'\n') {
+                Some(end) => (0, end),
+                None => return Ok(None),
+            },
+            _ => {
+                if buffer.len() < 4 {
+                    return Ok(None);
+                }
+                let length = u32::from_be_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]);
+                let end = 4 + length as usize;
+                if buffer.len() < end {
+                    return Ok(None);
+                }
+                (4, end)
+            }
+        };
+        let value = self.decode(&buffer[start..end])?;
+        // the newline after a json value is part of it
+        let used = if self == Encoding::Json { end + 1 } else { end };
+        Ok(Some((value, used)))
+    }
+
+    /// encodes the value as the body of a response with the matching content type
+    pub fn reply<T: Serialize>(self, value: &T) -> Response {
+        match self.encode(value) {
+            Ok(body) => {
+                reply::with_header(body, header::CONTENT_TYPE, self.content_type()).into_response()
+            }
+            Err(e) => ErrorResponse::new(ErrorCode::Internal, e).into_response(),
+        }
+    }
+}
+
+/// raw values are written to json as base64 text, as an array of numbers they would come
+/// back as a list
+pub fn raw_to_json(value: Value, encoding: Encoding) -> Value {
+    match (encoding, value) {
+        (Encoding::Json, Value::Bytes(x)) => Value::String(base64::encode(x)),
+        (_, value) => value,
+    }
+}
+
+/// reverses `raw_to_json`, raw values are the ones with a content type
+pub fn raw_from_json(value: Value, content_type: Option<&str>, encoding: Encoding) -> Value {
+    match (encoding, value, content_type) {
+        (Encoding::Json, Value::String(x), Some(_)) => match base64::decode(&x) {
+            Ok(bytes) => Value::Bytes(bytes),
+            Err(_) => Value::String(x),
+        },
+        (_, value, _) => value,
+    }
+}
+
+/// the bytes of a value for protocols without types like redis and memcached,
+/// strings and raw values are returned as they are, everything else as json
+pub fn to_plain(value: &Value) -> Vec<u8> {
+    match value {
+        Value::String(x) => x.clone().into_bytes(),
+        Value::Bytes(x) => x.clone(),
+        value => serde_json::to_vec(value).unwrap_or_default(),
+    }
+}
+
+/// the value of bytes from a protocol without types, text is stored as a string
+/// and everything else as a raw value with its content type
+pub fn from_plain(bytes: Vec<u8>) -> (Value, Option<String>) {
+    match String::from_utf8(bytes) {
+        Ok(x) => (Value::String(x), None),
+        Err(e) => (Value::Bytes(e.into_bytes()), Some(String::from(BINARY))),
+    }
+}
+
+/// integers and the text of integers, large enough for both i64 and u64
+pub fn plain_integer(value: &Value) -> Option<i128> {
+    match value {
+        Value::I8(x) => Some(*x as i128),
+        Value::I16(x) => Some(*x as i128),
+        Value::I32(x) => Some(*x as i128),
+        Value::I64(x) => Some(*x as i128),
+        Value::U8(x) => Some(*x as i128),
+        Value::U16(x) => Some(*x as i128),
+        Value::U32(x) => Some(*x as i128),
+        Value::U64(x) => Some(*x as i128),
+        Value::String(x) => x.parse().ok(),
+        Value::Bytes(x) => std::str::from_utf8(x).ok()?.parse().ok(),
+        _ => None,
+    }
+}
@@ -0,0 +1,314 @@
+use crate::client::Client;
+use crate::config::RuntimeConfigArc;
+use crate::encoding::{self, Encoding};
+use crate::metadata::{self, Metadata};
+use crate::quorum;
+use crate::raft::{self, Command, RaftArc, RaftError};
+use crate::routes::{self, Consistency};
+use crate::transport::{self, Message};
+use crate::{Db, MetaDb};
+
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::Stream;
+use serde_value::Value;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+use tonic::{Request, Response, Status};
+use tracing::info;
+
+pub mod proto {
+    tonic::include_proto!("racher");
+}
+
+use proto::racher_server::{Racher, RacherServer};
+
+/// events a watcher did not receive yet, when it falls further behind the watch ends
+const WATCH_BUFFER: usize = 64;
+
+#[allow(clippy::result_large_err)]
+fn consistency(value: i32) -> Result<Consistency, Status> {
+    match proto::Consistency::from_i32(value) {
+        Some(proto::Consistency::One) => Ok(Consistency::One),
+        Some(proto::Consistency::Quorum) => Ok(Consistency::Quorum),
+        Some(proto::Consistency::All) => Ok(Consistency::All),
+        Some(proto::Consistency::Strong) => Ok(Consistency::Strong),
+        None => Err(Status::invalid_argument("unknown consistency")),
+    }
+}
+
+#[allow(clippy::result_large_err)]
+/// raw values keep their content type, everything else is sent as json
+fn to_proto(value: &Value, content_type: Option<&str>) -> Result<proto::Value, Status> {
+    match (value, content_type) {
+        (Value::Bytes(bytes), Some(content_type)) => Ok(proto::Value {
+            data: bytes.clone(),
+            content_type: content_type.to_string(),
+        }),
+        _ => Ok(proto::Value {
+            data: Encoding::Json.encode(value).map_err(Status::internal)?,
+            content_type: encoding::JSON.to_string(),
+        }),
+    }
+}
+
+#[allow(clippy::result_large_err)]
+fn from_proto(value: Option<proto::Value>) -> Result<(Value, Option<String>), Status> {
+    let value = value.ok_or_else(|| Status::invalid_argument("value is missing"))?;
+    let content_type = Some(value.content_type).filter(|x| !x.is_empty());
+    routes::decode_value(content_type, &value.data).map_err(|e| Status::invalid_argument(e.error))
+}
+
+fn raft_status(error: RaftError) -> Status {
+    Status::unavailable(error.to_string())
+}
+
+fn watch_event(message: Message, prefix: &str) -> Option<proto::WatchEvent> {
+    let (key, value, metadata) = match message {
+        Message::Created(key, value, metadata, _) | Message::Updated(key, value, metadata) => {
+            (key, value, metadata)
+        }
+        Message::Deleted(key, version, _) => (
+            key,
+            Value::Unit,
+            Metadata {
+                version,
+                deleted: true,
+                ..Default::default()
+            },
+        ),
+    };
+    if !key.starts_with(prefix) {
+        return None;
+    }
+    let value = Some(value)
+        .filter(|_| !metadata.deleted)
+        .and_then(|x| to_proto(&x, metadata.content_type.as_deref()).ok());
+    Some(proto::WatchEvent {
+        key,
+        value,
+        version: metadata.version,
+    })
+}
+
+/// the events of one watch, filled by a task that follows the watch channel
+pub struct WatchStream(mpsc::Receiver<Result<proto::WatchEvent, Status>>);
+
+impl Stream for WatchStream {
+    type Item = Result<proto::WatchEvent, Status>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.0.poll_recv(cx)
+    }
+}
+
+/// the grpc api, it works on the same cache and replication as the http api
+#[derive(Clone)]
+pub struct RacherService {
+    cache: Db,
+    meta: MetaDb,
+    cfg: RuntimeConfigArc,
+    tx: transport::Sender,
+    raft: RaftArc,
+}
+
+impl RacherService {
+    pub fn new(
+        cache: Db,
+        meta: MetaDb,
+        cfg: RuntimeConfigArc,
+        tx: transport::Sender,
+        raft: RaftArc,
+    ) -> RacherService {
+        RacherService {
+            cache,
+            meta,
+            cfg,
+            tx,
+            raft,
+        }
+    }
+
+    fn local_get(&self, key: &str) -> Option<(Value, Option<String>)> {
+        let value = self.cache.get(key).map(|x| x.value().clone())?;
+        let content_type = self.meta.get(key).and_then(|x| x.content_type.clone());
+        Some((value, content_type))
+    }
+
+    async fn consensus_enabled(&self) -> Result<(), Status> {
+        if !self.cfg.read().await.consensus {
+            return Err(Status::failed_precondition("consensus mode is not enabled"));
+        }
+        Ok(())
+    }
+
+    /// a client for the leader, the config is only locked while it is built
+    async fn client(&self) -> Client {
+        Client::from_config(&*self.cfg.read().await)
+    }
+
+    /// the value and its content type, `None` when the key does not exist
+    async fn strong_get(&self, key: &str) -> Result<Option<(Value, Option<String>)>, Status> {
+        self.consensus_enabled().await?;
+        match raft::read_barrier(&self.raft, &self.cfg, &self.cache, &self.meta).await {
+            Ok(()) => Ok(self.local_get(key)),
+            Err(RaftError::NotLeader(Some(leader))) => self
+                .client()
+                .await
+                .strong_get(leader, key)
+                .await
+                .map(|x| (!x.meta.deleted).then_some((x.data, x.meta.content_type)))
+                .map_err(|e| Status::unavailable(e.to_string())),
+            Err(e) => Err(raft_status(e)),
+        }
+    }
+
+    async fn strong_set(&self, key: String, value: Value) -> Result<(), Status> {
+        self.consensus_enabled().await?;
+        let command = Command::Set(key.clone(), value.clone());
+        match raft::propose(&self.raft, &self.cfg, &self.cache, &self.meta, command).await {
+            Ok(()) => Ok(()),
+            Err(RaftError::NotLeader(Some(leader))) => self
+                .client()
+                .await
+                .strong_set(leader, &key, &value)
+                .await
+                .map(|_| ())
+                .map_err(|e| Status::unavailable(e.to_string())),
+            Err(e) => Err(raft_status(e)),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Racher for RacherService {
+    async fn get(
+        &self,
+        request: Request<proto::GetRequest>,
+    ) -> Result<Response<proto::GetResponse>, Status> {
+        let request = request.into_inner();
+        let key = request.key;
+        let found = match consistency(request.consistency)? {
+            Consistency::One if metadata::expired(&self.meta, &key) => None,
+            Consistency::One => self.local_get(&key),
+            Consistency::Strong => self.strong_get(&key).await?,
+            consistency => {
+                let newest = quorum::read(&self.cache, &self.meta, &self.cfg, &key, consistency)
+                    .await
+                    .map_err(|e| Status::unavailable(e.to_string()))?;
+                let found = !newest.meta.deleted && !newest.meta.is_expired();
+                found.then_some((newest.data, newest.meta.content_type))
+            }
+        };
+        let value = match found {
+            Some((value, content_type)) => Some(to_proto(&value, content_type.as_deref())?),
+            None => None,
+        };
+        Ok(Response::new(proto::GetResponse { value }))
+    }
+
+    async fn set(
+        &self,
+        request: Request<proto::SetRequest>,
+    ) -> Result<Response<proto::SetResponse>, Status> {
+        let request = request.into_inner();
+        let key = request.key;
+        let (value, content_type) = from_proto(request.value)?;
+        match consistency(request.consistency)? {
+            Consistency::Strong if content_type.is_some() => {
+                return Err(Status::invalid_argument(
+                    "raw values cannot be written with strong consistency",
+                ))
+            }
+            Consistency::Strong => self.strong_set(key, value).await?,
+            consistency => {
+                let (cache, meta, cfg, tx) = (&self.cache, &self.meta, &self.cfg, &self.tx);
+                routes::write_value(key, value, content_type, consistency, cache, meta, cfg, tx)
+                    .await
+                    .map_err(|e| Status::unavailable(routes::write_error(e)))?;
+            }
+        }
+        Ok(Response::new(proto::SetResponse {}))
+    }
+
+    async fn delete(
+        &self,
+        request: Request<proto::DeleteRequest>,
+    ) -> Result<Response<proto::DeleteResponse>, Status> {
+        let key = request.into_inner().key;
+        let deleted = metadata::remove(&self.cache, &self.meta, &self.tx, key);
+        Ok(Response::new(proto::DeleteResponse { deleted }))
+    }
+
+    async fn keys(
+        &self,
+        _request: Request<proto::KeysRequest>,
+    ) -> Result<Response<proto::KeysResponse>, Status> {
+        let keys = metadata::live_keys(&self.cache, &self.meta);
+        Ok(Response::new(proto::KeysResponse { keys }))
+    }
+
+    async fn purge(
+        &self,
+        _request: Request<proto::PurgeRequest>,
+    ) -> Result<Response<proto::PurgeResponse>, Status> {
+        metadata::purge(&self.cache, &self.meta);
+        Ok(Response::new(proto::PurgeResponse { purged: true }))
+    }
+
+    async fn ping(
+        &self,
+        _request: Request<proto::PingRequest>,
+    ) -> Result<Response<proto::PingResponse>, Status> {
+        Ok(Response::new(proto::PingResponse { pong: true }))
+    }
+
+    type WatchStream = WatchStream;
+
+    async fn watch(
+        &self,
+        request: Request<proto::WatchRequest>,
+    ) -> Result<Response<Self::WatchStream>, Status> {
+        let prefix = request.into_inner().prefix;
+        let mut rx = self.tx.watch();
+        let (events, stream) = mpsc::channel(WATCH_BUFFER);
+        tokio::spawn(async move {
+            loop {
+                let message = tokio::select! {
+                    _ = events.closed() => return,
+                    message = rx.recv() => message,
+                };
+                let event = match message {
+                    Ok(message) => match watch_event(message, &prefix) {
+                        Some(event) => Ok(event),
+                        None => continue,
+                    },
+                    Err(RecvError::Lagged(amount)) => Err(Status::data_loss(format!(
+                        "the watch fell behind and missed {} changes",
+                        amount
+                    ))),
+                    Err(RecvError::Closed) => return,
+                };
+                let lagged = event.is_err();
+                if events.send(event).await.is_err() || lagged {
+                    return;
+                }
+            }
+        });
+        Ok(Response::new(WatchStream(stream)))
+    }
+}
+
+/// serves the grpc api on the address
+pub async fn serve(
+    address: SocketAddr,
+    service: RacherService,
+) -> Result<(), tonic::transport::Error> {
+    info!("grpc address: {}", address);
+    tonic::transport::Server::builder()
+        .add_service(RacherServer::new(service))
+        .serve(address)
+        .await
+}
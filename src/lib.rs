@@ -1,54 +1,89 @@
 #![warn(rust_2018_idioms)]
 
+pub mod access_log;
 pub mod arguments;
+pub mod cache;
 pub mod cli;
 pub mod client;
 pub mod config;
+pub mod encoding;
+pub mod grpc;
+pub mod memcache;
+pub mod metadata;
+pub mod metrics;
+pub mod quorum;
+pub mod raft;
+pub mod rate_limit;
+pub mod resp;
 pub mod responses;
 pub mod routes;
+pub mod shell;
 pub mod sync;
+pub mod telemetry;
 pub mod transport;
+use access_log::SlowLogArc;
 use config::RuntimeConfigArc;
+use raft::RaftArc;
 
+use std::time::Duration;
 use sync::Arc;
 
 use dashmap::DashMap;
-use serde_value::Value;
 use warp::filters::BoxedFilter;
+use warp::http::Method;
 use warp::{Filter, Reply};
 
-pub type Db = Arc<DashMap<String, Value>>;
+pub type Db = Arc<cache::Cache>;
+pub type MetaDb = Arc<DashMap<String, metadata::Metadata>>;
 
 pub const MAX_FILE_SIZE: u64 = 1024 * 1024 * 32;
+pub const MAX_CONCURRENT_REQUESTS: usize = 10000;
+pub const REQUEST_TIMEOUT: Duration = Duration::from_millis(15000);
 
 pub fn create_api(
     arc_cache: Db,
+    meta: MetaDb,
     cfg: RuntimeConfigArc,
     tx: transport::Sender,
+    raft: RaftArc,
+    slow_log: SlowLogArc,
 ) -> BoxedFilter<(impl Reply,)> {
-    let cors = warp::cors().allow_any_origin().allow_methods(&[warp::http::Method::POST]).allow_header("content-type");
-
-    let api = warp::post()
-        .and(
-            routes::setter(arc_cache.clone(), tx.clone())
-                .or(routes::getter(arc_cache.clone()))
-                .or(routes::keys(arc_cache.clone()))
-                .or(routes::purge(arc_cache.clone()))
-                .or(routes::ping())
-                .or(routes::deleter(arc_cache.clone(), tx))
-                .or(routes::internal(arc_cache.clone(), cfg.clone())),
-        ).with(cors);
+    let cors = warp::cors()
+        .allow_any_origin()
+        .allow_methods(&[Method::POST, Method::GET, Method::HEAD, Method::PUT, Method::DELETE])
+        .allow_headers(vec!["content-type", "if-none-match"])
+        .expose_header("etag");
+
+    let api = routes::setter(arc_cache.clone(), meta.clone(), cfg.clone(), tx.clone(), raft.clone())
+        .or(routes::getter(arc_cache.clone(), meta.clone(), cfg.clone(), raft.clone()))
+        .or(routes::keys(arc_cache.clone(), meta.clone()))
+        .or(routes::purge(arc_cache.clone(), meta.clone()))
+        .or(routes::ping())
+        .or(routes::cluster(arc_cache.clone(), meta.clone(), cfg.clone()))
+        .or(routes::stats(arc_cache.clone(), cfg.clone()))
+        .or(routes::admin(cfg.clone(), slow_log))
+        .or(routes::deleter(arc_cache.clone(), meta.clone(), tx.clone()))
+        .or(routes::internal(arc_cache.clone(), meta.clone(), cfg.clone(), tx.clone(), raft.clone()))
+        .or(routes::rest(arc_cache.clone(), meta, cfg.clone(), tx, raft))
+        .with(cors)
+        .or(routes::metrics(arc_cache.clone()));
 
     // api.or(warp::options().map(warp::reply).with(cors))
     // let cors_stuff = warp::options().map(warp::reply).with(cors);
     // warp::any().and(api).or(cors_stuff)
     //     .boxed()
 
+    // the recover is last, the dashboard would not be reached after it
     cfg_if::cfg_if! {
         if #[cfg(feature = "dashboard")] {
-            api.or(warp::get().and(routes::web())).boxed()
+            api.or(routes::web())
+                .recover(routes::recover)
+                .with(warp::log::custom(metrics::observe))
+                .boxed()
         } else {
-            api.boxed()
+            api.recover(routes::recover)
+                .with(warp::log::custom(metrics::observe))
+                .boxed()
         }
     }
 }
@@ -56,19 +91,30 @@ pub fn create_api(
 #[cfg(test)]
 mod test {
     use super::*;
+    use cache::Cache;
+    use serde_value::Value;
 
     fn setup(arc_cache: Db) -> BoxedFilter<(impl Reply,)> {
         let (tx, _) = transport::channel(16);
 
         let config = config::RuntimeConfig::default().to_arc();
-        create_api(arc_cache, config, tx)
+        let raft = raft::Raft::default().to_arc();
+        let slow_log = access_log::SlowLog::default().to_arc();
+        create_api(arc_cache, Arc::new(DashMap::new()), config, tx, raft, slow_log)
+    }
+
+    fn versioned(version: u64) -> metadata::Metadata {
+        metadata::Metadata {
+            version,
+            ..Default::default()
+        }
     }
 
     #[tokio::test]
     async fn get_not_existing() {
         let map = DashMap::new();
 
-        let cache = Arc::new(map);
+        let cache = Arc::new(Cache::from(map));
         let filter = setup(cache);
 
         let value = warp::test::request()
@@ -88,7 +134,7 @@ mod test {
         let map = DashMap::new();
         map.insert(String::from("testing"), Value::Bool(true));
 
-        let cache = Arc::new(map);
+        let cache = Arc::new(Cache::from(map));
         let filter = setup(cache);
 
         let value = warp::test::request()
@@ -109,7 +155,7 @@ mod test {
     async fn set() {
         let map = DashMap::new();
 
-        let cache = Arc::new(map);
+        let cache = Arc::new(Cache::from(map));
         let filter = setup(cache.clone());
 
         let value = warp::test::request()
@@ -145,11 +191,141 @@ mod test {
         assert_eq!(&Value::U64(123), cache.get("testing").unwrap().value());
     }
 
+    #[tokio::test]
+    async fn rest_api() {
+        let cache = Arc::new(Cache::new());
+        let filter = setup(cache.clone());
+
+        let response = warp::test::request()
+            .method("GET")
+            .path("/keys/testing")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 404);
+
+        let response = warp::test::request()
+            .method("PUT")
+            .path("/keys/testing")
+            .json(&Value::Unit)
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 201);
+        let etag = response.headers()["etag"].clone();
+
+        // a stored null is found
+        let response = warp::test::request()
+            .method("GET")
+            .path("/keys/testing")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["etag"], etag);
+        assert_eq!(response.headers()["cache-control"], "no-cache");
+        assert_eq!(response.body(), "null");
+
+        let response = warp::test::request()
+            .method("GET")
+            .path("/keys/testing")
+            .header("if-none-match", etag.clone())
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 304);
+        assert!(response.body().is_empty());
+
+        let response = warp::test::request()
+            .method("PUT")
+            .path("/keys/testing")
+            .json(&Value::I64(5))
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 204);
+        assert_ne!(response.headers()["etag"], etag);
+
+        let response = warp::test::request()
+            .method("GET")
+            .path("/keys/testing")
+            .header("if-none-match", etag)
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.body(), "5");
+
+        let response = warp::test::request()
+            .method("HEAD")
+            .path("/keys/testing")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()["content-length"], "1");
+        assert!(response.body().is_empty());
+
+        let response = warp::test::request()
+            .method("DELETE")
+            .path("/keys/testing")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 204);
+        assert!(!cache.contains_key("testing"));
+        let response = warp::test::request()
+            .method("DELETE")
+            .path("/keys/testing")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 404);
+        let response = warp::test::request()
+            .method("HEAD")
+            .path("/keys/testing")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 404);
+
+        // the old routes keep working
+        let response = warp::test::request().method("POST").path("/keys").reply(&filter).await;
+        assert_eq!(response.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn error_responses() {
+        let filter = setup(Arc::new(Cache::new()));
+
+        let cases = vec![
+            ("POST", "/unknown", None, 404, responses::ErrorCode::NotFound),
+            ("GET", "/unknown", None, 404, responses::ErrorCode::NotFound),
+            ("GET", "/get/testing", None, 405, responses::ErrorCode::MethodNotAllowed),
+            ("POST", "/set/testing", Some("{"), 400, responses::ErrorCode::InvalidBody),
+            ("POST", "/_internal/join", Some("{}"), 400, responses::ErrorCode::InvalidBody),
+            ("POST", "/get/testing?consistency=wrong", None, 400, responses::ErrorCode::BadRequest),
+            ("GET", "/keys/testing", None, 404, responses::ErrorCode::NotFound),
+        ];
+        for (method, path, body, status, code) in cases {
+            let response = warp::test::request()
+                .method(method)
+                .path(path)
+                .header("content-type", "application/json")
+                .body(body.unwrap_or_default())
+                .reply(&filter)
+                .await;
+            assert_eq!(response.status(), status, "{} {}", method, path);
+            let error: responses::ErrorResponse = serde_json::from_slice(response.body()).unwrap();
+            assert_eq!(error.code, code, "{} {}", method, path);
+        }
+
+        let response = warp::test::request()
+            .method("PUT")
+            .path("/keys/testing")
+            .header("content-length", MAX_FILE_SIZE + 1)
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 413);
+        let error: responses::ErrorResponse = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(error.code, responses::ErrorCode::PayloadTooLarge);
+    }
+
     #[tokio::test]
     async fn set_does_not_work_with_slash() {
         let map = DashMap::new();
 
-        let cache = Arc::new(map);
+        let cache = Arc::new(Cache::from(map));
         let filter = setup(cache.clone());
 
         let response = warp::test::request()
@@ -162,13 +338,523 @@ mod test {
         assert_eq!(404, response.status());
     }
 
+    #[tokio::test]
+    async fn strong_set_without_consensus() {
+        let map = DashMap::new();
+
+        let cache = Arc::new(Cache::from(map));
+        let filter = setup(cache.clone());
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/set/testing?consistency=strong")
+            .json(&Value::I64(123))
+            .reply(&filter)
+            .await;
+
+        assert_eq!(400, response.status());
+        assert!(!cache.contains_key("testing"));
+    }
+
+    #[tokio::test]
+    async fn strong_set_single_node() {
+        let map = DashMap::new();
+
+        let cache = Arc::new(Cache::from(map));
+        let meta: MetaDb = Arc::new(DashMap::new());
+        let (tx, _) = transport::channel(16);
+        let mut config = config::RuntimeConfig::default();
+        config.consensus = true;
+        let config = config.to_arc();
+        let raft = raft::Raft::default().to_arc();
+        let filter = create_api(
+            cache.clone(),
+            meta.clone(),
+            config.clone(),
+            tx,
+            raft.clone(),
+            access_log::SlowLog::default().to_arc(),
+        );
+
+        tokio::spawn(raft::run(raft.clone(), config, cache.clone(), meta.clone()));
+        let elected = async {
+            while raft.read().await.role != raft::Role::Leader {
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            }
+        };
+        tokio::time::timeout(std::time::Duration::from_secs(10), elected)
+            .await
+            .expect("no leader elected");
+
+        // an older write with an expiry and a content type is replaced completely
+        let older = metadata::Metadata { version: 1, expires_at: Some(metadata::now() + 1_000_000), content_type: Some("text/plain".into()), ..Default::default() };
+        metadata::apply(&cache, &meta, "testing".into(), Value::Bytes(b"old".to_vec()), older);
+
+        let value = warp::test::request()
+            .method("POST")
+            .path("/set/testing?consistency=strong")
+            .json(&Value::I64(123))
+            .reply(&filter)
+            .await
+            .into_body();
+
+        let value: responses::SetResponse = serde_json::from_slice(&value).unwrap();
+        assert_eq!(
+            value,
+            responses::SetResponse {
+                status: Value::String("ok".into())
+            }
+        );
+
+        let value = warp::test::request()
+            .method("POST")
+            .path("/get/testing?consistency=strong")
+            .reply(&filter)
+            .await
+            .into_body();
+
+        let value: responses::GetResponse = serde_json::from_slice(&value).unwrap();
+        let expected = responses::GetResponse {
+            data: Value::U64(123),
+        };
+        assert_eq!(value, expected);
+        assert_eq!(2, raft.read().await.commit_index);
+        let metadata = meta.get("testing").unwrap().clone();
+        assert!(metadata.version > 1);
+        assert_eq!((metadata.expires_at, metadata.content_type), (None, None));
+    }
+
+    #[tokio::test]
+    async fn strong_write_changes_etag() {
+        let cache = Arc::new(Cache::new());
+        let (tx, _) = transport::channel(16);
+        let mut config = config::RuntimeConfig::default();
+        config.consensus = true;
+        let config = config.to_arc();
+        let meta: MetaDb = Arc::new(DashMap::new());
+        let raft = raft::Raft::default().to_arc();
+        let filter = create_api(cache.clone(), meta.clone(), config.clone(), tx, raft.clone(), access_log::SlowLog::default().to_arc());
+        tokio::spawn(raft::run(raft.clone(), config, cache.clone(), meta));
+        let elected = async {
+            while raft.read().await.role != raft::Role::Leader {
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            }
+        };
+        tokio::time::timeout(std::time::Duration::from_secs(10), elected).await.expect("no leader elected");
+
+        let response = warp::test::request().method("PUT").path("/keys/testing").json(&Value::I64(1)).reply(&filter).await;
+        let etag = response.headers()["etag"].clone();
+        let response = warp::test::request().method("PUT").path("/keys/testing?consistency=strong").json(&Value::I64(2)).reply(&filter).await;
+        assert_eq!(response.status(), 200);
+
+        let response = warp::test::request().method("GET").path("/keys/testing").header("if-none-match", etag.clone()).reply(&filter).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.body(), "2");
+        assert_ne!(response.headers()["etag"], etag);
+    }
+
+    #[tokio::test]
+    async fn raft_counts_unreachable_members() {
+        let cache = Arc::new(Cache::from(DashMap::new()));
+        let mut config = config::RuntimeConfig::default();
+        config.consensus = true;
+        // a member that is down is not a neighbour, but it still has a vote
+        config.members.insert("http://127.0.0.1:1".parse().unwrap());
+        let config = config.to_arc();
+        let raft = raft::Raft::default().to_arc();
+
+        let task = tokio::spawn(raft::run(raft.clone(), config, cache, Arc::new(DashMap::new())));
+        tokio::time::sleep(std::time::Duration::from_secs(3)).await;
+        task.abort();
+        assert_ne!(raft.read().await.role, raft::Role::Leader);
+        assert!(raft.read().await.current_term > 0);
+    }
+
+    #[tokio::test]
+    async fn raft_log_compaction() {
+        use raft::{AppendRequest, Command, LogEntry, Raft};
+
+        let dir = std::env::temp_dir().join(format!("racher-raft-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let cache: Db = Arc::new(Cache::new());
+        let meta: MetaDb = Arc::new(DashMap::new());
+        let leader: url::Url = "http://127.0.0.1:9226".parse().unwrap();
+        let entry = |index: u64| LogEntry {
+            term: 1,
+            index,
+            command: Command::Set(format!("key{}", index % 10), Value::U64(index)),
+        };
+        let append = |prev_log_index: u64, entries: Vec<LogEntry>, leader_commit: u64| AppendRequest {
+            term: 1,
+            leader: leader.clone(),
+            prev_log_index,
+            prev_log_term: if prev_log_index == 0 { 0 } else { 1 },
+            entries,
+            leader_commit,
+            snapshot: None,
+        };
+
+        // applying enough entries folds them into the snapshot
+        let mut node = Raft::load(&dir).await.unwrap();
+        let response = node.handle_append(append(0, (1..=1100).map(entry).collect(), 1100), &cache, &meta).await;
+        assert!(response.success);
+        assert_eq!(node.snapshot.index, 1100);
+        assert!(node.log.is_empty());
+        assert_eq!(cache.get("key9").unwrap().clone(), Value::U64(1099));
+        let response = node.handle_append(append(1100, (1101..=1105).map(entry).collect(), 1102), &cache, &meta).await;
+        assert_eq!(response.match_index, 1105);
+
+        // the snapshot and the entries appended after it are loaded again
+        let node = Raft::load(&dir).await.unwrap();
+        assert_eq!(node.current_term, 1);
+        assert_eq!(node.snapshot.index, 1100);
+        assert_eq!(node.log, (1101..=1105).map(entry).collect::<Vec<_>>());
+        assert_eq!(node.last_log_index(), 1105);
+
+        // a follower that is behind the snapshot gets it instead of the entries
+        let follower_cache: Db = Arc::new(Cache::new());
+        let follower_meta: MetaDb = Arc::new(DashMap::new());
+        let mut follower = Raft::default();
+        let mut request = append(1100, (1101..=1105).map(entry).collect(), 1105);
+        request.snapshot = Some(node.snapshot.clone());
+        assert!(follower.handle_append(request, &follower_cache, &follower_meta).await.success);
+        assert_eq!(follower.commit_index, 1105);
+        assert_eq!(follower_cache.get("key1").unwrap().clone(), Value::U64(1101));
+        assert_eq!(follower_cache.get("key9").unwrap().clone(), Value::U64(1099));
+        assert!(follower_meta.get("key9").unwrap().version > 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn sync_resume() {
+        let cache: Db = Arc::new(Cache::new());
+        let meta: MetaDb = Arc::new(DashMap::new());
+        for i in 0..600u64 {
+            metadata::apply(&cache, &meta, format!("key{:03}", i), Value::U64(i), versioned(i + 1));
+        }
+        let config = config::RuntimeConfig::default().to_arc();
+        let code = config.read().await.base_code.clone();
+        let (tx, _) = transport::channel(16);
+        let raft = raft::Raft::default().to_arc();
+        let slow_log = access_log::SlowLog::default().to_arc();
+        let api = create_api(cache.clone(), meta.clone(), config, tx, raft, slow_log);
+        let (address, server) = warp::serve(api).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let address: url::Url = format!("http://{}", address).parse().unwrap();
+        let url = address.join("/_internal/sync").unwrap();
+        let http = reqwest::Client::new();
+        let sync = |after: Option<String>| http.post(url.clone()).json(&serde_json::json!({ "code": code, "after": after })).send();
+        let lines = |buffer: &[u8]| -> Vec<responses::Change> {
+            let end = buffer.iter().rposition(|x| *x == b'\n').map_or(0, |x| x + 1);
+            buffer[..end].split(|x| *x == b'\n').filter(|x| !x.is_empty()).map(|x| serde_json::from_slice(x).unwrap()).collect()
+        };
+
+        // every key is one json line, the stream is dropped halfway
+        let mut response = sync(None).await.unwrap();
+        assert_eq!(response.headers()["content-type"], encoding::NDJSON);
+        let mut buffer = Vec::new();
+        while lines(&buffer).len() < 300 {
+            buffer.extend_from_slice(&response.chunk().await.unwrap().unwrap());
+        }
+        drop(response);
+        let mut received = lines(&buffer);
+        assert_eq!(received[0].key, "key000");
+        assert_eq!(received[0].meta.version, 1);
+
+        // resuming after the last whole key sends the rest without gaps or repeats
+        let after = received.last().map(|x| x.key.clone());
+        let body = sync(after).await.unwrap().bytes().await.unwrap();
+        assert_eq!(body.last(), Some(&b'\n'));
+        received.extend(lines(&body));
+        let keys: Vec<String> = received.iter().map(|x| x.key.clone()).collect();
+        let expected: Vec<String> = (0..600).map(|i| format!("key{:03}", i)).collect();
+        assert_eq!(keys, expected);
+        assert!(received.iter().all(|x| x.data == Value::U64(x.meta.version - 1)));
+
+        // the client reads the binary frames into its own cache
+        let (other_cache, other_meta): (Db, MetaDb) = (Arc::new(Cache::new()), Arc::new(DashMap::new()));
+        let synced = client::Client::new().sync(address, &code, &other_cache, &other_meta).await.unwrap();
+        assert_eq!(synced, 600);
+        assert_eq!(other_cache.len(), 600);
+        assert_eq!(other_meta.get("key599").unwrap().version, 600);
+    }
+
+    #[tokio::test]
+    async fn raw_value_round_trip() {
+        let image: &[u8] = &[0x89, b'P', b'N', b'G', 0xff, 0x00];
+        let raw = |version| metadata::Metadata { content_type: Some(String::from("image/png")), ..versioned(version) };
+        let cache: Db = Arc::new(Cache::new());
+        let meta: MetaDb = Arc::new(DashMap::new());
+        metadata::apply(&cache, &meta, String::from("image"), Value::Bytes(image.to_vec()), raw(1));
+        metadata::apply(&cache, &meta, String::from("text"), Value::String(String::from("iVBORw==")), versioned(2));
+        let dir = std::env::temp_dir().join(format!("racher-raw-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let config = config::RuntimeConfig { backup_dir: dir.clone(), ..Default::default() }.to_arc();
+        let code = config.read().await.base_code.clone();
+        let (tx, _) = transport::channel(16);
+        let api = create_api(cache.clone(), meta.clone(), config.clone(), tx, raft::Raft::default().to_arc(), access_log::SlowLog::default().to_arc());
+        let (address, server) = warp::serve(api).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let address: url::Url = format!("http://{}", address).parse().unwrap();
+
+        // json lines carry the raw value as base64 text, strings stay as they are
+        let body = reqwest::Client::new()
+            .post(address.join("/_internal/sync").unwrap())
+            .json(&serde_json::json!({ "code": code }))
+            .send().await.unwrap().bytes().await.unwrap();
+        let changes: Vec<responses::Change> = body.split(|x| *x == b'\n').filter(|x| !x.is_empty()).map(|x| serde_json::from_slice(x).unwrap()).collect();
+        assert_eq!(changes[0].data, Value::String(base64::encode(image)));
+        let data = |change: &responses::Change| encoding::raw_from_json(change.data.clone(), change.meta.content_type.as_deref(), encoding::Encoding::Json);
+        assert_eq!(data(&changes[0]), Value::Bytes(image.to_vec()));
+        assert_eq!(data(&changes[1]), Value::String(String::from("iVBORw==")));
+
+        // the binary sync keeps the bytes and the content type
+        let (other_cache, other_meta): (Db, MetaDb) = (Arc::new(Cache::new()), Arc::new(DashMap::new()));
+        client::Client::new().sync(address, &code, &other_cache, &other_meta).await.unwrap();
+        assert_eq!(*other_cache.get("image").unwrap(), Value::Bytes(image.to_vec()));
+        assert_eq!(other_meta.get("image").unwrap().content_type.as_deref(), Some("image/png"));
+
+        // and so does a backup
+        cli::write_backup(&config, &other_cache, &other_meta).await.unwrap();
+        let loaded_meta: MetaDb = Arc::new(DashMap::new());
+        let loaded = cli::load_from_backup(config, loaded_meta.clone()).await.unwrap();
+        assert_eq!(*loaded.get("image").unwrap(), Value::Bytes(image.to_vec()));
+        assert_eq!(*loaded.get("text").unwrap(), Value::String(String::from("iVBORw==")));
+        assert_eq!(loaded_meta.get("image").unwrap().content_type.as_deref(), Some("image/png"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn best_peer() {
+        let info = |code: &str, keys: usize, version: u64| responses::InfoResponse {
+            identifier: code.to_string(),
+            code: code.to_string(),
+            address: "http://127.0.0.1:9226".parse().unwrap(),
+            neighbours: Vec::new(),
+            keys,
+            version,
+            last_backup: None,
+        };
+        let peer = |port: u16, info| (format!("http://127.0.0.1:{}", port).parse::<url::Url>().unwrap(), info);
+
+        assert!(cli::best_peer(&[]).is_none());
+        // the newest write wins over more keys, and equal nodes agree on the lowest code
+        let peers = vec![peer(1, info("a", 10, 5)), peer(2, info("b", 1, 6)), peer(3, info("c", 1, 6))];
+        assert_eq!(cli::best_peer(&peers).unwrap().0.port(), Some(2));
+        let peers = vec![peer(1, info("b", 3, 6)), peer(2, info("a", 3, 6))];
+        assert_eq!(cli::best_peer(&peers).unwrap().0.port(), Some(2));
+    }
+
+    #[tokio::test]
+    async fn converge() {
+        let seed_cache: Db = Arc::new(Cache::new());
+        let seed_meta: MetaDb = Arc::new(DashMap::new());
+        seed_cache.insert("key".into(), Value::String("value".into()));
+        seed_meta.insert("key".into(), versioned(10));
+        let seed_config = config::RuntimeConfig::default().to_arc();
+        let (tx, _) = transport::channel(16);
+        let api = create_api(
+            seed_cache,
+            seed_meta,
+            seed_config.clone(),
+            tx,
+            raft::Raft::default().to_arc(),
+            access_log::SlowLog::default().to_arc(),
+        );
+        let (address, server) = warp::serve(api).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let seed: url::Url = format!("http://{}", address).parse().unwrap();
+        seed_config.write().await.external_address = seed.clone();
+        let seed_code = seed_config.read().await.base_code.clone();
+
+        // a new node adopts the code of the seed with newer data and syncs from it
+        let cache: Db = Arc::new(Cache::new());
+        let meta: MetaDb = Arc::new(DashMap::new());
+        let mut config = config::RuntimeConfig::default();
+        config.external_address = "http://127.0.0.1:1".parse().unwrap();
+        config.seeds.insert(seed.clone());
+        let config = config.to_arc();
+        assert!(cli::converge(config.clone(), cache.clone(), meta.clone(), true).await.unwrap());
+        assert_eq!(cache.get("key").unwrap().clone(), Value::String("value".into()));
+        assert_eq!(meta.get("key").unwrap().version, 10);
+        assert_eq!(config.read().await.base_code, seed_code);
+        assert!(config.read().await.neighbours.contains(&seed));
+
+        // without anyone to reach it reports that and keeps its code
+        let lonely = config::RuntimeConfig::default().to_arc();
+        let code = lonely.read().await.base_code.clone();
+        assert!(!cli::converge(lonely.clone(), cache, meta, true).await.unwrap());
+        assert_eq!(lonely.read().await.base_code, code);
+    }
+
+    #[tokio::test]
+    async fn cluster_status() {
+        use chrono::{Duration, Utc};
+
+        let cache: Db = Arc::new(Cache::new());
+        let meta: MetaDb = Arc::new(DashMap::new());
+        cache.insert("key".into(), Value::Bool(true));
+        meta.insert("key".into(), versioned(5_000_000));
+        let down: url::Url = "http://127.0.0.1:1".parse().unwrap();
+        let left: url::Url = "http://127.0.0.1:2".parse().unwrap();
+        let mut config = config::RuntimeConfig::default();
+        config.neighbours.insert(down.clone());
+        config.last_seen.insert(down.clone(), Utc::now() - Duration::hours(48));
+        config.last_seen.insert(left.clone(), Utc::now() - Duration::hours(48));
+
+        // members that are no longer neighbours are forgotten after a while
+        config.forget_members(Utc::now());
+        assert!(config.last_seen.contains_key(&down));
+        assert!(!config.last_seen.contains_key(&left));
+
+        let (tx, _) = transport::channel(16);
+        let filter = create_api(
+            cache,
+            meta,
+            config.to_arc(),
+            tx,
+            raft::Raft::default().to_arc(),
+            access_log::SlowLog::default().to_arc(),
+        );
+        let response = warp::test::request().method("POST").path("/cluster/status").reply(&filter).await;
+        let status: responses::ClusterStatusResponse = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(status.members.len(), 2);
+        let me = &status.members[0];
+        assert!(me.up);
+        assert_eq!(me.keys, Some(1));
+        assert_eq!(me.version_gap_ms, Some(0));
+        let member = &status.members[1];
+        assert_eq!(member.address, down);
+        assert!(!member.up);
+        assert_eq!(member.version_gap_ms, None);
+    }
+
+    #[tokio::test]
+    async fn quorum_set_and_get() {
+        let map = DashMap::new();
+
+        let cache = Arc::new(Cache::from(map));
+        let filter = setup(cache.clone());
+
+        let value = warp::test::request()
+            .method("POST")
+            .path("/set/testing?consistency=quorum")
+            .json(&Value::I64(123))
+            .reply(&filter)
+            .await
+            .into_body();
+
+        let value: responses::SetResponse = serde_json::from_slice(&value).unwrap();
+        assert_eq!(
+            value,
+            responses::SetResponse {
+                status: Value::String("ok".into())
+            }
+        );
+
+        let value = warp::test::request()
+            .method("POST")
+            .path("/get/testing?consistency=all")
+            .reply(&filter)
+            .await
+            .into_body();
+
+        let value: responses::GetResponse = serde_json::from_slice(&value).unwrap();
+        let expected = responses::GetResponse {
+            data: Value::U64(123),
+        };
+        assert_eq!(value, expected);
+    }
+
+    #[tokio::test]
+    async fn quorum_counts_unreachable_members() {
+        let cache = Arc::new(Cache::from(DashMap::new()));
+        let (tx, _) = transport::channel(16);
+        let mut config = config::RuntimeConfig::default();
+        // the member is down, so it is not a neighbour anymore
+        config.members.insert("http://127.0.0.1:1".parse().unwrap());
+        let filter = create_api(cache.clone(), Arc::new(DashMap::new()), config.to_arc(), tx, raft::Raft::default().to_arc(), access_log::SlowLog::default().to_arc());
+
+        let response = warp::test::request().method("POST").path("/set/testing?consistency=quorum").json(&Value::I64(123)).reply(&filter).await;
+        assert_eq!(503, response.status());
+        let response = warp::test::request().method("POST").path("/get/testing?consistency=all").reply(&filter).await;
+        assert_eq!(503, response.status());
+        let response = warp::test::request().method("POST").path("/get/testing").reply(&filter).await;
+        assert_eq!(200, response.status());
+    }
+
+    #[tokio::test]
+    async fn internal_update_ignores_stale_version() {
+        let map = DashMap::new();
+
+        let cache = Arc::new(Cache::from(map));
+        let filter = setup(cache.clone());
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/_internal/update/testing?version=20")
+            .json(&Value::I64(1))
+            .reply(&filter)
+            .await;
+        assert_eq!(200, response.status());
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/_internal/update/testing?version=10")
+            .json(&Value::I64(2))
+            .reply(&filter)
+            .await;
+        assert_eq!(200, response.status());
+
+        let value = warp::test::request()
+            .method("POST")
+            .path("/_internal/get/testing")
+            .reply(&filter)
+            .await
+            .into_body();
+
+        let value: responses::VersionedResponse = serde_json::from_slice(&value).unwrap();
+        let expected = responses::VersionedResponse {
+            data: Value::U64(1),
+            meta: versioned(20),
+        };
+        assert_eq!(value, expected);
+    }
+
+    #[tokio::test]
+    async fn replicated_null_is_stored() {
+        let cache: Db = Arc::new(Cache::new());
+        let meta: MetaDb = Arc::new(DashMap::new());
+        let (tx, _) = transport::channel(16);
+        let config = config::RuntimeConfig::default().to_arc();
+        let raft = raft::Raft::default().to_arc();
+        let slow_log = access_log::SlowLog::default().to_arc();
+        let filter = create_api(cache.clone(), meta, config, tx, raft, slow_log);
+        let update = |path: &'static str| warp::test::request().method("POST").path(path).json(&Value::Unit).reply(&filter);
+        let get = || warp::test::request().path("/keys/testing?consistency=quorum").reply(&filter);
+
+        assert_eq!(update("/_internal/update/testing?version=10").await.status(), 200);
+        assert_eq!(cache.get("testing").unwrap().clone(), Value::Unit);
+        let response = get().await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.body(), "null");
+
+        // deletes carry the tombstone flag, the value does not matter
+        assert_eq!(update("/_internal/update/testing?version=20&deleted=true").await.status(), 200);
+        assert!(!cache.contains_key("testing"));
+        assert_eq!(get().await.status(), 404);
+        let value = warp::test::request().method("POST").path("/_internal/get/testing").reply(&filter).await.into_body();
+        let value: responses::VersionedResponse = serde_json::from_slice(&value).unwrap();
+        assert_eq!(value.meta, metadata::Metadata { deleted: true, ..versioned(20) });
+    }
+
     #[tokio::test]
     async fn keys() {
         let map = DashMap::new();
         map.insert(String::from("testing"), Value::Bool(true));
         map.insert(String::from("another"), Value::Bool(true));
 
-        let cache = Arc::new(map);
+        let cache = Arc::new(Cache::from(map));
         let filter = setup(cache);
 
         let value = warp::test::request()
@@ -185,13 +871,37 @@ mod test {
         assert_eq!(keys, vec!["another", "testing"]);
     }
 
+    #[tokio::test]
+    async fn expired_keys_hidden() {
+        let cache: Db = Arc::new(Cache::new());
+        let meta: MetaDb = Arc::new(DashMap::new());
+        metadata::apply(&cache, &meta, String::from("live"), Value::Bool(true), versioned(1));
+        metadata::apply(&cache, &meta, String::from("old"), Value::Bool(true), metadata::Metadata { expires_at: Some(1), ..versioned(2) });
+        let (tx, _) = transport::channel(16);
+        let config = config::RuntimeConfig::default().to_arc();
+        let raft = raft::Raft::default().to_arc();
+        let slow_log = access_log::SlowLog::default().to_arc();
+        let filter = create_api(cache, meta, config, tx, raft, slow_log);
+
+        let value = warp::test::request().method("POST").path("/keys").reply(&filter).await.into_body();
+        let value: responses::KeysResponse = serde_json::from_slice(&value).unwrap();
+        assert_eq!(value.keys, vec!["live"]);
+
+        for consistency in &["one", "quorum", "all"] {
+            let path = format!("/get/old?consistency={}", consistency);
+            let value = warp::test::request().method("POST").path(&path).reply(&filter).await.into_body();
+            let value: responses::GetResponse = serde_json::from_slice(&value).unwrap();
+            assert_eq!(value.data, Value::Unit, "{}", consistency);
+        }
+    }
+
     #[tokio::test]
     async fn delete_existing() {
         let map = DashMap::new();
         map.insert(String::from("testing"), Value::Bool(true));
         map.insert(String::from("another"), Value::Bool(true));
 
-        let cache = Arc::new(map);
+        let cache = Arc::new(Cache::from(map));
 
         assert!(cache.contains_key("testing"));
         assert!(cache.contains_key("another"));
@@ -219,7 +929,7 @@ mod test {
         map.insert(String::from("testing"), Value::Bool(true));
         map.insert(String::from("another"), Value::Bool(true));
 
-        let cache = Arc::new(map);
+        let cache = Arc::new(Cache::from(map));
 
         assert!(cache.contains_key("testing"));
         assert!(cache.contains_key("another"));
@@ -247,7 +957,7 @@ mod test {
         map.insert(String::from("testing"), Value::Bool(true));
         map.insert(String::from("another"), Value::Bool(true));
 
-        let cache = Arc::new(map);
+        let cache = Arc::new(Cache::from(map));
 
         assert!(cache.contains_key("testing"));
         assert!(cache.contains_key("another"));
@@ -268,4 +978,1105 @@ mod test {
         assert!(!cache.contains_key("testing"));
         assert!(!cache.contains_key("another"));
     }
+
+    #[test]
+    fn purge_keeps_versions() {
+        let cache: Db = Arc::new(Cache::new());
+        let meta: MetaDb = Arc::new(DashMap::new());
+        metadata::apply(&cache, &meta, String::from("testing"), Value::Bool(true), versioned(1));
+        metadata::purge(&cache, &meta);
+        assert!(cache.is_empty());
+
+        // the value a neighbour or backup still has is older than the purge
+        let version = metadata::version_of(&meta, "testing");
+        assert!(version > 1);
+        assert!(!metadata::apply(&cache, &meta, String::from("testing"), Value::Bool(true), versioned(1)));
+        assert!(cache.is_empty());
+        assert!(metadata::apply(&cache, &meta, String::from("testing"), Value::Bool(false), versioned(version + 1)));
+    }
+
+    #[tokio::test]
+    async fn metrics() {
+        let map = DashMap::new();
+        map.insert(String::from("testing"), Value::Bool(true));
+
+        let cache = Arc::new(Cache::from(map));
+        let filter = setup(cache);
+
+        // the registry is shared with the other tests, so only the increase is checked
+        let requests = metrics::REQUESTS.with_label_values(&["get", "200"]);
+        let (requests_before, hits_before) = (requests.get(), metrics::GETS.with_label_values(&["hit"]).get());
+        let misses_before = metrics::GETS.with_label_values(&["miss"]).get();
+        for path in ["/get/testing", "/get/testing?consistency=quorum", "/get/missing?consistency=all"] {
+            warp::test::request().method("POST").path(path).reply(&filter).await;
+        }
+        assert!(requests.get() >= requests_before + 3);
+        assert!(metrics::GETS.with_label_values(&["hit"]).get() >= hits_before + 2);
+        assert!(metrics::GETS.with_label_values(&["miss"]).get() > misses_before);
+
+        let response = warp::test::request()
+            .method("GET")
+            .path("/metrics")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+
+        let body = String::from_utf8(response.into_body().to_vec()).unwrap();
+        assert!(body.contains("racher_keys 1"));
+        assert!(body.contains("racher_requests_total{route=\"get\",status=\"200\"}"));
+    }
+
+    #[tokio::test]
+    async fn stats() {
+        let map = DashMap::new();
+        map.insert(String::from("testing"), Value::Bool(true));
+
+        let cache = Arc::new(Cache::from(map));
+        let filter = setup(cache);
+
+        let value = warp::test::request()
+            .method("POST")
+            .path("/stats")
+            .reply(&filter)
+            .await
+            .into_body();
+
+        let value: responses::StatsResponse = serde_json::from_slice(&value).unwrap();
+        assert_eq!(value.keys, 1);
+        assert_eq!(value.memory_bytes, "testing".len() as u64 + "true".len() as u64);
+        assert_eq!(value.last_backup, None);
+        assert_eq!(value.limits.max_value_bytes, MAX_FILE_SIZE);
+
+        // the size follows every write, replace and delete
+        let memory_bytes = || async {
+            let body = warp::test::request().method("POST").path("/stats").reply(&filter).await.into_body();
+            serde_json::from_slice::<responses::StatsResponse>(&body).unwrap().memory_bytes
+        };
+        warp::test::request().method("POST").path("/set/other").json(&"value").reply(&filter).await;
+        assert_eq!(memory_bytes().await, 11 + "other\"value\"".len() as u64);
+        warp::test::request().method("POST").path("/set/testing").json(&false).reply(&filter).await;
+        assert_eq!(memory_bytes().await, 12 + "other\"value\"".len() as u64);
+        warp::test::request().method("POST").path("/del/other").reply(&filter).await;
+        assert_eq!(memory_bytes().await, 12);
+        warp::test::request().method("POST").path("/purge").reply(&filter).await;
+        assert_eq!(memory_bytes().await, 0);
+    }
+
+    #[tokio::test]
+    async fn slowlog() {
+        let (tx, _) = transport::channel(16);
+        let config = config::RuntimeConfig {
+            admin_token: Some(String::from("secret")),
+            ..Default::default()
+        }
+        .to_arc();
+        let raft = raft::Raft::default().to_arc();
+        let slow_log = access_log::SlowLog::new(Duration::from_millis(10), 2).to_arc();
+        for latency in [20, 5, 30, 40].iter() {
+            let entry = responses::SlowLogEntry {
+                time: chrono::Utc::now(),
+                method: String::from("POST"),
+                path: format!("/get/{}", latency),
+                status: 200,
+                latency_ms: *latency as f64,
+                peer: "127.0.0.1:1234".parse().unwrap(),
+            };
+            slow_log
+                .lock()
+                .unwrap()
+                .record(entry, Duration::from_millis(*latency));
+        }
+        let filter = create_api(
+            Arc::new(Cache::new()),
+            Arc::new(DashMap::new()),
+            config,
+            tx,
+            raft,
+            slow_log,
+        );
+
+        let value = warp::test::request()
+            .method("POST")
+            .path("/admin/slowlog")
+            .header("authorization", "Bearer secret")
+            .reply(&filter)
+            .await
+            .into_body();
+
+        let value: responses::SlowLogResponse = serde_json::from_slice(&value).unwrap();
+        let paths: Vec<&str> = value.entries.iter().map(|x| x.path.as_str()).collect();
+        assert_eq!(paths, vec!["/get/40", "/get/30"]);
+    }
+
+    #[tokio::test]
+    async fn admin_config() {
+        let (tx, _) = transport::channel(16);
+        let config = config::RuntimeConfig::default().to_arc();
+        let raft = raft::Raft::default().to_arc();
+        let slow_log = access_log::SlowLog::default().to_arc();
+        let filter = create_api(
+            Arc::new(Cache::new()),
+            Arc::new(DashMap::new()),
+            config.clone(),
+            tx,
+            raft,
+            slow_log,
+        );
+        let update = |token: &str, body: &str| {
+            warp::test::request()
+                .method("POST")
+                .path("/admin/config")
+                .header("authorization", format!("Bearer {}", token))
+                .body(body.to_string())
+        };
+
+        let response = update("secret", "{}").reply(&filter).await;
+        assert_eq!(response.status(), 403);
+
+        config.write().await.admin_token = Some(String::from("secret"));
+        let response = update("wrong", "{}").reply(&filter).await;
+        assert_eq!(response.status(), 401);
+        let response = update("secret", r#"{"backup_interval": 0}"#).reply(&filter).await;
+        assert_eq!(response.status(), 400);
+
+        let response = update("secret", r#"{"backup_interval": 5}"#).reply(&filter).await;
+        assert_eq!(response.status(), 200);
+        let value: responses::AdminConfigResponse = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(value.backup_interval, 5);
+        assert_eq!(value.backup_amount, 10);
+        assert_eq!(config.read().await.backup_interval, 5);
+    }
+
+    #[tokio::test]
+    async fn internal_changes() {
+        let (tx, _) = transport::channel(16);
+        let config = config::RuntimeConfig::default();
+        let code = config.base_code.clone();
+        let cache: Db = Arc::new(Cache::new());
+        let meta: MetaDb = Arc::new(DashMap::new());
+        metadata::apply(&cache, &meta, String::from("old"), Value::Bool(true), versioned(10));
+        metadata::apply(&cache, &meta, String::from("new"), Value::Bool(true), versioned(20));
+        metadata::apply(&cache, &meta, String::from("deleted"), Value::Bool(true), versioned(21));
+        metadata::apply(&cache, &meta, String::from("deleted"), Value::Unit, versioned(30));
+        let filter = create_api(
+            cache,
+            meta,
+            config.to_arc(),
+            tx,
+            raft::Raft::default().to_arc(),
+            access_log::SlowLog::default().to_arc(),
+        );
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/_internal/changes")
+            .json(&serde_json::json!({"code": code, "since": 15}))
+            .reply(&filter)
+            .await;
+        let mut value: responses::ChangesResponse = serde_json::from_slice(response.body()).unwrap();
+        value.changes.sort_by_key(|x| x.meta.version);
+        let expected = vec![
+            responses::Change { key: String::from("new"), data: Value::Bool(true), meta: versioned(20) },
+            responses::Change { key: String::from("deleted"), data: Value::Unit, meta: versioned(30) },
+        ];
+        assert_eq!(value.changes, expected);
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/_internal/changes")
+            .json(&serde_json::json!({"code": "wrong", "since": 0}))
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 400);
+    }
+
+    #[tokio::test]
+    async fn binary_encodings() {
+        let cache = Arc::new(Cache::new());
+        let filter = setup(cache.clone());
+        let value = serde_json::json!({"list": [1, 2.5, "three"], "nested": {"yes": true}});
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/set/testing")
+            .header("content-type", encoding::MSGPACK)
+            .body(encoding::Encoding::MessagePack.encode(&value).unwrap())
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/get/testing")
+            .header("accept", encoding::CBOR)
+            .reply(&filter)
+            .await;
+        assert_eq!(response.headers()["content-type"], encoding::CBOR);
+        let body: serde_json::Value = encoding::Encoding::Cbor.decode(response.body()).unwrap();
+        assert_eq!(body, serde_json::json!({ "data": value }));
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/set/testing")
+            .header("content-type", encoding::CBOR)
+            .body("not cbor")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 400);
+
+        // a value that can not be written leaves the frames before it as they were
+        let mut buffer = Vec::new();
+        encoding::Encoding::Json.write_frame(&mut buffer, &1).unwrap();
+        let mut map = std::collections::BTreeMap::new();
+        map.insert(Value::Bool(true), Value::Unit);
+        assert!(encoding::Encoding::Json.write_frame(&mut buffer, &Value::Map(map)).is_err());
+        assert_eq!(buffer, b"1\n");
+    }
+
+    #[tokio::test]
+    async fn internal_update_falls_back_to_json() {
+        use warp::http::StatusCode;
+
+        // a node that only takes json, like the ones from before the binary formats
+        let received = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let noted = received.clone();
+        let old_node = warp::path!("_internal" / "update" / String)
+            .and(warp::header::optional::<String>("content-type"))
+            .and(warp::body::bytes())
+            .map(move |_key: String, content_type: Option<String>, body: warp::hyper::body::Bytes| {
+                match encoding::Encoding::from_content_type(content_type.as_deref()) {
+                    Some(encoding::Encoding::Json) => {
+                        noted.lock().unwrap().push(serde_json::from_slice::<Value>(&body).unwrap());
+                        StatusCode::OK
+                    }
+                    _ => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                }
+            });
+        let (address, server) = warp::serve(old_node).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let address: url::Url = format!("http://{}", address).parse().unwrap();
+
+        let value = Value::String(String::from("value"));
+        client::Client::new().internal_update(address, "key", &value, &versioned(1)).await.unwrap();
+        assert_eq!(*received.lock().unwrap(), vec![value]);
+    }
+
+    #[tokio::test]
+    async fn raw_values() {
+        let cache: Db = Arc::new(Cache::new());
+        let meta: MetaDb = Arc::new(DashMap::new());
+        let (tx, mut rx) = transport::channel(16);
+        let filter = create_api(
+            cache.clone(),
+            meta.clone(),
+            config::RuntimeConfig::default().to_arc(),
+            tx,
+            raft::Raft::default().to_arc(),
+            access_log::SlowLog::default().to_arc(),
+        );
+        let image: &[u8] = &[0x89, b'P', b'N', b'G', 0xff, 0x00];
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/set/image")
+            .header("content-type", "image/png")
+            .body(image)
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(cache.get("image").unwrap().value(), &Value::Bytes(image.to_vec()));
+        match rx.try_recv().unwrap() {
+            transport::Message::Created(_, _, metadata, _) => {
+                assert_eq!(metadata.content_type.as_deref(), Some("image/png"))
+            }
+            message => panic!("unexpected message {:?}", message),
+        }
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/get/image")
+            .header("accept", encoding::MSGPACK)
+            .reply(&filter)
+            .await;
+        assert_eq!(response.headers()["content-type"], "image/png");
+        assert_eq!(response.body().as_ref(), image);
+
+        // replicated raw values keep their content type
+        let response = warp::test::request()
+            .method("POST")
+            .path("/_internal/update/text?version=10&content_type=text%2Fplain")
+            .header("content-type", encoding::MSGPACK)
+            .body(encoding::Encoding::MessagePack.encode(&Value::Bytes(b"plain".to_vec())).unwrap())
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/get/text")
+            .reply(&filter)
+            .await;
+        assert_eq!(response.headers()["content-type"], "text/plain");
+        assert_eq!(response.body().as_ref(), b"plain");
+
+        // json replaces the raw value and its content type
+        let response = warp::test::request()
+            .method("POST")
+            .path("/set/image")
+            .json(&true)
+            .reply(&filter)
+            .await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(meta.get("image").unwrap().content_type, None);
+    }
+
+    #[test]
+    fn config_file_settings() {
+        let file: config::ConfigFile = toml::from_str(
+            r#"
+            address = "127.0.0.1:9000"
+            consensus = false
+
+            [backup]
+            interval = 30
+
+            [logging]
+            level = "debug"
+
+            [rate_limit.prefixes]
+            "users:" = 10
+            "sessions:" = 50
+            "#,
+        )
+        .unwrap();
+        assert_eq!(file.validate(), Ok(()));
+        assert_eq!(
+            file.settings(),
+            vec![
+                ("address", "RACHER_ADDRESS", String::from("127.0.0.1:9000")),
+                ("backup.interval", "RACHER_BACKUP_INTERVAL", String::from("30")),
+                ("logging.level", "RACHER_LOGGER_LEVEL", String::from("DEBUG")),
+                (
+                    "rate_limit.prefixes",
+                    "RACHER_RATE_LIMIT_PREFIXES",
+                    String::from("sessions:=50,users:=10")
+                ),
+            ]
+        );
+
+        let file: config::ConfigFile = serde_yaml::from_str("backup:\n  interval: 0\n").unwrap();
+        assert!(file.validate().is_err());
+        assert!(toml::from_str::<config::ConfigFile>("adress = \"x\"").is_err());
+    }
+
+    #[test]
+    fn config_file_merge() {
+        use arguments::{Args, SubArg};
+        use structopt::StructOpt;
+
+        let file: config::ConfigFile = toml::from_str(
+            r#"
+            address = "127.0.0.1:9000"
+            consensus = true
+            retry_attempts = 2
+            request_timeout = 5000
+
+            [backup]
+            interval = 30
+            amount = 5
+
+            [rate_limit.prefixes]
+            "users:" = 10
+            "#,
+        )
+        .unwrap();
+        let parse = |argv: &[&str]| {
+            let matches = Args::clap().get_matches_from_safe(argv).unwrap();
+            Args::from_clap(&matches).merge_config_file(&file, &matches)
+        };
+
+        // the file replaces the defaults, flags replace the file
+        let args = parse(&["racher", "--backup-amount", "3", "--retry-backoff", "10"]);
+        let cfg = args.as_runtime_config();
+        assert_eq!(cfg.address, "127.0.0.1:9000".parse().unwrap());
+        assert!(cfg.consensus);
+        assert_eq!(cfg.backup_interval, 30);
+        assert_eq!(cfg.backup_amount, 3);
+        assert_eq!(cfg.rate_limit_prefixes, vec!["users:=10".parse().unwrap()]);
+        let retry = client::RetryPolicy::from_config(&cfg);
+        assert_eq!((retry.retries, retry.initial_backoff), (2, std::time::Duration::from_millis(10)));
+        assert_eq!((cfg.max_concurrent_requests, cfg.request_timeout), (MAX_CONCURRENT_REQUESTS, 5000));
+        assert!(args.fixed.contains("backup.amount"));
+        assert!(!args.fixed.contains("backup.interval"));
+
+        // the settings of a joining node come from the file as well
+        let args = parse(&["racher", "join", "--join-address", "http://127.0.0.1:9226", "-a", "127.0.0.1:9001"]);
+        assert!(matches!(args.sub_cmd, Some(SubArg::Join(_))));
+        let cfg = args.as_runtime_config();
+        assert_eq!(cfg.address, "127.0.0.1:9001".parse().unwrap());
+        assert_eq!(cfg.backup_interval, 30);
+        assert_eq!(cfg.backup_amount, 5);
+    }
+
+    #[test]
+    fn config_file_reload() {
+        let defaults = config::RuntimeConfig { backup_interval: 60, backup_amount: 10, ..Default::default() };
+        let mut cfg = defaults.clone();
+        let file: config::ConfigFile = toml::from_str("admin_token = \"secret\"\n[backup]\ninterval = 30\namount = 5").unwrap();
+        let fixed = vec!["backup.amount"].into_iter().collect();
+
+        // settings given as flags or env vars keep their value
+        let update = cfg.reload(&file, &fixed, &defaults);
+        assert_eq!(update, config::ConfigUpdate { backup_interval: Some(30), backup_amount: None });
+        assert_eq!((cfg.backup_interval, cfg.backup_amount), (30, 10));
+        assert_eq!(cfg.admin_token.as_deref(), Some("secret"));
+
+        // settings that were removed from the file go back to their defaults
+        cfg.reload(&config::ConfigFile::default(), &fixed, &defaults);
+        assert_eq!((cfg.backup_interval, cfg.backup_amount), (60, 10));
+        assert_eq!(cfg.admin_token, None);
+
+        // the rate limits can only be set at startup, a reload that changes them is rejected
+        let limited = config::RuntimeConfig { rate_limit: 100, ..defaults.clone() };
+        assert!(defaults.same_rate_limits(&defaults.clone()));
+        assert!(!defaults.same_rate_limits(&limited));
+    }
+
+    async fn send_command(client: &mut tokio::io::DuplexStream, request: &[u8], expected: &[u8]) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        client.write_all(request).await.unwrap();
+        let mut response = vec![0; expected.len()];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(String::from_utf8_lossy(&response), String::from_utf8_lossy(expected));
+    }
+
+    #[tokio::test]
+    async fn resp_protocol() {
+        let cache: Db = Arc::new(Cache::new());
+        let meta: MetaDb = Arc::new(DashMap::new());
+        let (tx, mut rx) = transport::channel(16);
+        let (mut client, server) = tokio::io::duplex(4096);
+        tokio::spawn(resp::handle(server, cache.clone(), meta.clone(), tx));
+
+        send_command(&mut client, b"PING\r\n", b"+PONG\r\n").await;
+        send_command(&mut client, b"*3\r\n$3\r\nSET\r\n$3\r\none\r\n$5\r\nfirst\r\n", b"+OK\r\n").await;
+        assert_eq!(cache.get("one").unwrap().value(), &Value::String(String::from("first")));
+        assert!(matches!(rx.try_recv(), Ok(transport::Message::Created(..))));
+        send_command(&mut client, b"*2\r\n$3\r\nGET\r\n$3\r\none\r\n", b"$5\r\nfirst\r\n").await;
+        send_command(&mut client, b"*3\r\n$4\r\nMGET\r\n$3\r\none\r\n$3\r\ntwo\r\n", b"*2\r\n$5\r\nfirst\r\n$-1\r\n").await;
+
+        send_command(&mut client, b"INCR counter\r\n", b":1\r\n").await;
+        send_command(&mut client, b"INCR counter\r\n", b":2\r\n").await;
+        send_command(&mut client, b"INCR one\r\n", b"-ERR value is not an integer or out of range\r\n").await;
+        send_command(&mut client, b"KEYS c*\r\n", b"*1\r\n$7\r\ncounter\r\n").await;
+        send_command(&mut client, b"KEYS c?u[a-n]t*r\r\n", b"*1\r\n$7\r\ncounter\r\n").await;
+        cache.insert("a".repeat(64), Value::Unit);
+        send_command(&mut client, b"KEYS *a*a*a*a*a*a*a*a*a*a*a*a*b\r\n", b"*0\r\n").await;
+        cache.remove(&"a".repeat(64));
+
+        send_command(&mut client, b"SET short value EX 100\r\n", b"+OK\r\n").await;
+        assert!(meta.get("short").unwrap().expires_at.is_some());
+        send_command(&mut client, b"SET short value EX 0\r\n", b"-ERR invalid expire time in 'set' command\r\n").await;
+        meta.get_mut("short").unwrap().expires_at = Some(1);
+        send_command(&mut client, b"GET short\r\n", b"$-1\r\n").await;
+        assert_eq!(metadata::remove_expired(&cache, &meta), 1);
+
+        send_command(&mut client, b"DEL one two\r\n", b":1\r\n").await;
+        let version = env!("CARGO_PKG_VERSION");
+        let hello = format!(
+            "%6\r\n$6\r\nserver\r\n$6\r\nracher\r\n$7\r\nversion\r\n${}\r\n{}\r\n\
+             $5\r\nproto\r\n:3\r\n$4\r\nmode\r\n$10\r\nstandalone\r\n\
+             $4\r\nrole\r\n$6\r\nmaster\r\n$7\r\nmodules\r\n*0\r\n",
+            version.len(),
+            version
+        );
+        send_command(&mut client, b"HELLO 3\r\n", hello.as_bytes()).await;
+        send_command(&mut client, b"GET one\r\n", b"_\r\n").await;
+        send_command(&mut client, b"FLUSHALL\r\n", b"+OK\r\n").await;
+        assert!(cache.is_empty());
+        send_command(&mut client, b"NOPE\r\n", b"-ERR unknown command 'NOPE'\r\n").await;
+        send_command(&mut client, b"QUIT\r\n", b"+OK\r\n").await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn memcache_protocol() {
+        let cache: Db = Arc::new(Cache::new());
+        let meta: MetaDb = Arc::new(DashMap::new());
+        let (tx, _) = transport::channel(16);
+        let (mut client, server) = tokio::io::duplex(4096);
+        tokio::spawn(memcache::handle(server, cache.clone(), meta.clone(), tx.clone()));
+
+        send_command(&mut client, b"set one 5 0 5\r\nfirst\r\n", b"STORED\r\n").await;
+        assert_eq!(meta.get("one").unwrap().flags, Some(5));
+        send_command(&mut client, b"get one two\r\n", b"VALUE one 5 5\r\nfirst\r\nEND\r\n").await;
+        send_command(&mut client, b"add one 0 0 1\r\nx\r\n", b"NOT_STORED\r\n").await;
+        send_command(&mut client, b"replace two 0 0 1\r\nx\r\n", b"NOT_STORED\r\n").await;
+
+        let version = meta.get("one").unwrap().version;
+        let cas = format!("cas one 0 0 6 {}\r\nsecond\r\n", version);
+        send_command(&mut client, cas.as_bytes(), b"STORED\r\n").await;
+        send_command(&mut client, cas.as_bytes(), b"EXISTS\r\n").await;
+        let version = meta.get("one").unwrap().version;
+        let gets = format!("VALUE one 0 6 {}\r\nsecond\r\nEND\r\n", version);
+        send_command(&mut client, b"gets one\r\n", gets.as_bytes()).await;
+
+        send_command(&mut client, b"set counter 0 100 2\r\n10\r\n", b"STORED\r\n").await;
+        send_command(&mut client, b"incr counter 5\r\n", b"15\r\n").await;
+        send_command(&mut client, b"decr counter 20\r\n", b"0\r\n").await;
+        assert!(meta.get("counter").unwrap().expires_at.is_some());
+        send_command(
+            &mut client,
+            b"incr one 1\r\n",
+            b"CLIENT_ERROR cannot increment or decrement non-numeric value\r\n",
+        )
+        .await;
+        send_command(&mut client, b"incr two 1\r\n", b"NOT_FOUND\r\n").await;
+        assert!(!meta.contains_key("two"));
+
+        // of several cas writes with the same version only one is stored
+        let cas = format!("cas counter 0 0 1 {}\r\n1\r\n", meta.get("counter").unwrap().version);
+        let writes = (0..8).map(|_| {
+            let (mut client, server) = tokio::io::duplex(4096);
+            tokio::spawn(memcache::handle(server, cache.clone(), meta.clone(), tx.clone()));
+            let cas = cas.clone();
+            tokio::spawn(async move {
+                use tokio::io::{AsyncReadExt, AsyncWriteExt};
+                client.write_all(cas.as_bytes()).await.unwrap();
+                let mut response = vec![0; 8];
+                client.read_exact(&mut response).await.unwrap();
+                response
+            })
+        });
+        let responses = futures::future::try_join_all(writes).await.unwrap();
+        assert_eq!(responses.iter().filter(|x| x.as_slice() == b"STORED\r\n").count(), 1);
+        assert_eq!(responses.iter().filter(|x| x.as_slice() == b"EXISTS\r\n").count(), 7);
+
+        // times that do not fit in microseconds are rejected
+        send_command(&mut client, b"set huge 0 99999999999999 1\r\nx\r\n", b"CLIENT_ERROR bad command line format\r\n").await;
+        send_command(&mut client, b"flush_all 99999999999999\r\n", b"CLIENT_ERROR bad command line format\r\n").await;
+
+        send_command(&mut client, b"set gone 0 -1 1\r\nx\r\n", b"STORED\r\n").await;
+        send_command(&mut client, b"get gone\r\n", b"END\r\n").await;
+        send_command(&mut client, b"delete one noreply\r\ndelete one\r\n", b"NOT_FOUND\r\n").await;
+        send_command(&mut client, b"flush_all\r\n", b"OK\r\n").await;
+        assert!(cache.is_empty());
+        send_command(&mut client, b"bogus\r\n", b"ERROR\r\n").await;
+    }
+
+    #[tokio::test]
+    async fn grpc_api() {
+        use futures::StreamExt;
+        use grpc::proto::racher_server::Racher;
+        use grpc::proto::{self, Consistency};
+        use tonic::Request;
+
+        let cache: Db = Arc::new(Cache::new());
+        let meta: MetaDb = Arc::new(DashMap::new());
+        let (tx, mut rx) = transport::channel(16);
+        let config = config::RuntimeConfig::default().to_arc();
+        let raft = raft::Raft::default().to_arc();
+        let service = grpc::RacherService::new(cache.clone(), meta.clone(), config, tx.clone(), raft);
+
+        let watch = Request::new(proto::WatchRequest { prefix: "user/".to_string() });
+        let mut events = service.watch(watch).await.unwrap().into_inner();
+
+        for (key, data, content_type) in vec![
+            ("user/one", &b"{\"name\": \"one\"}"[..], ""),
+            ("other", &b"1"[..], "application/json"),
+            ("user/raw", &b"\x00\x01"[..], "image/png"),
+        ] {
+            let value = proto::Value { data: data.to_vec(), content_type: content_type.to_string() };
+            let request = proto::SetRequest {
+                key: key.to_string(),
+                value: Some(value),
+                consistency: Consistency::One as i32,
+            };
+            service.set(Request::new(request)).await.unwrap();
+        }
+
+        let get = |key: &str| {
+            Request::new(proto::GetRequest { key: key.to_string(), consistency: Consistency::One as i32 })
+        };
+        let value = service.get(get("user/one")).await.unwrap().into_inner().value.unwrap();
+        assert_eq!(value.content_type, "application/json");
+        assert_eq!(value.data, br#"{"name":"one"}"#.to_vec());
+        let value = service.get(get("user/raw")).await.unwrap().into_inner().value.unwrap();
+        assert_eq!(value.content_type, "image/png");
+        assert_eq!(value.data, vec![0, 1]);
+        assert!(service.get(get("missing")).await.unwrap().into_inner().value.is_none());
+
+        let invalid = proto::SetRequest {
+            key: "bad".to_string(),
+            value: Some(proto::Value { data: b"{".to_vec(), content_type: "application/json".to_string() }),
+            consistency: Consistency::One as i32,
+        };
+        let status = service.set(Request::new(invalid)).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+        let mut keys = service.keys(Request::new(proto::KeysRequest {})).await.unwrap().into_inner().keys;
+        keys.sort();
+        assert_eq!(keys, vec!["other", "user/one", "user/raw"]);
+        meta.get_mut("other").unwrap().expires_at = Some(1);
+        assert_eq!(service.keys(Request::new(proto::KeysRequest {})).await.unwrap().into_inner().keys.len(), 2);
+
+        let delete = Request::new(proto::DeleteRequest { key: "user/one".to_string() });
+        assert!(service.delete(delete).await.unwrap().into_inner().deleted);
+        assert!(!cache.contains_key("user/one"));
+
+        let event = events.next().await.unwrap().unwrap();
+        assert_eq!(event.key, "user/one");
+        assert!(event.value.is_some());
+        let event = events.next().await.unwrap().unwrap();
+        assert_eq!(event.key, "user/raw");
+        assert_eq!(event.value.unwrap().content_type, "image/png");
+        let event = events.next().await.unwrap().unwrap();
+        assert_eq!(event.key, "user/one");
+        assert!(event.value.is_none());
+
+        // writes from other nodes only go to the watchers, local writes stay in the replication
+        for i in 0..32 {
+            tx.send(transport::Message::Updated(format!("user/{}", i), Value::U64(i), versioned(1))).ok();
+        }
+        let replicated = std::iter::from_fn(|| rx.try_recv().ok()).count();
+        assert_eq!(replicated, 4);
+        assert_eq!(events.next().await.unwrap().unwrap().key, "user/0");
+    }
+
+    #[tokio::test]
+    async fn cluster_client() {
+        use client::{ClientError, ClusterClient, RetryPolicy};
+        use serde::{Deserialize, Serialize};
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct User {
+            name: String,
+            age: u8,
+        }
+
+        let cache: Db = Arc::new(Cache::new());
+        let (address, server) =
+            warp::serve(setup(cache.clone())).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        // nothing listens on the first node, the client moves on to the second one
+        let nodes = vec![
+            "http://127.0.0.1:1".parse().unwrap(),
+            format!("http://{}", address).parse().unwrap(),
+        ];
+        let retry = RetryPolicy { retries: 0, ..Default::default() };
+        let mut client = ClusterClient::new(nodes).unwrap().with_retry(retry);
+
+        let user = User { name: String::from("racher"), age: 3 };
+        client.set("user", &user).await.unwrap();
+        assert_eq!(client.node().port(), Some(address.port()));
+        assert_eq!(client.get::<User>("user").await.unwrap(), Some(user));
+        assert_eq!(client.get::<User>("missing").await.unwrap(), None);
+        client.set("nothing", &()).await.unwrap();
+        assert_eq!(client.get::<()>("nothing").await.unwrap(), Some(()));
+
+        let mut keys = client.keys().await.unwrap().keys;
+        keys.sort();
+        assert_eq!(keys, vec!["nothing", "user"]);
+        assert!(client.del("user").await.unwrap().deleted);
+        assert!(!client.del("user").await.unwrap().deleted);
+        assert!(client.purge().await.unwrap().purged);
+        assert!(cache.is_empty());
+
+        let mut strong = client.with_consistency(routes::Consistency::Strong);
+        match strong.set("user", &1).await {
+            Err(ClientError::Server(error)) => {
+                assert_eq!(error.code, responses::ErrorCode::ConsensusDisabled)
+            }
+            other => panic!("expected a server error, got {:?}", other),
+        }
+
+        // writes only go to the next node when they did not reach the first one
+        let unavailable = warp::any().map(|| warp::http::StatusCode::SERVICE_UNAVAILABLE);
+        let (unavailable, server) = warp::serve(unavailable).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let nodes = vec![format!("http://{}", unavailable).parse().unwrap(), format!("http://{}", address).parse().unwrap()];
+        let mut client = ClusterClient::new(nodes).unwrap().with_retry(retry);
+        assert!(client.set("once", &1).await.is_err());
+        assert!(!cache.contains_key("once"));
+        assert_eq!(client.get::<u8>("once").await.unwrap(), None);
+
+        assert!(matches!(ClusterClient::new(Vec::new()), Err(ClientError::NoNodes)));
+    }
+
+    #[tokio::test]
+    async fn node_client() {
+        let cache: Db = Arc::new(Cache::new());
+        let (address, server) = warp::serve(setup(cache.clone())).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let address: url::Url = format!("http://{}", address).parse().unwrap();
+        let mut client = client::Client::new();
+        let one = routes::Consistency::One;
+
+        client.set(address.clone(), "key", &Value::String("value".into()), one).await.unwrap();
+        let response = client.get(address.clone(), "key", one).await.unwrap();
+        assert_eq!(response.data, Value::String("value".into()));
+        let response = client.get(address.clone(), "missing", one).await.unwrap();
+        assert_eq!(response.data, Value::Unit);
+        assert_eq!(client.keys(address.clone()).await.unwrap().keys, vec!["key"]);
+        assert!(client.del(address.clone(), "key").await.unwrap().deleted);
+        assert!(!client.del(address.clone(), "key").await.unwrap().deleted);
+        assert!(client.keys(address).await.unwrap().keys.is_empty());
+    }
+
+    #[tokio::test]
+    async fn client_command() {
+        use arguments::{ClientArgs, SubArg};
+
+        let cache: Db = Arc::new(Cache::new());
+        let (address, server) = warp::serve(setup(cache.clone())).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let client_args = ClientArgs { address: format!("http://{}", address).parse().unwrap() };
+        let consistency = routes::Consistency::One;
+        let set = |key: &str, value: &str| SubArg::Set {
+            client_args: client_args.clone(),
+            key: key.to_string(),
+            value: Some(value.to_string()),
+            file: None,
+            string: false,
+            consistency,
+        };
+        let get = |key: &str| SubArg::Get { client_args: client_args.clone(), key: key.to_string(), consistency };
+        let del = |key: &str| SubArg::Del { client_args: client_args.clone(), key: key.to_string() };
+
+        assert!(cli::client_command(&set("user", r#"{"name":"racher"}"#)).await.unwrap());
+        assert!(cli::client_command(&get("user")).await.unwrap());
+        assert!(cli::client_command(&set("user", "not json")).await.is_err());
+
+        // a stored null exists, a missing key does not
+        assert!(cli::client_command(&set("nothing", "null")).await.unwrap());
+        assert_eq!(cache.get("nothing").unwrap().clone(), Value::Unit);
+        assert!(cli::client_command(&get("nothing")).await.unwrap());
+        assert!(!cli::client_command(&get("missing")).await.unwrap());
+
+        assert!(cli::client_command(&SubArg::Keys { client_args: client_args.clone() }).await.unwrap());
+        assert!(cli::client_command(&del("user")).await.unwrap());
+        assert!(!cli::client_command(&del("user")).await.unwrap());
+        assert!(cli::client_command(&SubArg::Ping { client_args: client_args.clone() }).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn shell() {
+        use rustyline::completion::Completer;
+        use rustyline::history::DefaultHistory;
+        use shell::{Shell, ShellHelper};
+
+        let cache: Db = Arc::new(Cache::new());
+        let (address, server) = warp::serve(setup(cache.clone())).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let node: url::Url = format!("http://{}", address).parse().unwrap();
+        let mut shell = Shell::new(vec![node.clone()]);
+
+        assert!(shell.execute("set user {\"name\": \"racher\"}").await.unwrap());
+        assert_eq!(cache.len(), 1);
+        assert!(shell.execute("get user").await.unwrap());
+        assert!(shell.execute("set user {").await.is_err());
+        assert!(shell.execute("fly away").await.is_err());
+        assert!(shell.execute("consistency quorum").await.unwrap());
+        assert_eq!(shell.consistency, routes::Consistency::Quorum);
+
+        // the keys are cached until a command changes them
+        assert_eq!(shell.keys().await, vec!["user"]);
+        cache.insert("other".into(), Value::Bool(true));
+        assert_eq!(shell.keys().await, vec!["user"]);
+        assert!(shell.execute("del user").await.unwrap());
+        assert_eq!(shell.keys().await, vec!["other"]);
+
+        // an unreachable node is asked once and gives no keys
+        assert!(shell.execute("use http://127.0.0.1:1").await.unwrap());
+        assert_eq!(shell.current, 1);
+        assert!(shell.keys().await.is_empty());
+        assert!(shell.execute("use 0").await.unwrap());
+        assert!(shell.execute("use 5").await.is_err());
+        assert!(!shell.execute("quit").await.unwrap());
+        assert!(!shell.execute("exit").await.unwrap());
+
+        let helper = ShellHelper { keys: vec!["user:1".into(), "user:2".into(), "other".into()], nodes: vec![node] };
+        let history = DefaultHistory::new();
+        let context = rustyline::Context::new(&history);
+        let complete = |line: &str| {
+            let (start, pairs) = helper.complete(line, line.len(), &context).unwrap();
+            (start, pairs.into_iter().map(|x| x.replacement).collect::<Vec<_>>())
+        };
+        assert_eq!(complete("q"), (0, vec![String::from("quit")]));
+        assert_eq!(complete("get us"), (4, vec![String::from("user:1"), String::from("user:2")]));
+        assert_eq!(complete("consistency q"), (12, vec![String::from("quorum")]));
+        assert_eq!(complete("use http"), (4, vec![format!("http://{}/", address)]));
+        assert_eq!(complete("keys x"), (5, Vec::new()));
+    }
+
+    #[tokio::test]
+    async fn client_retries_and_breaker() {
+        use client::{Client, ClientError, RetryPolicy};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::time::Duration;
+
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = hits.clone();
+        let failing = warp::any().map(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            warp::http::StatusCode::INTERNAL_SERVER_ERROR
+        });
+        let (address, server) = warp::serve(failing).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        // a node that misses a quorum is still healthy
+        let unavailable = warp::any().map(|| warp::http::StatusCode::SERVICE_UNAVAILABLE);
+        let (unavailable, server) = warp::serve(unavailable).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let retry = RetryPolicy {
+            retries: 2,
+            initial_backoff: Duration::from_millis(1),
+            ..Default::default()
+        };
+        let mut client = Client::with_retry(retry);
+        let http = client.client.clone();
+        let url = format!("http://{}", address);
+        let get = |path: &str| http.post(format!("{}{}", url, path)).build().unwrap();
+
+        // reads are tried again, writes without a version and joins are not
+        let response = client.call(get("/get/key")).await.unwrap();
+        assert_eq!(response.status(), 500);
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+        let response = client.call(get("/set/key")).await.unwrap();
+        assert_eq!(response.status(), 500);
+        assert_eq!(hits.load(Ordering::SeqCst), 4);
+        let response = client.call(http.put(format!("{}/keys/key", url)).build().unwrap()).await.unwrap();
+        assert_eq!(response.status(), 500);
+        assert_eq!(hits.load(Ordering::SeqCst), 5);
+        let other = format!("http://{}/_internal/join", unavailable);
+        for _ in 0..10 {
+            let response = client.call(http.post(&other).build().unwrap()).await.unwrap();
+            assert_eq!(response.status(), 503);
+        }
+
+        // the fifth failure opened the circuit, after that the node is skipped
+        let result = client.call(get("/get/key")).await;
+        assert!(matches!(result, Err(ClientError::CircuitOpen(_))));
+        assert_eq!(hits.load(Ordering::SeqCst), 5);
+        let result = client.call(get("/keys")).await;
+        assert!(matches!(result, Err(ClientError::CircuitOpen(_))));
+        assert_eq!(hits.load(Ordering::SeqCst), 5);
+        // other clients have their own breakers
+        let response = Client::with_retry(retry).call(get("/keys")).await.unwrap();
+        assert_eq!(response.status(), 500);
+        assert_eq!(hits.load(Ordering::SeqCst), 8);
+    }
+
+    #[tokio::test]
+    async fn client_broadcast() {
+        use client::{BroadcastPolicy, Client, ClientError};
+        use std::collections::HashSet;
+        use std::time::{Duration, Instant};
+
+        let cache: Db = Arc::new(Cache::new());
+        let (healthy, server) = warp::serve(setup(cache)).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        let hanging = warp::any().and_then(|| async {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            Ok::<_, std::convert::Infallible>("pong")
+        });
+        let (hanging, server) = warp::serve(hanging).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let healthy: url::Url = format!("http://{}", healthy).parse().unwrap();
+        let hanging: url::Url = format!("http://{}", hanging).parse().unwrap();
+        let addresses: HashSet<_> = vec![healthy.clone(), hanging.clone()].into_iter().collect();
+        let broadcast = BroadcastPolicy { concurrency: 2, deadline: Duration::from_millis(300) };
+        let mut client = Client::new().with_broadcast(broadcast);
+
+        let started = Instant::now();
+        let results = client.ping_all(addresses).await;
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(results.len(), 2);
+        for (address, result) in results {
+            if address == healthy {
+                assert!(result.unwrap().pong);
+            } else {
+                assert!(matches!(result, Err(ClientError::Timeout(x)) if x == hanging));
+            }
+        }
+
+        // updates and reads of single keys give up on their own
+        let mut client = Client::with_retry(client::RetryPolicy { retries: 0, ..Default::default() });
+        let started = Instant::now();
+        assert!(client.internal_get(hanging.clone(), "key").await.is_err());
+        assert!(client.internal_update(hanging, "key", &Value::Unit, &versioned(1)).await.is_err());
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+
+    #[tokio::test]
+    async fn rate_limit() {
+        use rate_limit::{RateLimitLayer, RateLimiter};
+        use tower::{Layer, ServiceExt};
+        use warp::hyper::{Body, Request};
+
+        let prefixes = vec!["sessions:=1".parse().unwrap(), "users:=1".parse().unwrap(), "users:admin:=2".parse().unwrap()];
+        let api_keys = vec!["service-a", "service-b", "service-c"].into_iter().map(String::from).collect();
+        let limiter = RateLimiter::new(2, 2, prefixes).with_api_keys(api_keys).to_arc();
+        let peer = "127.0.0.1:50000".parse().unwrap();
+        let cache: Db = Arc::new(Cache::new());
+        let service = RateLimitLayer::new(peer, Some(limiter)).layer(warp::service(setup(cache)));
+        let request = |path: &str, api_key: Option<&str>| {
+            let mut request = Request::post(path);
+            if let Some(api_key) = api_key {
+                request = request.header(rate_limit::API_KEY_HEADER, api_key);
+            }
+            service.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+
+        assert_eq!(request("/ping", None).await.unwrap().status(), 200);
+        assert_eq!(request("/ping", None).await.unwrap().status(), 200);
+        let response = request("/ping", None).await.unwrap();
+        assert_eq!(response.status(), 429);
+        assert_eq!(response.headers()["retry-after"], "1");
+        let body = warp::hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let error: responses::ErrorResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(error.code, responses::ErrorCode::TooManyRequests);
+
+        // known api keys have their own bucket, unknown ones and internal routes count for the ip
+        assert_eq!(request("/ping", Some("service-a")).await.unwrap().status(), 200);
+        assert_eq!(request("/ping", Some("made-up")).await.unwrap().status(), 429);
+        assert_eq!(request("/_internal/info", None).await.unwrap().status(), 429);
+
+        // a prefix is limited on its own, other keys use what is left of the client limit
+        assert_eq!(request("/get/sessions:1", Some("service-b")).await.unwrap().status(), 200);
+        assert_eq!(request("/get/sessions:2", Some("service-b")).await.unwrap().status(), 429);
+        assert_eq!(request("/get/other", Some("service-b")).await.unwrap().status(), 200);
+        assert_eq!(request("/get/other", Some("service-b")).await.unwrap().status(), 429);
+
+        // the longest matching prefix wins
+        assert_eq!(request("/get/users:admin:1", Some("service-c")).await.unwrap().status(), 200);
+        assert_eq!(request("/get/users:admin:2", Some("service-c")).await.unwrap().status(), 200);
+
+        // neighbours are found by their ip or their name, again once the neighbours changed
+        let cfg = config::RuntimeConfig::default().to_arc();
+        let limiter = RateLimiter::new(1, 1, Vec::new()).to_arc();
+        cfg.write().await.neighbours.insert("http://localhost:9226".parse().unwrap());
+        assert!(limiter.is_neighbour(&cfg, "127.0.0.1".parse().unwrap()).await);
+        assert!(!limiter.is_neighbour(&cfg, "10.1.2.3".parse().unwrap()).await);
+        cfg.write().await.neighbours.clear();
+        assert!(!limiter.is_neighbour(&cfg, "127.0.0.1".parse().unwrap()).await);
+
+        // only neighbours that send the cluster secret are left out
+        let cache: Db = Arc::new(Cache::new());
+        let service = RateLimitLayer::new(peer, Some(limiter)).with_secret(Some(String::from("s3cret"))).layer(warp::service(setup(cache)));
+        let request = |secret: &str| {
+            let request = Request::post("/ping").header(rate_limit::SECRET_HEADER, secret);
+            service.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+        for _ in 0..3 {
+            assert_eq!(request("s3cret").await.unwrap().status(), 200);
+        }
+        assert_eq!(request("guessed").await.unwrap().status(), 200);
+        assert_eq!(request("guessed").await.unwrap().status(), 429);
+    }
+
+    #[tokio::test]
+    async fn access_log() {
+        use std::io;
+        use std::sync::Mutex;
+        use tower::{Layer, ServiceExt};
+        use warp::hyper::{Body, Request};
+
+        #[derive(Clone, Default)]
+        struct Logs(std::sync::Arc<Mutex<Vec<u8>>>);
+        impl io::Write for Logs {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let logs = Logs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let slow_log = access_log::SlowLog::new(Duration::from_millis(0), 1).to_arc();
+        let peer = "127.0.0.1:50000".parse().unwrap();
+        let cache: Db = Arc::new(Cache::new());
+        cache.insert(String::from("testing"), Value::Bool(true));
+        let layer = access_log::AccessLogLayer::new(peer, slow_log.clone());
+        let service = layer.layer(warp::service(setup(cache)));
+        let request = |path: &str| service.clone().oneshot(Request::post(path).body(Body::empty()).unwrap());
+
+        assert_eq!(request("/get/testing").await.unwrap().status(), 200);
+        assert_eq!(request("/unknown").await.unwrap().status(), 404);
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = logs.lines().filter(|x| x.contains(" access: ")).collect();
+        assert_eq!(lines.len(), 2);
+        for (line, (path, status)) in lines.iter().zip([("/get/testing", 200), ("/unknown", 404)].iter()) {
+            assert!(line.contains("method=POST"), "{}", line);
+            assert!(line.contains(&format!("path={}", path)), "{}", line);
+            assert!(line.contains(&format!("status={}", status)), "{}", line);
+            assert!(line.contains("peer=127.0.0.1:50000"), "{}", line);
+            assert!(line.contains("latency_us="), "{}", line);
+        }
+
+        // the slow log keeps as many requests as it has room for, the latest one here
+        let entries = slow_log.lock().unwrap().slowest();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].method, "POST");
+        assert_eq!(entries[0].path, "/unknown");
+        assert_eq!(entries[0].status, 404);
+        assert_eq!(entries[0].peer, peer);
+    }
+
+    #[tokio::test]
+    async fn trace_propagation() {
+        use opentelemetry::trace::{TraceContextExt, TraceId};
+        use std::convert::Infallible;
+        use std::sync::Mutex;
+        use tracing_opentelemetry::OpenTelemetrySpanExt;
+        use tracing_subscriber::layer::SubscriberExt;
+        use warp::hyper::server::conn::AddrStream;
+        use warp::hyper::service::make_service_fn;
+
+        let tracer = telemetry::tracer(None).unwrap();
+        let subscriber = tracing_subscriber::Registry::default().with(tracing_opentelemetry::layer().with_tracer(tracer));
+        let _guard = tracing::subscriber::set_default(subscriber);
+        let trace_id = |span: &tracing::Span| span.context().span().span_context().trace_id();
+
+        // the receiving node notes the trace its requests are part of
+        let received = std::sync::Arc::new(Mutex::new(Vec::new()));
+        let noted = received.clone();
+        let note = warp::any()
+            .map(move || noted.lock().unwrap().push(trace_id(&tracing::Span::current())))
+            .untuple_one();
+        let cache: Db = Arc::new(Cache::new());
+        let service = warp::service(note.and(setup(cache.clone())));
+        let slow_log = access_log::SlowLog::default().to_arc();
+        let make_service = make_service_fn(move |stream: &AddrStream| {
+            let layer = access_log::AccessLogLayer::new(stream.remote_addr(), slow_log.clone());
+            let service = tower::Layer::layer(&layer, service.clone());
+            async move { Ok::<_, Infallible>(service) }
+        });
+        let server = warp::hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let address: url::Url = format!("http://{}", server.local_addr()).parse().unwrap();
+        tokio::spawn(server);
+
+        let span = tracing::info_span!(target: telemetry::TARGET, "replicate");
+        let sent = trace_id(&span);
+        assert_ne!(sent, TraceId::invalid());
+        let mut client = client::Client::new();
+        let (value, metadata) = (Value::Bool(true), versioned(1));
+        let update = client.internal_update(address, "traced", &value, &metadata);
+        tracing::Instrument::instrument(update, span).await.unwrap();
+
+        assert_eq!(*received.lock().unwrap(), vec![sent]);
+        assert_eq!(*cache.get("traced").unwrap(), Value::Bool(true));
+    }
 }
@@ -1,15 +1,18 @@
 #![warn(rust_2018_idioms)]
 
 use libracher;
-use libracher::arguments::{Args, SubArg};
+use libracher::arguments::{Args, ConfigCmd, SubArg};
+use libracher::cache::Cache;
 use libracher::cli;
 use libracher::config::RuntimeConfigArc;
 use libracher::sync;
+use libracher::telemetry;
 use libracher::transport;
 
 use std::error::Error;
 use sync::Arc;
 
+use dashmap::DashMap;
 use structopt::StructOpt;
 use tokio::{signal, task};
 use tracing::{debug, error};
@@ -18,31 +21,75 @@ async fn inner_loop(args: &Args, config: RuntimeConfigArc) -> Result<(), Box<dyn
     // if args.backup_args.backup_remove {
     //     return Ok(cli::remove_backups(config.clone()).await?);
     // };
+    let meta = Arc::new(DashMap::new());
     let arc_cache = match args.sub_cmd.as_ref() {
         Some(SubArg::RemoveBackup { backup_dir }) => {
             return Ok(cli::remove_backups(&backup_dir.path).await?);
         }
-        Some(SubArg::Join { join_address, .. }) => {
-            cli::join_cache(join_address.clone(), config.clone()).await?
+        Some(SubArg::Join(join)) => {
+            cli::join_cache(join.join_address.clone(), config.clone(), meta.clone()).await?
         }
-        None => {
-            let cache = cli::load_from_backup(config.clone()).await?;
-            Arc::new(cache)
+        _ => {
+            cli::load_cluster_state(config.clone()).await?;
+            let cache = cli::load_from_backup(config.clone(), meta.clone()).await?;
+            Arc::new(Cache::from(cache))
         }
     };
 
+    let raft = cli::load_raft(config.clone()).await?;
+
     let (tx, rx1) = transport::channel(16);
 
     let config_clone = config.clone();
     let cache_clone = arc_cache.clone();
-    let http_server = task::spawn(async { cli::http_server(config_clone, cache_clone, tx) });
+    let meta_clone = meta.clone();
+    let tx_clone = tx.clone();
+    let raft_clone = raft.clone();
+    let http_server = task::spawn(async {
+        cli::http_server(config_clone, cache_clone, meta_clone, tx_clone, raft_clone)
+    });
+    let config_clone = config.clone();
+    let cache_clone = arc_cache.clone();
+    let meta_clone = meta.clone();
+    let sync_to_fs = task::spawn(async { cli::sync_to_fs(config_clone, cache_clone, meta_clone) });
     let config_clone = config.clone();
     let cache_clone = arc_cache.clone();
-    let sync_to_fs = task::spawn(async { cli::sync_to_fs(config_clone, cache_clone) });
+    let meta_clone = meta.clone();
+    let tx_clone = tx.clone();
+    let resp_server =
+        task::spawn(async { cli::resp_server(config_clone, cache_clone, meta_clone, tx_clone) });
+    let config_clone = config.clone();
+    let cache_clone = arc_cache.clone();
+    let meta_clone = meta.clone();
+    let tx_clone = tx.clone();
+    let memcache_server = task::spawn(async {
+        cli::memcache_server(config_clone, cache_clone, meta_clone, tx_clone)
+    });
+    let config_clone = config.clone();
+    let cache_clone = arc_cache.clone();
+    let meta_clone = meta.clone();
+    let raft_clone = raft.clone();
+    let grpc_server = task::spawn(async {
+        cli::grpc_server(config_clone, cache_clone, meta_clone, tx, raft_clone)
+    });
+    let cache_clone = arc_cache.clone();
+    let meta_clone = meta.clone();
+    let expire_keys = task::spawn(async { cli::expire_keys(cache_clone, meta_clone) });
     let config_clone = config.clone();
     let server_sender = task::spawn(async { cli::server_sender(config_clone, rx1) });
     let config_clone = config.clone();
-    let sync_neighbours = task::spawn(async { cli::sync_neighbours(config_clone) });
+    let cache_clone = arc_cache.clone();
+    let meta_clone = meta.clone();
+    let sync_neighbours =
+        task::spawn(async { cli::sync_neighbours(config_clone, cache_clone, meta_clone) });
+    let config_clone = config.clone();
+    let cache_clone = arc_cache.clone();
+    let meta_clone = meta.clone();
+    let consensus =
+        task::spawn(async { cli::consensus(config_clone, raft, cache_clone, meta_clone) });
+    let config_clone = config.clone();
+    let args_clone = args.clone();
+    let reload_config = task::spawn(async { cli::reload_config(config_clone, args_clone) });
 
     tokio::select!(
         Ok(()) = signal::ctrl_c() => {},
@@ -50,6 +97,12 @@ async fn inner_loop(args: &Args, config: RuntimeConfigArc) -> Result<(), Box<dyn
         Ok(()) = sync_to_fs.await? => {},
         Ok(()) = server_sender.await? => {},
         Ok(()) = sync_neighbours.await? => {},
+        Ok(()) = consensus.await? => {},
+        Ok(()) = reload_config.await? => {},
+        Ok(()) = resp_server.await? => {},
+        Ok(()) = memcache_server.await? => {},
+        Ok(()) = grpc_server.await? => {},
+        Ok(()) = expire_keys.await? => {},
     );
 
     Ok(())
@@ -73,7 +126,34 @@ async fn main() -> Result<(), Box<dyn Error>> {
         Err(e) => return Ok(println!("{}", e)),
     };
 
+    if let Some(SubArg::Config(ConfigCmd::Check { path })) = args.sub_cmd.as_ref() {
+        if let Err(e) = cli::check_config(path.as_ref().or_else(|| args.config.as_ref())) {
+            eprintln!("error: {}", e);
+            std::process::exit(2);
+        }
+        return Ok(());
+    }
+    let args = match args.with_config_file() {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            std::process::exit(2);
+        }
+    };
+
     args.set_logger();
+
+    // client commands should not be restarted by the main loop on errors
+    if let Some(sub_cmd) = args.sub_cmd.as_ref().filter(|x| x.is_client()) {
+        match cli::client_command(sub_cmd).await {
+            Ok(true) => return Ok(()),
+            Ok(false) => std::process::exit(1),
+            Err(e) => {
+                eprintln!("error: {}", e);
+                std::process::exit(2);
+            }
+        }
+    }
     // println!("{:?}", args);
 
     let config = args.as_runtime_config();
@@ -81,6 +161,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
     // let config = args.as_runtime_config().to_arc();
     let config = config.to_arc();
     main_loop(&args, config).await?;
+    telemetry::shutdown();
 
     debug!("Exiting");
 
@@ -0,0 +1,437 @@
+use crate::cache;
+use crate::encoding;
+use crate::metadata::{self, Metadata};
+use crate::transport;
+use crate::{Db, MetaDb, MAX_FILE_SIZE};
+
+use std::convert::TryFrom;
+use std::net::SocketAddr;
+
+use dashmap::mapref::entry::Entry;
+use serde_value::Value;
+use tokio::io::{
+    self, AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+    BufReader,
+};
+use tokio::net::TcpListener;
+use tracing::{debug, info, warn};
+
+/// keys are at most 250 bytes, so command lines stay short
+const MAX_LINE: u64 = 2048;
+const MAX_KEY_LENGTH: usize = 250;
+/// expiration times up to 30 days are relative, larger ones are unix timestamps
+const MAX_RELATIVE_EXPTIME: i64 = 60 * 60 * 24 * 30;
+
+const BAD_FORMAT: &str = "CLIENT_ERROR bad command line format";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    Set,
+    Add,
+    Replace,
+    /// only stores when the version still matches
+    Cas(u64),
+}
+
+#[derive(Debug, PartialEq)]
+enum Command<'a> {
+    Get {
+        keys: Vec<&'a str>,
+        cas: bool,
+    },
+    Store {
+        mode: Mode,
+        key: &'a str,
+        flags: u32,
+        exptime: i64,
+        bytes: usize,
+    },
+    Delete {
+        key: &'a str,
+    },
+    Change {
+        key: &'a str,
+        delta: u64,
+        increment: bool,
+    },
+    Flush {
+        delay: i64,
+    },
+    Version,
+    Quit,
+}
+
+/// microseconds since the epoch, `BAD_FORMAT` if the time does not fit
+fn micros(seconds: i64, relative: bool) -> Result<u64, &'static str> {
+    (seconds as u64)
+        .checked_mul(1_000_000)
+        .and_then(|x| x.checked_add(if relative { metadata::now() } else { 0 }))
+        .ok_or(BAD_FORMAT)
+}
+
+fn expires_at(exptime: i64) -> Result<Option<u64>, &'static str> {
+    match exptime {
+        0 => Ok(None),
+        // negative times expire the item right away
+        x if x < 0 => Ok(Some(0)),
+        x => micros(x, x <= MAX_RELATIVE_EXPTIME).map(Some),
+    }
+}
+
+fn key(key: &str) -> Result<&str, &'static str> {
+    if key.len() > MAX_KEY_LENGTH {
+        return Err(BAD_FORMAT);
+    }
+    Ok(key)
+}
+
+fn number<T: std::str::FromStr>(value: &str) -> Result<T, &'static str> {
+    value.parse().map_err(|_| BAD_FORMAT)
+}
+
+/// the command and whether the client asked for no reply
+fn parse(line: &str) -> Result<(Command<'_>, bool), &'static str> {
+    let mut args: Vec<&str> = line.split_ascii_whitespace().collect();
+    if args.is_empty() {
+        return Err("ERROR");
+    }
+    let name = args.remove(0);
+    let noreply = name != "get" && name != "gets" && args.last() == Some(&"noreply");
+    if noreply {
+        args.pop();
+    }
+
+    let command = match (name, args.as_slice()) {
+        ("get", keys) | ("gets", keys) if !keys.is_empty() => Command::Get {
+            keys: keys.iter().map(|x| key(x)).collect::<Result<_, _>>()?,
+            cas: name == "gets",
+        },
+        ("set", [k, flags, exptime, bytes])
+        | ("add", [k, flags, exptime, bytes])
+        | ("replace", [k, flags, exptime, bytes]) => Command::Store {
+            mode: match name {
+                "set" => Mode::Set,
+                "add" => Mode::Add,
+                _ => Mode::Replace,
+            },
+            key: key(k)?,
+            flags: number(flags)?,
+            exptime: number(exptime)?,
+            bytes: number(bytes)?,
+        },
+        ("cas", [k, flags, exptime, bytes, unique]) => Command::Store {
+            mode: Mode::Cas(number(unique)?),
+            key: key(k)?,
+            flags: number(flags)?,
+            exptime: number(exptime)?,
+            bytes: number(bytes)?,
+        },
+        ("delete", [k]) => Command::Delete { key: key(k)? },
+        ("incr", [k, delta]) | ("decr", [k, delta]) => Command::Change {
+            key: key(k)?,
+            delta: delta
+                .parse()
+                .map_err(|_| "CLIENT_ERROR invalid numeric delta argument")?,
+            increment: name == "incr",
+        },
+        ("flush_all", []) => Command::Flush { delay: 0 },
+        ("flush_all", [delay]) => Command::Flush {
+            delay: number(delay)?,
+        },
+        ("version", []) => Command::Version,
+        ("quit", []) => Command::Quit,
+        // unknown commands and known ones with the wrong amount of arguments
+        _ => return Err("ERROR"),
+    };
+    Ok((command, noreply))
+}
+
+/// a line without the `\r\n`, `None` when the connection was closed
+async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    let read = reader.take(MAX_LINE).read_until(b'\n', &mut line).await?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with(b"\n") {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "line too long or incomplete",
+        ));
+    }
+    line.pop();
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+/// state of one client connection
+struct Connection {
+    cache: Db,
+    meta: MetaDb,
+    tx: transport::Sender,
+}
+
+impl Connection {
+    fn get(&self, keys: &[&str], cas: bool) -> Vec<u8> {
+        let mut response = Vec::new();
+        for key in keys {
+            if metadata::expired(&self.meta, key) {
+                continue;
+            }
+            let value = match self.cache.get(*key) {
+                Some(x) => encoding::to_plain(x.value()),
+                None => continue,
+            };
+            let metadata = self.meta.get(*key).map(|x| x.clone()).unwrap_or_default();
+            let flags = metadata.flags.unwrap_or(0);
+            let header = if cas {
+                format!(
+                    "VALUE {} {} {} {}\r\n",
+                    key,
+                    flags,
+                    value.len(),
+                    metadata.version
+                )
+            } else {
+                format!("VALUE {} {} {}\r\n", key, flags, value.len())
+            };
+            response.extend_from_slice(header.as_bytes());
+            response.extend_from_slice(&value);
+            response.extend_from_slice(b"\r\n");
+        }
+        response.extend_from_slice(b"END\r\n");
+        response
+    }
+
+    fn store(
+        &self,
+        mode: Mode,
+        key: String,
+        flags: u32,
+        exptime: i64,
+        data: Vec<u8>,
+    ) -> &'static str {
+        let expires_at = match expires_at(exptime) {
+            Ok(x) => x,
+            Err(e) => return e,
+        };
+        let (value, content_type) = encoding::from_plain(data);
+
+        // the entries stay locked, so no other write gets between the check and the write.
+        // metadata before the value, like `metadata::apply`
+        let meta_entry = self.meta.entry(key.clone());
+        let (expired, version) = match &meta_entry {
+            Entry::Occupied(x) => (x.get().is_expired(), x.get().version),
+            Entry::Vacant(_) => (false, 0),
+        };
+        let entry = self.cache.entry(key.clone());
+        let exists = matches!(entry, Entry::Occupied(_)) && !expired;
+        match mode {
+            Mode::Add if exists => return "NOT_STORED",
+            Mode::Replace if !exists => return "NOT_STORED",
+            Mode::Cas(_) if !exists => return "NOT_FOUND",
+            Mode::Cas(unique) if version != unique => return "EXISTS",
+            _ => {}
+        }
+        let metadata = Metadata {
+            version: metadata::new_version(),
+            content_type,
+            expires_at,
+            flags: Some(flags).filter(|x| *x != 0),
+            ..Default::default()
+        };
+        *meta_entry.or_default() = metadata.clone();
+        let before = match entry {
+            Entry::Occupied(mut entry) => cache::size(&key, &entry.insert(value.clone())),
+            Entry::Vacant(entry) => {
+                entry.insert(value.clone());
+                0
+            }
+        };
+        self.cache.resized(before, cache::size(&key, &value));
+        metadata::send(&self.tx, key, value, metadata);
+        "STORED"
+    }
+
+    fn change(&self, key: String, delta: u64, increment: bool) -> String {
+        // the entries stay locked, so concurrent changes are not lost and the newest value
+        // gets the newest version. metadata before the value, like `metadata::apply`
+        let meta_entry = self.meta.entry(key.clone());
+        let expired = matches!(&meta_entry, Entry::Occupied(x) if x.get().is_expired());
+        let (value, metadata) = match self.cache.get_mut(&key) {
+            Some(mut entry) if !expired => {
+                let mut meta_entry = meta_entry.or_default();
+                let current =
+                    encoding::plain_integer(entry.value()).and_then(|x| u64::try_from(x).ok());
+                let current = match current {
+                    Some(x) => x,
+                    None => {
+                        return String::from(
+                            "CLIENT_ERROR cannot increment or decrement non-numeric value",
+                        )
+                    }
+                };
+                // increments wrap around, decrements stop at 0
+                let next = if increment {
+                    current.wrapping_add(delta)
+                } else {
+                    current.saturating_sub(delta)
+                };
+                let before = cache::size(&key, entry.value());
+                *entry = Value::U64(next);
+                self.cache.resized(before, cache::size(&key, entry.value()));
+                // flags and expiry stay the same
+                meta_entry.version = metadata::new_version();
+                meta_entry.content_type = None;
+                (next, meta_entry.clone())
+            }
+            _ => return String::from("NOT_FOUND"),
+        };
+        metadata::send(&self.tx, key, Value::U64(value), metadata);
+        value.to_string()
+    }
+
+    fn flush(&self, delay: i64) -> &'static str {
+        if delay <= 0 {
+            metadata::purge(&self.cache, &self.meta);
+            return "OK";
+        }
+        // everything expires once the delay passed
+        let expires_at = match micros(delay, true) {
+            Ok(x) => x,
+            Err(e) => return e,
+        };
+        for mut item in self.meta.iter_mut() {
+            if item.expires_at.is_none_or(|x| x > expires_at) {
+                item.expires_at = Some(expires_at);
+            }
+        }
+        "OK"
+    }
+}
+
+/// answers the commands of one client until it disconnects or sends `quit`
+pub async fn handle<S>(stream: S, cache: Db, meta: MetaDb, tx: transport::Sender) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (reader, mut writer) = io::split(stream);
+    let mut reader = BufReader::new(reader);
+    let connection = Connection { cache, meta, tx };
+
+    loop {
+        let line = match read_line(&mut reader).await {
+            Ok(Some(line)) => line,
+            Ok(None) => return Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                writer.write_all(b"CLIENT_ERROR line too long\r\n").await?;
+                return Err(e);
+            }
+            Err(e) => return Err(e),
+        };
+        let line = match std::str::from_utf8(&line) {
+            Ok(line) => line,
+            Err(_) => {
+                writer
+                    .write_all(b"CLIENT_ERROR keys have to be valid utf-8\r\n")
+                    .await?;
+                continue;
+            }
+        };
+        let (command, noreply) = match parse(line) {
+            Ok(x) => x,
+            Err(error) => {
+                writer
+                    .write_all(format!("{}\r\n", error).as_bytes())
+                    .await?;
+                continue;
+            }
+        };
+
+        let response = match command {
+            Command::Get { keys, cas } => connection.get(&keys, cas),
+            Command::Store {
+                mode,
+                key,
+                flags,
+                exptime,
+                bytes,
+            } => {
+                // the data block follows the command line and ends with `\r\n` as well
+                if bytes as u64 > MAX_FILE_SIZE {
+                    let mut data = (&mut reader).take(bytes as u64 + 2);
+                    io::copy(&mut data, &mut io::sink()).await?;
+                    writer
+                        .write_all(b"SERVER_ERROR object too large for cache\r\n")
+                        .await?;
+                    continue;
+                }
+                let mut data = vec![0; bytes + 2];
+                reader.read_exact(&mut data).await?;
+                if !data.ends_with(b"\r\n") {
+                    writer.write_all(b"CLIENT_ERROR bad data chunk\r\n").await?;
+                    continue;
+                }
+                data.truncate(bytes);
+                let result = connection.store(mode, key.to_string(), flags, exptime, data);
+                format!("{}\r\n", result).into_bytes()
+            }
+            Command::Delete { key } => {
+                let deleted = metadata::remove(
+                    &connection.cache,
+                    &connection.meta,
+                    &connection.tx,
+                    key.to_string(),
+                );
+                if deleted {
+                    b"DELETED\r\n".to_vec()
+                } else {
+                    b"NOT_FOUND\r\n".to_vec()
+                }
+            }
+            Command::Change {
+                key,
+                delta,
+                increment,
+            } => format!(
+                "{}\r\n",
+                connection.change(key.to_string(), delta, increment)
+            )
+            .into_bytes(),
+            Command::Flush { delay } => format!("{}\r\n", connection.flush(delay)).into_bytes(),
+            Command::Version => format!("VERSION {}\r\n", env!("CARGO_PKG_VERSION")).into_bytes(),
+            Command::Quit => return Ok(()),
+        };
+        if !noreply {
+            writer.write_all(&response).await?;
+        }
+    }
+}
+
+/// accepts memcached clients on the address, they share the cache and replication with the http api
+pub async fn serve(
+    address: SocketAddr,
+    cache: Db,
+    meta: MetaDb,
+    tx: transport::Sender,
+) -> io::Result<()> {
+    let listener = TcpListener::bind(address).await?;
+    info!("memcache address: {}", listener.local_addr()?);
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(x) => x,
+            Err(e) => {
+                warn!(%e, "could not accept a memcache connection");
+                continue;
+            }
+        };
+        let (cache, meta, tx) = (cache.clone(), meta.clone(), tx.clone());
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream, cache, meta, tx).await {
+                debug!(%e, %peer, "memcache connection closed");
+            }
+        });
+    }
+}
@@ -0,0 +1,212 @@
+use crate::telemetry;
+use crate::transport::{self, Message};
+use crate::{Db, MetaDb};
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use dashmap::mapref::entry::Entry;
+use serde::{Deserialize, Serialize};
+use serde_value::Value;
+
+static LAST_VERSION: AtomicU64 = AtomicU64::new(0);
+/// microseconds the metadata of deleted and expired keys is kept. a node that was down
+/// for longer syncs all keys instead of only the changes
+pub const TOMBSTONE_TTL: u64 = 24 * 60 * 60 * 1_000_000;
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Metadata {
+    /// microseconds since the unix epoch of the last write, the newest write wins
+    pub version: u64,
+    /// set for raw values, they are returned with this content type
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    /// microseconds since the unix epoch after which the key is removed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    /// opaque flags of values set through memcached
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flags: Option<u32>,
+    /// set for deleted keys, unlike a stored null
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub deleted: bool,
+}
+
+impl Metadata {
+    pub fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|x| x <= now())
+    }
+}
+
+/// microseconds since the unix epoch
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_micros() as u64)
+        .unwrap_or(0)
+}
+
+/// returns the current time in microseconds, always larger than the previous call
+pub fn new_version() -> u64 {
+    let now = now();
+    let mut last = LAST_VERSION.load(Ordering::Relaxed);
+    loop {
+        let next = now.max(last + 1);
+        match LAST_VERSION.compare_exchange(last, next, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => return next,
+            Err(x) => last = x,
+        }
+    }
+}
+
+/// version of the key, 0 if it was never written
+pub fn version_of(meta: &MetaDb, key: &str) -> u64 {
+    meta.get(key).map(|x| x.version).unwrap_or(0)
+}
+
+/// newest version in the cache, 0 if it is empty
+pub fn latest_version(meta: &MetaDb) -> u64 {
+    meta.iter().map(|x| x.version).max().unwrap_or(0)
+}
+
+/// stores the value if it is newer than what is in the cache, metadata marked as deleted
+/// deletes the key. the metadata of deleted keys is kept, so an older write cannot bring them back.
+pub fn apply(cache: &Db, meta: &MetaDb, key: String, value: Value, metadata: Metadata) -> bool {
+    // the entry stays locked until the value is written, so a newer write that arrives
+    // at the same time cannot be overwritten
+    let entry = match meta.entry(key.clone()) {
+        Entry::Occupied(entry) if entry.get().version > metadata.version => return false,
+        entry => entry,
+    };
+
+    if metadata.deleted {
+        cache.remove(&key);
+    } else {
+        cache.insert(key, value);
+    }
+    match entry {
+        Entry::Occupied(mut entry) => {
+            entry.insert(metadata);
+        }
+        Entry::Vacant(entry) => {
+            entry.insert(metadata);
+        }
+    }
+    true
+}
+
+/// the value and metadata of a key as other nodes see it, keys without a value are deleted
+pub fn lookup(cache: &Db, meta: &MetaDb, key: &str) -> (Value, Metadata) {
+    let mut metadata = meta.get(key).map(|x| x.clone()).unwrap_or_default();
+    let value = cache.get(key).map(|x| x.value().clone());
+    metadata.deleted = value.is_none();
+    (value.unwrap_or(Value::Unit), metadata)
+}
+
+/// true if the key has an expiry that passed, the key may not be removed yet
+pub fn expired(meta: &MetaDb, key: &str) -> bool {
+    meta.get(key).is_some_and(|x| x.is_expired())
+}
+
+/// the value and metadata of a key that is set and not expired. the metadata is read
+/// before the cache like everywhere else, a lock on both at once is always taken in that order
+pub fn get(cache: &Db, meta: &MetaDb, key: &str) -> Option<(Value, Metadata)> {
+    let metadata = meta.get(key).map(|x| x.clone()).unwrap_or_default();
+    if metadata.is_expired() {
+        return None;
+    }
+    cache.get(key).map(|x| (x.value().clone(), metadata))
+}
+
+/// the keys that are set and not expired. the cache is released before the metadata is read
+pub fn live_keys(cache: &Db, meta: &MetaDb) -> Vec<String> {
+    let keys: Vec<String> = cache.iter().map(|x| x.key().clone()).collect();
+    keys.into_iter().filter(|key| !expired(meta, key)).collect()
+}
+
+/// removes the values of expired keys, their metadata is kept like the one of deleted keys.
+/// every node expires keys by itself, so nothing is replicated
+pub fn remove_expired(cache: &Db, meta: &MetaDb) -> usize {
+    let expired: Vec<String> = meta
+        .iter()
+        .filter(|x| x.is_expired())
+        .map(|x| x.key().clone())
+        .collect();
+    expired
+        .into_iter()
+        .filter(|key| cache.remove(key).is_some())
+        .count()
+}
+
+/// forgets the metadata of keys that were deleted or expired longer than `TOMBSTONE_TTL` ago
+pub fn remove_tombstones(cache: &Db, meta: &MetaDb) -> usize {
+    let before = meta.len();
+    let oldest = now().saturating_sub(TOMBSTONE_TTL);
+    meta.retain(|key, metadata| metadata.version > oldest || cache.contains_key(key));
+    before.saturating_sub(meta.len())
+}
+
+/// deletes every key on this node. the deleted keys keep their metadata like other deletes,
+/// so their old values do not come back from a backup or a neighbour
+pub fn purge(cache: &Db, meta: &MetaDb) {
+    let version = new_version();
+    let keys: Vec<String> = cache.iter().map(|x| x.key().clone()).collect();
+    for key in keys {
+        let metadata = Metadata {
+            version,
+            deleted: true,
+            ..Default::default()
+        };
+        // writes that came in after the purge started are kept
+        apply(cache, meta, key, Value::Unit, metadata);
+    }
+}
+
+/// stores a write made on this node and hands it to the replication
+pub fn store(
+    cache: &Db,
+    meta: &MetaDb,
+    tx: &transport::Sender,
+    key: String,
+    value: Value,
+    metadata: Metadata,
+) {
+    write(cache, meta, key.clone(), value.clone(), metadata.clone());
+    send(tx, key, value, metadata);
+}
+
+/// stores a write made on this node, true if the key was set and not expired before.
+/// the entry stays locked like in `apply`, so a replicated write cannot land in between
+pub fn write(cache: &Db, meta: &MetaDb, key: String, value: Value, metadata: Metadata) -> bool {
+    let mut entry = meta.entry(key.clone()).or_default();
+    let existed = !entry.is_expired();
+    let existed = cache.insert(key, value).is_some() && existed;
+    *entry = metadata;
+    existed
+}
+
+/// hands a write whose value and metadata are already stored to the replication
+pub fn send(tx: &transport::Sender, key: String, value: Value, metadata: Metadata) {
+    // ignore the error, this will only return if no-one is listening.
+    tx.send(Message::Created(key, value, metadata, telemetry::current()))
+        .ok();
+}
+
+/// deletes the key on this node and hands the delete to the replication,
+/// false if the key did not exist
+pub fn remove(cache: &Db, meta: &MetaDb, tx: &transport::Sender, key: String) -> bool {
+    let existed = !expired(meta, &key);
+    let deleted = cache.remove(&key).is_some() && existed;
+    let version = new_version();
+    meta.insert(
+        key.clone(),
+        Metadata {
+            version,
+            deleted: true,
+            ..Default::default()
+        },
+    );
+    tx.send(Message::Deleted(key, version, telemetry::current()))
+        .ok();
+    deleted
+}
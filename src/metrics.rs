@@ -0,0 +1,139 @@
+use crate::Db;
+
+use lazy_static::lazy_static;
+use prometheus::core::Collector;
+use prometheus::{
+    exponential_buckets, register_histogram, register_histogram_vec, register_int_counter,
+    register_int_counter_vec, register_int_gauge, Encoder, Histogram, HistogramVec, IntCounter,
+    IntCounterVec, IntGauge, TextEncoder,
+};
+use warp::log::Info;
+
+lazy_static! {
+    pub static ref REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "racher_requests_total",
+        "handled http requests",
+        &["route", "status"]
+    )
+    .unwrap();
+    pub static ref REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "racher_request_duration_seconds",
+        "time it took to answer a http request",
+        &["route"]
+    )
+    .unwrap();
+    pub static ref VALUE_SIZE: Histogram = register_histogram!(
+        "racher_value_size_bytes",
+        "size of the values that are set",
+        exponential_buckets(64.0, 4.0, 10).unwrap()
+    )
+    .unwrap();
+    pub static ref GETS: IntCounterVec = register_int_counter_vec!(
+        "racher_gets_total",
+        "reads of a key, with every consistency level",
+        &["result"]
+    )
+    .unwrap();
+    pub static ref BACKUP_DURATION: Histogram = register_histogram!(
+        "racher_backup_duration_seconds",
+        "time it took to write a backup file"
+    )
+    .unwrap();
+    pub static ref BACKUP_SIZE: IntGauge =
+        register_int_gauge!("racher_backup_size_bytes", "size of the last backup file").unwrap();
+    pub static ref BACKUP_FAILURES: IntCounter = register_int_counter!(
+        "racher_backup_failures_total",
+        "backups that could not be written"
+    )
+    .unwrap();
+    pub static ref REPLICATIONS: IntCounterVec = register_int_counter_vec!(
+        "racher_replications_total",
+        "updates sent to neighbours",
+        &["result"]
+    )
+    .unwrap();
+    pub static ref REPLICATIONS_DROPPED: IntCounter = register_int_counter!(
+        "racher_replications_dropped_total",
+        "updates that were dropped because the sender could not keep up"
+    )
+    .unwrap();
+    pub static ref CLIENT_RETRIES: IntCounter = register_int_counter!(
+        "racher_client_retries_total",
+        "requests to other nodes that were retried"
+    )
+    .unwrap();
+    pub static ref RATE_LIMITED: IntCounterVec = register_int_counter_vec!(
+        "racher_rate_limited_total",
+        "requests that were answered with a 429",
+        &["limit"]
+    )
+    .unwrap();
+}
+
+/// the first segment of the path, keys are left out to keep the amount of labels small
+fn route(path: &str) -> &str {
+    let mut segments = path.trim_start_matches('/').split('/');
+    match segments.next() {
+        Some("_internal") => match segments.next() {
+            Some("raft") => "_internal/raft",
+            Some("update") => "_internal/update",
+            Some("get") => "_internal/get",
+            Some(_) => "_internal",
+            None => "_internal",
+        },
+        Some(route @ "get")
+        | Some(route @ "set")
+        | Some(route @ "del")
+        | Some(route @ "keys")
+        | Some(route @ "purge")
+        | Some(route @ "ping")
+        | Some(route @ "cluster")
+        | Some(route @ "stats")
+        | Some(route @ "metrics") => route,
+        _ => "other",
+    }
+}
+
+/// records every request, used with `warp::log::custom`
+pub fn observe(info: Info<'_>) {
+    let route = route(info.path());
+    REQUESTS
+        .with_label_values(&[route, info.status().as_str()])
+        .inc();
+    REQUEST_DURATION
+        .with_label_values(&[route])
+        .observe(info.elapsed().as_secs_f64());
+
+    if route == "set" {
+        let size = info
+            .request_headers()
+            .get(warp::http::header::CONTENT_LENGTH)
+            .and_then(|x| x.to_str().ok())
+            .and_then(|x| x.parse::<f64>().ok());
+        if let Some(size) = size {
+            VALUE_SIZE.observe(size);
+        }
+    }
+}
+
+/// counts a read as a hit or a miss
+pub fn count_get(found: bool) {
+    let result = if found { "hit" } else { "miss" };
+    GETS.with_label_values(&[result]).inc();
+}
+
+/// all metrics in the prometheus text format
+pub fn gather(cache: &Db) -> String {
+    // the key count belongs to this cache, so it is not kept in the global registry
+    let keys = IntGauge::new("racher_keys", "amount of keys in the cache")
+        .expect("the name and help are valid");
+    keys.set(cache.len() as i64);
+    let mut families = prometheus::gather();
+    families.extend(keys.collect());
+
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&families, &mut buffer)
+        .expect("metrics are always valid");
+    String::from_utf8(buffer).expect("the text encoder writes utf8")
+}
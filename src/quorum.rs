@@ -0,0 +1,173 @@
+use crate::client::Client;
+use crate::config::RuntimeConfigArc;
+use crate::metadata::{self, Metadata};
+use crate::raft::{cluster, majority};
+use crate::responses::VersionedResponse;
+use crate::routes::Consistency;
+use crate::{Db, MetaDb};
+
+use futures::stream::{FuturesUnordered, StreamExt};
+use serde_value::Value;
+use tracing::debug;
+
+#[derive(Debug)]
+pub struct QuorumError {
+    pub acknowledged: usize,
+    pub required: usize,
+}
+
+impl std::fmt::Display for QuorumError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "only {} of the {} required replicas responded",
+            self.acknowledged, self.required
+        )
+    }
+}
+
+impl std::error::Error for QuorumError {}
+
+/// amount of nodes, counting this one, that have to respond for the consistency level.
+/// `peers` are all other members, a node that lost its neighbours still needs them
+fn required(consistency: Consistency, peers: usize) -> usize {
+    match consistency {
+        Consistency::Quorum => majority(peers),
+        Consistency::All => peers + 1,
+        _ => 1,
+    }
+}
+
+/// sends an already applied local write to every neighbour and waits until
+/// enough of them acknowledged it, the neighbours that are slower still get the write.
+/// the local write is not undone when too few acknowledged it
+pub async fn write(
+    cfg: &RuntimeConfigArc,
+    key: &str,
+    value: &Value,
+    metadata: &Metadata,
+    consistency: Consistency,
+) -> Result<(), QuorumError> {
+    let (_, peers) = cluster(cfg).await;
+    let required = required(consistency, peers.len());
+
+    let client = Client::from_config(&*cfg.read().await);
+    let mut updates: FuturesUnordered<_> = peers
+        .into_iter()
+        .map(|peer| {
+            let mut client = client.clone();
+            let (key, value, metadata) = (key.to_string(), value.clone(), metadata.clone());
+            async move {
+                client
+                    .internal_update(peer, &key, &value, &metadata)
+                    .await
+                    .is_ok()
+            }
+        })
+        .collect();
+
+    let mut acknowledged = 1;
+    while acknowledged < required {
+        if acknowledged + updates.len() < required {
+            return Err(QuorumError {
+                acknowledged,
+                required,
+            });
+        }
+        if updates.next().await == Some(true) {
+            acknowledged += 1;
+        }
+    }
+    if !updates.is_empty() {
+        tokio::spawn(async move { while updates.next().await.is_some() {} });
+    }
+    Ok(())
+}
+
+/// reads the key from enough replicas and returns the newest value with its metadata,
+/// replicas that returned an older version are updated in the background
+pub async fn read(
+    cache: &Db,
+    meta: &MetaDb,
+    cfg: &RuntimeConfigArc,
+    key: &str,
+    consistency: Consistency,
+) -> Result<VersionedResponse, QuorumError> {
+    let (_, peers) = cluster(cfg).await;
+    let required = required(consistency, peers.len());
+
+    let local = {
+        let (data, meta) = metadata::lookup(cache, meta, key);
+        VersionedResponse { data, meta }
+    };
+
+    let client = Client::from_config(&*cfg.read().await);
+    let mut reads: FuturesUnordered<_> = peers
+        .into_iter()
+        .map(|peer| {
+            let mut client = client.clone();
+            async move {
+                let response = client.internal_get(peer.clone(), key).await.ok()?;
+                Some((peer, response))
+            }
+        })
+        .collect();
+
+    // the replicas that did not answer yet are not waited for once there are enough
+    let mut remote = Vec::new();
+    while 1 + remote.len() < required {
+        match reads.next().await {
+            Some(Some(response)) => remote.push(response),
+            Some(None) => (),
+            None => break,
+        }
+    }
+
+    let acknowledged = 1 + remote.len();
+    if acknowledged < required {
+        return Err(QuorumError {
+            acknowledged,
+            required,
+        });
+    }
+
+    let newest = remote
+        .iter()
+        .map(|(_, response)| response)
+        .fold(&local, |newest, response| {
+            if response.meta.version > newest.meta.version {
+                response
+            } else {
+                newest
+            }
+        })
+        .clone();
+
+    if local.meta.version < newest.meta.version {
+        debug!("read repair of key '{}' on this node", key);
+        metadata::apply(
+            cache,
+            meta,
+            key.to_string(),
+            newest.data.clone(),
+            newest.meta.clone(),
+        );
+    }
+
+    for (peer, response) in remote {
+        if response.meta.version < newest.meta.version {
+            debug!("read repair of key '{}' on '{}'", key, peer);
+            let mut client = client.clone();
+            let key = key.to_string();
+            let newest = newest.clone();
+            tokio::spawn(async move {
+                client
+                    .internal_update(peer, &key, &newest.data, &newest.meta)
+                    .await
+                    .ok();
+            });
+        }
+    }
+
+    Ok(newest)
+}
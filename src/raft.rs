@@ -0,0 +1,817 @@
+use crate::client::Client;
+use crate::config::RuntimeConfigArc;
+use crate::metadata::{self, Metadata};
+use crate::responses::{AppendResponse, VoteResponse};
+use crate::sync::{Arc, RwLock};
+use crate::{Db, MetaDb};
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use futures::future::join_all;
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use serde_value::Value;
+use tokio::io::AsyncWriteExt;
+use tokio::{fs, io, time};
+use tracing::{debug, error, info};
+use url::Url;
+
+pub type RaftArc = Arc<RwLock<Raft>>;
+
+/// file in the backup dir where the term, vote and snapshot are persisted
+pub const STATE_FILE: &str = "raft.json";
+/// file in the backup dir the entries after the snapshot are appended to, one json entry per line
+pub const LOG_FILE: &str = "raft.log";
+
+const TICK: Duration = Duration::from_millis(50);
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(200);
+const ELECTION_TIMEOUT_MIN: u64 = 1000;
+const ELECTION_TIMEOUT_MAX: u64 = 2000;
+const PROPOSE_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_ENTRIES_PER_APPEND: usize = 512;
+/// applied entries are folded into the snapshot once there are this many of them
+const COMPACT_THRESHOLD: u64 = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Command {
+    /// written by a new leader to commit an entry from its own term
+    Noop,
+    Set(String, Value),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LogEntry {
+    pub term: u64,
+    pub index: u64,
+    pub command: Command,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct VoteRequest {
+    pub term: u64,
+    pub candidate: Url,
+    pub last_log_index: u64,
+    pub last_log_term: u64,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct AppendRequest {
+    pub term: u64,
+    pub leader: Url,
+    pub prev_log_index: u64,
+    pub prev_log_term: u64,
+    pub entries: Vec<LogEntry>,
+    pub leader_commit: u64,
+    /// sent instead of the entries a follower needs when they are compacted already
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub snapshot: Option<Snapshot>,
+}
+
+/// the applied entries up to and including `index`, as the last value of every key
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub index: u64,
+    pub term: u64,
+    pub values: BTreeMap<String, Value>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct PersistentState {
+    current_term: u64,
+    voted_for: Option<Url>,
+    #[serde(default)]
+    snapshot: Snapshot,
+    /// state files written before the log had its own file contain the whole log
+    #[serde(default, skip_serializing)]
+    log: Vec<LogEntry>,
+}
+
+#[derive(Debug)]
+pub enum RaftError {
+    NotLeader(Option<Url>),
+    NoQuorum,
+    Timeout,
+    Io(io::Error),
+}
+
+impl std::fmt::Display for RaftError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RaftError::NotLeader(Some(leader)) => {
+                write!(f, "not the leader, leader is '{}'", leader)
+            }
+            RaftError::NotLeader(None) => write!(f, "no leader elected"),
+            RaftError::NoQuorum => write!(f, "could not reach a quorum"),
+            RaftError::Timeout => write!(f, "timed out waiting for commit"),
+            RaftError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for RaftError {}
+
+#[derive(Debug)]
+pub struct Raft {
+    pub current_term: u64,
+    pub voted_for: Option<Url>,
+    /// the entries after the snapshot
+    pub log: Vec<LogEntry>,
+    pub snapshot: Snapshot,
+    pub commit_index: u64,
+    pub last_applied: u64,
+    pub role: Role,
+    pub leader: Option<Url>,
+    last_contact: Instant,
+    last_heartbeat: Instant,
+    election_timeout: Duration,
+    next_index: HashMap<Url, u64>,
+    match_index: HashMap<Url, u64>,
+    state_file: Option<PathBuf>,
+    log_file: Option<PathBuf>,
+}
+
+impl Default for Raft {
+    fn default() -> Raft {
+        Raft {
+            current_term: 0,
+            voted_for: None,
+            log: Vec::new(),
+            snapshot: Snapshot::default(),
+            commit_index: 0,
+            last_applied: 0,
+            role: Role::Follower,
+            leader: None,
+            last_contact: Instant::now(),
+            last_heartbeat: Instant::now(),
+            election_timeout: random_election_timeout(),
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            state_file: None,
+            log_file: None,
+        }
+    }
+}
+
+impl Raft {
+    pub fn to_arc(self) -> RaftArc {
+        Arc::new(RwLock::new(self))
+    }
+
+    /// loads the persisted state from `backup_dir`, starting empty if there is none
+    pub async fn load(backup_dir: &Path) -> io::Result<Raft> {
+        let state_file = backup_dir.join(STATE_FILE);
+        let log_file = backup_dir.join(LOG_FILE);
+        let state: PersistentState = match fs::read(&state_file).await {
+            Ok(contents) => serde_json::from_slice(&contents)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => PersistentState::default(),
+            Err(e) => return Err(e),
+        };
+        let migrate = !state.log.is_empty();
+        let mut entries = state.log;
+        match fs::read(&log_file).await {
+            // a line cut off by a crash is the end of the log
+            Ok(contents) => entries.extend(
+                contents
+                    .split(|x| *x == b'\n')
+                    .map_while(|line| serde_json::from_slice::<LogEntry>(line).ok()),
+            ),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+
+        let mut raft = Raft {
+            current_term: state.current_term,
+            voted_for: state.voted_for,
+            commit_index: state.snapshot.index,
+            last_applied: state.snapshot.index,
+            snapshot: state.snapshot,
+            state_file: Some(state_file),
+            log_file: Some(log_file),
+            ..Default::default()
+        };
+        // entries are appended after the ones they replace are cut off in memory,
+        // replaying them in order gives the same log
+        for entry in entries {
+            if entry.index <= raft.snapshot.index || entry.index > raft.last_log_index() + 1 {
+                continue;
+            }
+            let position = raft.position(entry.index);
+            raft.log.truncate(position);
+            raft.log.push(entry);
+        }
+        if migrate {
+            raft.persist().await?;
+            raft.rewrite_log().await?;
+        }
+        Ok(raft)
+    }
+
+    /// writes the term, vote and snapshot, the log is written by `append_log` and `rewrite_log`
+    async fn persist(&self) -> io::Result<()> {
+        let state_file = match &self.state_file {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        if let Some(dir) = state_file.parent() {
+            fs::create_dir_all(dir).await?;
+        }
+        let state = PersistentState {
+            current_term: self.current_term,
+            voted_for: self.voted_for.clone(),
+            snapshot: self.snapshot.clone(),
+            log: Vec::new(),
+        };
+        // the snapshot cannot be lost halfway, so the file is replaced at once
+        let temp_file = state_file.with_extension("json.tmp");
+        fs::write(&temp_file, serde_json::to_vec(&state)?).await?;
+        fs::rename(temp_file, state_file).await
+    }
+
+    /// appends the entries from `index` on to the log file
+    async fn append_log(&self, index: u64) -> io::Result<()> {
+        let log_file = match &self.log_file {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        if let Some(dir) = log_file.parent() {
+            fs::create_dir_all(dir).await?;
+        }
+        let mut lines = Vec::new();
+        for entry in &self.log[self.position(index)..] {
+            serde_json::to_writer(&mut lines, entry)?;
+            lines.push(b'\n');
+        }
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(log_file)
+            .await?;
+        file.write_all(&lines).await?;
+        file.sync_data().await
+    }
+
+    /// writes the log file again, after entries were cut off or compacted
+    async fn rewrite_log(&self) -> io::Result<()> {
+        let log_file = match &self.log_file {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        if let Some(dir) = log_file.parent() {
+            fs::create_dir_all(dir).await?;
+        }
+        let mut lines = Vec::new();
+        for entry in &self.log {
+            serde_json::to_writer(&mut lines, entry)?;
+            lines.push(b'\n');
+        }
+        let temp_file = log_file.with_extension("log.tmp");
+        fs::write(&temp_file, lines).await?;
+        fs::rename(temp_file, log_file).await
+    }
+
+    async fn persist_or_log(&self) {
+        if let Err(e) = self.persist().await {
+            error!("failed persisting raft state: {}", e);
+        }
+    }
+
+    async fn append_log_or_log(&self, index: u64) {
+        if let Err(e) = self.append_log(index).await {
+            error!("failed persisting raft log: {}", e);
+        }
+    }
+
+    async fn rewrite_log_or_log(&self) {
+        if let Err(e) = self.rewrite_log().await {
+            error!("failed persisting raft log: {}", e);
+        }
+    }
+
+    /// the position in `log` of the entry with this index, which comes after the snapshot
+    fn position(&self, index: u64) -> usize {
+        (index - self.snapshot.index - 1) as usize
+    }
+
+    pub fn last_log_index(&self) -> u64 {
+        self.log
+            .last()
+            .map(|entry| entry.index)
+            .unwrap_or(self.snapshot.index)
+    }
+
+    pub fn last_log_term(&self) -> u64 {
+        self.log
+            .last()
+            .map(|entry| entry.term)
+            .unwrap_or(self.snapshot.term)
+    }
+
+    fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.snapshot.index {
+            return Some(self.snapshot.term);
+        }
+        if index < self.snapshot.index {
+            return None;
+        }
+        self.log.get(self.position(index)).map(|entry| entry.term)
+    }
+
+    fn reset_election_timer(&mut self) {
+        self.last_contact = Instant::now();
+        self.election_timeout = random_election_timeout();
+    }
+
+    fn step_down(&mut self, term: u64) {
+        if term > self.current_term {
+            self.current_term = term;
+            self.voted_for = None;
+        }
+        self.role = Role::Follower;
+    }
+
+    fn become_leader(&mut self, me: Url, peers: &[Url]) {
+        self.role = Role::Leader;
+        self.leader = Some(me);
+        let next = self.last_log_index() + 1;
+        self.next_index = peers.iter().map(|peer| (peer.clone(), next)).collect();
+        self.match_index = peers.iter().map(|peer| (peer.clone(), 0)).collect();
+        let entry = LogEntry {
+            term: self.current_term,
+            index: next,
+            command: Command::Noop,
+        };
+        self.log.push(entry);
+    }
+
+    pub async fn handle_vote(&mut self, req: VoteRequest) -> VoteResponse {
+        let changed = req.term > self.current_term;
+        if req.term > self.current_term {
+            self.step_down(req.term);
+            self.leader = None;
+        }
+
+        let up_to_date = (req.last_log_term, req.last_log_index)
+            >= (self.last_log_term(), self.last_log_index());
+        let granted = req.term == self.current_term
+            && up_to_date
+            && self
+                .voted_for
+                .as_ref()
+                .is_none_or(|voted| voted == &req.candidate);
+
+        if granted {
+            self.voted_for = Some(req.candidate);
+            self.reset_election_timer();
+        }
+        if changed || granted {
+            self.persist_or_log().await;
+        }
+
+        VoteResponse {
+            term: self.current_term,
+            granted,
+        }
+    }
+
+    pub async fn handle_append(
+        &mut self,
+        req: AppendRequest,
+        cache: &Db,
+        meta: &MetaDb,
+    ) -> AppendResponse {
+        if req.term < self.current_term {
+            return AppendResponse {
+                term: self.current_term,
+                success: false,
+                match_index: 0,
+            };
+        }
+
+        let term_changed = req.term > self.current_term;
+        self.step_down(req.term);
+        self.leader = Some(req.leader);
+        self.reset_election_timer();
+
+        if let Some(snapshot) = req.snapshot {
+            if snapshot.index > self.snapshot.index {
+                self.install(snapshot, cache, meta).await;
+            }
+        }
+
+        // entries up to the snapshot are committed, so they match the ones of the leader
+        let matches = req.prev_log_index < self.snapshot.index
+            || self.term_at(req.prev_log_index) == Some(req.prev_log_term);
+        if !matches {
+            if term_changed {
+                self.persist_or_log().await;
+            }
+            // hint the leader where our log ends, so it does not have to walk back one by one
+            return AppendResponse {
+                term: self.current_term,
+                success: false,
+                match_index: self
+                    .last_log_index()
+                    .min(req.prev_log_index.saturating_sub(1)),
+            };
+        }
+
+        let last_new_index = req.prev_log_index + req.entries.len() as u64;
+        let mut first_new = None;
+        let mut truncated = false;
+        for entry in req.entries {
+            if entry.index <= self.snapshot.index {
+                continue;
+            }
+            match self.term_at(entry.index) {
+                Some(term) if term == entry.term => continue,
+                Some(_) => {
+                    let position = self.position(entry.index);
+                    self.log.truncate(position);
+                    truncated = true;
+                }
+                None => (),
+            }
+            first_new.get_or_insert(entry.index);
+            self.log.push(entry);
+        }
+        if term_changed {
+            self.persist_or_log().await;
+        }
+        match first_new {
+            Some(_) if truncated => self.rewrite_log_or_log().await,
+            Some(index) => self.append_log_or_log(index).await,
+            None => (),
+        }
+
+        if req.leader_commit > self.commit_index {
+            self.commit_index = req.leader_commit.min(last_new_index);
+            self.apply_committed(cache, meta).await;
+        }
+
+        AppendResponse {
+            term: self.current_term,
+            success: true,
+            match_index: last_new_index,
+        }
+    }
+
+    async fn advance_commit(&mut self, peers: &[Url], cache: &Db, meta: &MetaDb) {
+        for index in (self.commit_index + 1..=self.last_log_index()).rev() {
+            if self.term_at(index) != Some(self.current_term) {
+                break;
+            }
+            let replicated = peers
+                .iter()
+                .filter(|peer| self.match_index.get(*peer).copied().unwrap_or(0) >= index)
+                .count();
+            if replicated + 1 >= majority(peers.len()) {
+                self.commit_index = index;
+                break;
+            }
+        }
+        self.apply_committed(cache, meta).await;
+    }
+
+    async fn apply_committed(&mut self, cache: &Db, meta: &MetaDb) {
+        while self.last_applied < self.commit_index {
+            self.last_applied += 1;
+            let entry = &self.log[self.position(self.last_applied)];
+            debug!("applying raft entry {}", entry.index);
+            if let Command::Set(key, value) = &entry.command {
+                apply_set(cache, meta, key.clone(), value.clone());
+            }
+        }
+        if self.last_applied - self.snapshot.index >= COMPACT_THRESHOLD {
+            self.compact().await;
+        }
+    }
+
+    /// folds the applied entries into the snapshot, so the log does not grow forever
+    async fn compact(&mut self) {
+        let applied = self.position(self.last_applied) + 1;
+        for entry in self.log.drain(..applied) {
+            if let Command::Set(key, value) = entry.command {
+                self.snapshot.values.insert(key, value);
+            }
+            self.snapshot.index = entry.index;
+            self.snapshot.term = entry.term;
+        }
+        debug!("compacted raft log up to entry {}", self.snapshot.index);
+        // entries before the snapshot in the log file are skipped when loading,
+        // so a crash between the two writes loses nothing
+        self.persist_or_log().await;
+        self.rewrite_log_or_log().await;
+    }
+
+    /// replaces the start of the log with the snapshot of the leader
+    async fn install(&mut self, snapshot: Snapshot, cache: &Db, meta: &MetaDb) {
+        // entries after the snapshot are kept when they agree with it
+        if self.term_at(snapshot.index) == Some(snapshot.term) {
+            let position = self.position(snapshot.index);
+            self.log.drain(..=position);
+        } else {
+            self.log.clear();
+        }
+        if snapshot.index > self.last_applied {
+            for (key, value) in snapshot.values.iter() {
+                apply_set(cache, meta, key.clone(), value.clone());
+            }
+            self.last_applied = snapshot.index;
+        }
+        self.commit_index = self.commit_index.max(snapshot.index);
+        debug!("installed raft snapshot up to entry {}", snapshot.index);
+        self.snapshot = snapshot;
+        self.persist_or_log().await;
+        self.rewrite_log_or_log().await;
+    }
+}
+
+/// writes a committed value like any other write, with a new version and without the expiry,
+/// content type and flags of the value it replaces
+fn apply_set(cache: &Db, meta: &MetaDb, key: String, value: Value) {
+    let metadata = Metadata {
+        version: metadata::new_version(),
+        ..Default::default()
+    };
+    metadata::apply(cache, meta, key, value, metadata);
+}
+
+fn random_election_timeout() -> Duration {
+    Duration::from_millis(thread_rng().gen_range(ELECTION_TIMEOUT_MIN..ELECTION_TIMEOUT_MAX))
+}
+
+/// amount of votes or acknowledgements needed, counting this node
+pub(crate) fn majority(peers: usize) -> usize {
+    let members = peers + 1;
+    members / 2 + 1
+}
+
+/// this node and the other members. members that cannot be reached still count,
+/// the neighbours only hold the ones that answered the last ping
+pub(crate) async fn cluster(cfg: &RuntimeConfigArc) -> (Url, Vec<Url>) {
+    let read_cfg = cfg.read().await;
+    let me = read_cfg.external_address.clone();
+    let peers = read_cfg
+        .members
+        .iter()
+        .filter(|neighbour| *neighbour != &me)
+        .cloned()
+        .collect();
+    (me, peers)
+}
+
+/// drives elections and heartbeats, runs until the process stops
+pub async fn run(raft: RaftArc, cfg: RuntimeConfigArc, cache: Db, meta: MetaDb) {
+    let mut interval = time::interval(TICK);
+    loop {
+        interval.tick().await;
+
+        let (role, election_due, heartbeat_due) = {
+            let guard = raft.read().await;
+            (
+                guard.role,
+                guard.last_contact.elapsed() >= guard.election_timeout,
+                guard.last_heartbeat.elapsed() >= HEARTBEAT_INTERVAL,
+            )
+        };
+
+        match role {
+            Role::Leader if heartbeat_due => {
+                replicate(&raft, &cfg, &cache, &meta).await;
+            }
+            Role::Leader => (),
+            _ if election_due => elect(&raft, &cfg, &cache, &meta).await,
+            _ => (),
+        }
+    }
+}
+
+async fn elect(raft: &RaftArc, cfg: &RuntimeConfigArc, cache: &Db, meta: &MetaDb) {
+    let (me, peers) = cluster(cfg).await;
+    let request = {
+        let mut guard = raft.write().await;
+        guard.current_term += 1;
+        guard.role = Role::Candidate;
+        guard.voted_for = Some(me.clone());
+        guard.leader = None;
+        guard.reset_election_timer();
+        guard.persist_or_log().await;
+        VoteRequest {
+            term: guard.current_term,
+            candidate: me.clone(),
+            last_log_index: guard.last_log_index(),
+            last_log_term: guard.last_log_term(),
+        }
+    };
+    debug!("starting election for term {}", request.term);
+
+    let client = Client::new();
+    let responses = join_all(peers.iter().map(|peer| {
+        let mut client = client.clone();
+        let request = &request;
+        async move { client.raft_vote(peer.clone(), request).await.ok() }
+    }))
+    .await;
+
+    let mut votes = 1;
+    let mut guard = raft.write().await;
+    for response in responses.into_iter().flatten() {
+        if response.term > guard.current_term {
+            guard.step_down(response.term);
+            guard.persist_or_log().await;
+            return;
+        }
+        if response.granted {
+            votes += 1;
+        }
+    }
+
+    if guard.role == Role::Candidate
+        && guard.current_term == request.term
+        && votes >= majority(peers.len())
+    {
+        info!("elected leader for term {}", request.term);
+        guard.become_leader(me, &peers);
+        let index = guard.last_log_index();
+        guard.append_log_or_log(index).await;
+        drop(guard);
+        replicate(raft, cfg, cache, meta).await;
+    }
+}
+
+/// sends append entries (or heartbeats) to every peer, returns the amount
+/// of nodes that still accept this node as leader, counting itself
+async fn replicate(raft: &RaftArc, cfg: &RuntimeConfigArc, cache: &Db, meta: &MetaDb) -> usize {
+    let (me, peers) = cluster(cfg).await;
+    let (term, requests) = {
+        let mut guard = raft.write().await;
+        if guard.role != Role::Leader {
+            return 0;
+        }
+        guard.last_heartbeat = Instant::now();
+
+        let mut requests = Vec::new();
+        for peer in peers.iter() {
+            let next = match guard.next_index.get(peer) {
+                Some(next) => *next,
+                None => {
+                    let next = guard.last_log_index() + 1;
+                    guard.next_index.insert(peer.clone(), next);
+                    next
+                }
+            };
+            // a peer that needs compacted entries gets the snapshot instead
+            let (prev_log_index, snapshot) = if next <= guard.snapshot.index {
+                (guard.snapshot.index, Some(guard.snapshot.clone()))
+            } else {
+                (next - 1, None)
+            };
+            let request = AppendRequest {
+                term: guard.current_term,
+                leader: me.clone(),
+                prev_log_index,
+                prev_log_term: guard.term_at(prev_log_index).unwrap_or(0),
+                entries: guard
+                    .log
+                    .iter()
+                    .skip((prev_log_index - guard.snapshot.index) as usize)
+                    .take(MAX_ENTRIES_PER_APPEND)
+                    .cloned()
+                    .collect(),
+                leader_commit: guard.commit_index,
+                snapshot,
+            };
+            requests.push((peer, request));
+        }
+        (guard.current_term, requests)
+    };
+
+    let client = Client::new();
+    let responses = join_all(requests.iter().map(|(peer, request)| {
+        let mut client = client.clone();
+        async move {
+            let response = client.raft_append((*peer).clone(), request).await;
+            (*peer, response.map_err(|e| e.to_string()))
+        }
+    }))
+    .await;
+
+    let mut guard = raft.write().await;
+    if guard.role != Role::Leader || guard.current_term != term {
+        return 0;
+    }
+
+    let mut acks = 1;
+    for (peer, response) in responses {
+        match response {
+            Ok(response) if response.term > guard.current_term => {
+                guard.step_down(response.term);
+                guard.leader = None;
+                guard.persist_or_log().await;
+                return 0;
+            }
+            Ok(response) if response.success => {
+                acks += 1;
+                guard.match_index.insert(peer.clone(), response.match_index);
+                guard
+                    .next_index
+                    .insert(peer.clone(), response.match_index + 1);
+            }
+            Ok(response) => {
+                acks += 1;
+                let next = guard.next_index.get(peer).copied().unwrap_or(1);
+                let next = (response.match_index + 1)
+                    .min(next.saturating_sub(1))
+                    .max(1);
+                guard.next_index.insert(peer.clone(), next);
+            }
+            Err(e) => debug!("append to '{}' failed: {}", peer, e),
+        }
+    }
+    guard.advance_commit(&peers, cache, meta).await;
+
+    acks
+}
+
+/// appends the command to the log and waits until a majority has stored it
+pub async fn propose(
+    raft: &RaftArc,
+    cfg: &RuntimeConfigArc,
+    cache: &Db,
+    meta: &MetaDb,
+    command: Command,
+) -> Result<(), RaftError> {
+    let (index, term) = {
+        let mut guard = raft.write().await;
+        if guard.role != Role::Leader {
+            return Err(RaftError::NotLeader(guard.leader.clone()));
+        }
+        let index = guard.last_log_index() + 1;
+        let term = guard.current_term;
+        guard.log.push(LogEntry {
+            term,
+            index,
+            command,
+        });
+        guard.append_log(index).await.map_err(RaftError::Io)?;
+        (index, term)
+    };
+
+    let deadline = Instant::now() + PROPOSE_TIMEOUT;
+    loop {
+        replicate(raft, cfg, cache, meta).await;
+        {
+            let guard = raft.read().await;
+            if guard.commit_index >= index {
+                return match guard.term_at(index) {
+                    Some(x) if x == term => Ok(()),
+                    // a leader never replaces its own entries, so a compacted one is still ours
+                    None if guard.role == Role::Leader && guard.current_term == term => Ok(()),
+                    _ => Err(RaftError::NotLeader(guard.leader.clone())),
+                };
+            }
+            if guard.role != Role::Leader {
+                return Err(RaftError::NotLeader(guard.leader.clone()));
+            }
+        }
+        if Instant::now() >= deadline {
+            return Err(RaftError::Timeout);
+        }
+        time::sleep(TICK).await;
+    }
+}
+
+/// confirms this node is still the leader before serving a linearizable read
+pub async fn read_barrier(
+    raft: &RaftArc,
+    cfg: &RuntimeConfigArc,
+    cache: &Db,
+    meta: &MetaDb,
+) -> Result<(), RaftError> {
+    {
+        let guard = raft.read().await;
+        if guard.role != Role::Leader {
+            return Err(RaftError::NotLeader(guard.leader.clone()));
+        }
+    }
+
+    let peers = cluster(cfg).await.1.len();
+    let acks = replicate(raft, cfg, cache, meta).await;
+
+    let guard = raft.read().await;
+    if acks >= majority(peers) && guard.term_at(guard.commit_index) == Some(guard.current_term) {
+        Ok(())
+    } else {
+        Err(RaftError::NoQuorum)
+    }
+}
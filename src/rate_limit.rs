@@ -0,0 +1,364 @@
+use crate::config::{base64_sha3, RuntimeConfig, RuntimeConfigArc};
+use crate::metrics;
+use crate::responses::{ErrorCode, ErrorResponse};
+use crate::sync::Arc;
+
+use std::collections::HashSet;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+use tower::{Layer, Service};
+use url::Url;
+use warp::http::header::{HeaderValue, RETRY_AFTER};
+use warp::hyper::{Body, Request, Response};
+use warp::Reply;
+
+/// clients that send one of the configured api keys in this header are limited by it
+/// instead of by their ip
+pub const API_KEY_HEADER: &str = "x-api-key";
+/// nodes send the cluster secret in this header, requests of neighbours that carry it are not limited
+pub const SECRET_HEADER: &str = "x-racher-secret";
+/// buckets that filled up again are dropped once there are more than this many
+const MAX_BUCKETS: usize = 10_000;
+/// the buckets are checked at most this often, a sweep goes over all of them
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+/// routes that have the key as their second segment
+const KEY_ROUTES: [&str; 4] = ["get", "set", "del", "keys"];
+
+/// a limit for the keys that start with `prefix`, e.g. `sessions:=50`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PrefixLimit {
+    pub prefix: String,
+    pub per_second: u32,
+}
+
+impl FromStr for PrefixLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (prefix, per_second) = s
+            .trim()
+            .rsplit_once('=')
+            .ok_or_else(|| format!("'{}' should look like prefix=requests", s))?;
+        let per_second = per_second
+            .parse()
+            .map_err(|_| format!("'{}' is not a number of requests", per_second))?;
+        if prefix.is_empty() || per_second == 0 {
+            return Err(format!("'{}' needs a prefix and a limit larger than 0", s));
+        }
+        Ok(PrefixLimit {
+            prefix: prefix.to_string(),
+            per_second,
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn full(capacity: f64, now: Instant) -> Self {
+        Bucket {
+            tokens: capacity,
+            updated: now,
+        }
+    }
+
+    /// takes a token, without one it returns how long it takes until there is one
+    fn take(&mut self, per_second: f64, capacity: f64, now: Instant) -> Result<(), Duration> {
+        let refill = now.duration_since(self.updated).as_secs_f64() * per_second;
+        self.tokens = (self.tokens + refill).min(capacity);
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+        Err(Duration::from_secs_f64((1.0 - self.tokens) / per_second))
+    }
+
+    fn is_full(&self, per_second: f64, capacity: f64, now: Instant) -> bool {
+        self.tokens + now.duration_since(self.updated).as_secs_f64() * per_second >= capacity
+    }
+}
+
+pub type RateLimiterArc = Arc<RateLimiter>;
+
+/// token buckets per client, and per client and key prefix
+#[derive(Debug)]
+pub struct RateLimiter {
+    /// requests per second of a client, 0 turns it off
+    per_second: u32,
+    /// requests a client can make at once after being idle
+    burst: u32,
+    prefixes: Vec<PrefixLimit>,
+    /// the values of `x-api-key` that get a bucket of their own
+    api_keys: HashSet<String>,
+    buckets: DashMap<String, Bucket>,
+    last_sweep: Mutex<Instant>,
+    /// the neighbours that were looked up last and their ips
+    neighbours: tokio::sync::Mutex<(HashSet<Url>, HashSet<IpAddr>)>,
+}
+
+impl RateLimiter {
+    pub fn new(per_second: u32, burst: u32, prefixes: Vec<PrefixLimit>) -> Self {
+        RateLimiter {
+            per_second,
+            burst: burst.max(per_second),
+            prefixes,
+            api_keys: HashSet::new(),
+            buckets: DashMap::new(),
+            last_sweep: Mutex::new(Instant::now()),
+            neighbours: Default::default(),
+        }
+    }
+
+    pub fn with_api_keys(mut self, api_keys: HashSet<String>) -> Self {
+        self.api_keys = api_keys;
+        self
+    }
+
+    /// `None` when no limits are configured
+    pub fn from_config(cfg: &RuntimeConfig) -> Option<Self> {
+        if cfg.rate_limit == 0 && cfg.rate_limit_prefixes.is_empty() {
+            return None;
+        }
+        let limiter = RateLimiter::new(
+            cfg.rate_limit,
+            cfg.rate_limit_burst,
+            cfg.rate_limit_prefixes.clone(),
+        );
+        Some(limiter.with_api_keys(cfg.rate_limit_api_keys.clone()))
+    }
+
+    pub fn to_arc(self) -> RateLimiterArc {
+        Arc::new(self)
+    }
+
+    fn take(
+        &self,
+        bucket: String,
+        per_second: u32,
+        capacity: u32,
+        now: Instant,
+    ) -> Result<(), Duration> {
+        let (per_second, capacity) = (per_second as f64, capacity as f64);
+        self.buckets
+            .entry(bucket)
+            .or_insert_with(|| Bucket::full(capacity, now))
+            .take(per_second, capacity, now)
+    }
+
+    /// takes a token of the client and of the longest prefix the key starts with.
+    /// returns how long the client has to wait when one of them is empty
+    pub fn check(&self, client: &str, key: Option<&str>) -> Result<(), Duration> {
+        let now = Instant::now();
+        if self.buckets.len() > MAX_BUCKETS {
+            self.sweep(now);
+        }
+
+        let prefix = key.and_then(|key| {
+            self.prefixes
+                .iter()
+                .filter(|x| key.starts_with(&x.prefix))
+                .max_by_key(|x| x.prefix.len())
+        });
+        if let Some(limit) = prefix {
+            let bucket = format!("{}\n{}", client, limit.prefix);
+            if let Err(wait) = self.take(bucket, limit.per_second, limit.per_second, now) {
+                metrics::RATE_LIMITED.with_label_values(&["prefix"]).inc();
+                return Err(wait);
+            }
+        }
+        if self.per_second > 0 {
+            if let Err(wait) = self.take(client.to_string(), self.per_second, self.burst, now) {
+                metrics::RATE_LIMITED.with_label_values(&["client"]).inc();
+                return Err(wait);
+            }
+        }
+        Ok(())
+    }
+
+    /// drops the buckets that filled up again, they would be created full anyway.
+    /// only one request sweeps at a time, and at most once per `SWEEP_INTERVAL`
+    fn sweep(&self, now: Instant) {
+        match self.last_sweep.try_lock() {
+            Ok(mut last) if now.duration_since(*last) >= SWEEP_INTERVAL => *last = now,
+            _ => return,
+        }
+        let (per_second, burst) = (self.per_second as f64, self.burst as f64);
+        self.buckets
+            .retain(|bucket, state| match bucket.split_once('\n') {
+                Some((_, prefix)) => match self.prefixes.iter().find(|x| x.prefix == prefix) {
+                    Some(limit) => {
+                        let per_second = limit.per_second as f64;
+                        !state.is_full(per_second, per_second, now)
+                    }
+                    None => false,
+                },
+                None => !state.is_full(per_second, burst, now),
+            });
+    }
+
+    /// the api key of the request when it is a configured one, otherwise the ip of the peer.
+    /// unknown keys are ignored, or a client could get a fresh bucket with every request
+    fn client(&self, req: &Request<Body>, peer: SocketAddr) -> String {
+        match req
+            .headers()
+            .get(API_KEY_HEADER)
+            .and_then(|x| x.to_str().ok())
+            .filter(|x| self.api_keys.contains(*x))
+        {
+            Some(key) => format!("key:{}", key),
+            None => format!("ip:{}", peer.ip()),
+        }
+    }
+
+    /// whether the ip is the one of a neighbour, neighbours given by name are looked up.
+    /// the ips are kept until the neighbours change, not looked up for every connection
+    pub async fn is_neighbour(&self, cfg: &RuntimeConfigArc, ip: IpAddr) -> bool {
+        let mut resolved = self.neighbours.lock().await;
+        let neighbours = {
+            let read_cfg = cfg.read().await;
+            if read_cfg.neighbours == resolved.0 {
+                return resolved.1.contains(&ip);
+            }
+            read_cfg.neighbours.clone()
+        };
+        let ips = lookup(&neighbours).await;
+        *resolved = (neighbours, ips);
+        resolved.1.contains(&ip)
+    }
+}
+
+/// the ips of the neighbours, the ones that cannot be looked up are left out
+async fn lookup(neighbours: &HashSet<Url>) -> HashSet<IpAddr> {
+    let mut ips = HashSet::new();
+    for neighbour in neighbours {
+        let address = match (neighbour.host_str(), neighbour.port_or_known_default()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            _ => continue,
+        };
+        if let Ok(addresses) = tokio::net::lookup_host(address).await {
+            ips.extend(addresses.map(|x| x.ip()));
+        }
+    }
+    ips
+}
+
+/// the key a request is about, for the routes that have one in their path
+fn key(path: &str) -> Option<String> {
+    let mut segments = path.trim_start_matches('/').splitn(2, '/');
+    let route = segments.next()?;
+    let key = segments.next().filter(|_| KEY_ROUTES.contains(&route))?;
+    Some(percent_decode_str(key).decode_utf8_lossy().into_owned())
+}
+
+fn too_many_requests(wait: Duration) -> Response<Body> {
+    let mut response =
+        ErrorResponse::new(ErrorCode::TooManyRequests, "rate limit exceeded").into_response();
+    let seconds = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+    response
+        .headers_mut()
+        .insert(RETRY_AFTER, HeaderValue::from(seconds.max(1)));
+    response
+}
+
+#[derive(Clone)]
+pub struct RateLimitLayer {
+    peer: SocketAddr,
+    limiter: Option<RateLimiterArc>,
+    secret: Option<String>,
+}
+
+impl RateLimitLayer {
+    pub fn new(peer: SocketAddr, limiter: Option<RateLimiterArc>) -> Self {
+        RateLimitLayer {
+            peer,
+            limiter,
+            secret: None,
+        }
+    }
+
+    /// the cluster secret, only set when the peer is a neighbour.
+    /// an ip alone is not enough, a client can share it with a node
+    pub fn with_secret(mut self, secret: Option<String>) -> Self {
+        self.secret = secret;
+        self
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimit {
+            inner,
+            peer: self.peer,
+            limiter: self.limiter.clone(),
+            secret: self.secret.clone(),
+        }
+    }
+}
+
+/// answers with a 429 when the client made too many requests, requests of neighbours
+/// that carry the cluster secret are let through
+#[derive(Clone)]
+pub struct RateLimit<S> {
+    inner: S,
+    peer: SocketAddr,
+    limiter: Option<RateLimiterArc>,
+    secret: Option<String>,
+}
+
+impl<S> RateLimit<S> {
+    fn carries_secret(&self, req: &Request<Body>) -> bool {
+        let secret = match self.secret.as_deref() {
+            Some(secret) => secret,
+            None => return false,
+        };
+        let given = req
+            .headers()
+            .get(SECRET_HEADER)
+            .and_then(|x| x.to_str().ok())
+            .unwrap_or_default();
+        // comparing the hashes keeps the time it takes independent of the secret
+        base64_sha3(given) == base64_sha3(secret)
+    }
+}
+
+impl<S> Service<Request<Body>> for RateLimit<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>>,
+    S::Future: Send + 'static,
+    S::Error: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        if let Some(limiter) = self.limiter.as_ref().filter(|_| !self.carries_secret(&req)) {
+            let client = limiter.client(&req, self.peer);
+            if let Err(wait) = limiter.check(&client, key(req.uri().path()).as_deref()) {
+                let response = too_many_requests(wait);
+                return Box::pin(async move { Ok(response) });
+            }
+        }
+        Box::pin(self.inner.call(req))
+    }
+}
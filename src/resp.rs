@@ -0,0 +1,496 @@
+use crate::cache;
+use crate::encoding;
+use crate::metadata::{self, Metadata};
+use crate::transport;
+use crate::{Db, MetaDb, MAX_FILE_SIZE};
+
+use std::convert::TryFrom;
+use std::net::SocketAddr;
+
+use serde_value::Value;
+use tokio::io::{
+    self, AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+    BufReader,
+};
+use tokio::net::TcpListener;
+use tracing::{debug, info, warn};
+
+/// length lines and inline commands are never longer than this
+const MAX_LINE: u64 = 64 * 1024;
+
+/// a reply, RESP2 clients get the closest RESP2 type for the RESP3 only ones
+#[derive(Debug, Clone, PartialEq)]
+pub enum Frame {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Null,
+    Array(Vec<Frame>),
+    Map(Vec<(Frame, Frame)>),
+}
+
+impl Frame {
+    fn ok() -> Frame {
+        Frame::Simple(String::from("OK"))
+    }
+
+    fn error(message: &str) -> Frame {
+        Frame::Error(format!("ERR {}", message))
+    }
+
+    fn write(&self, buffer: &mut Vec<u8>, protocol: u8) {
+        match self {
+            Frame::Simple(x) => buffer.extend_from_slice(format!("+{}\r\n", x).as_bytes()),
+            Frame::Error(x) => buffer.extend_from_slice(format!("-{}\r\n", x).as_bytes()),
+            Frame::Integer(x) => buffer.extend_from_slice(format!(":{}\r\n", x).as_bytes()),
+            Frame::Bulk(x) => {
+                buffer.extend_from_slice(format!("${}\r\n", x.len()).as_bytes());
+                buffer.extend_from_slice(x);
+                buffer.extend_from_slice(b"\r\n");
+            }
+            Frame::Null if protocol >= 3 => buffer.extend_from_slice(b"_\r\n"),
+            Frame::Null => buffer.extend_from_slice(b"$-1\r\n"),
+            Frame::Array(items) => {
+                buffer.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+                for item in items {
+                    item.write(buffer, protocol);
+                }
+            }
+            Frame::Map(items) => {
+                let header = if protocol >= 3 {
+                    format!("%{}\r\n", items.len())
+                } else {
+                    format!("*{}\r\n", items.len() * 2)
+                };
+                buffer.extend_from_slice(header.as_bytes());
+                for (key, value) in items {
+                    key.write(buffer, protocol);
+                    value.write(buffer, protocol);
+                }
+            }
+        }
+    }
+}
+
+fn protocol_error(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// a line without the `\r\n`, `None` when the connection was closed
+async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    let read = reader.take(MAX_LINE).read_until(b'\n', &mut line).await?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with(b"\n") {
+        return Err(protocol_error("line too long or incomplete"));
+    }
+    line.pop();
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_length(line: &[u8]) -> io::Result<Option<usize>> {
+    let length: i64 = std::str::from_utf8(line)
+        .ok()
+        .and_then(|x| x.parse().ok())
+        .ok_or_else(|| protocol_error("invalid length"))?;
+    Ok(usize::try_from(length).ok())
+}
+
+/// reads one command, either an array of bulk strings or an inline command like `PING`.
+/// `None` when the connection was closed
+async fn read_command<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<Option<Vec<Vec<u8>>>> {
+    let line = match read_line(reader).await? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    if line.first() != Some(&b'*') {
+        let args = line
+            .split(|x| x.is_ascii_whitespace())
+            .filter(|x| !x.is_empty())
+            .map(|x| x.to_vec())
+            .collect();
+        return Ok(Some(args));
+    }
+
+    // a null array is an empty command
+    let amount = parse_length(&line[1..])?.unwrap_or(0);
+    let mut args = Vec::with_capacity(amount.min(1024));
+    for _ in 0..amount {
+        let line = read_line(reader)
+            .await?
+            .ok_or_else(|| protocol_error("connection closed in the middle of a command"))?;
+        if line.first() != Some(&b'$') {
+            return Err(protocol_error("expected a bulk string"));
+        }
+        let length = parse_length(&line[1..])?.ok_or_else(|| protocol_error("invalid length"))?;
+        if length as u64 > MAX_FILE_SIZE {
+            return Err(protocol_error("bulk string is too large"));
+        }
+        let mut arg = vec![0; length + 2];
+        reader.read_exact(&mut arg).await?;
+        if !arg.ends_with(b"\r\n") {
+            return Err(protocol_error("bulk string is longer than its length"));
+        }
+        arg.truncate(length);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+fn key(arg: &[u8]) -> Result<String, Frame> {
+    String::from_utf8(arg.to_vec()).map_err(|_| Frame::error("keys have to be valid utf-8"))
+}
+
+/// matches a redis style pattern: `*`, `?`, `[abc]`, `[a-z]`, `[^a]` and `\` to escape.
+/// a mismatch only goes back to the last `*`, so it takes at most pattern times text steps
+fn glob(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // the pattern after the last `*` and the text it is tried against next
+    let mut star = None;
+    while t < text.len() {
+        let next = match pattern.get(p) {
+            Some(b'*') => {
+                p += 1;
+                star = Some((p, t));
+                continue;
+            }
+            Some(b'?') => Some(p + 1),
+            Some(b'[') => match class(&pattern[p + 1..]) {
+                Some((class, rest)) => class(text[t]).then_some(pattern.len() - rest.len()),
+                None => None,
+            },
+            Some(b'\\') if p + 1 < pattern.len() => (pattern[p + 1] == text[t]).then_some(p + 2),
+            Some(c) => (*c == text[t]).then_some(p + 1),
+            None => None,
+        };
+        match (next, star) {
+            (Some(next), _) => {
+                p = next;
+                t += 1;
+            }
+            (None, Some((after, tried))) => {
+                p = after;
+                t = tried + 1;
+                star = Some((after, t));
+            }
+            (None, None) => return false,
+        }
+    }
+    pattern[p..].iter().all(|x| *x == b'*')
+}
+
+/// the class after a `[` as a matcher and the pattern after the closing `]`
+fn class(pattern: &[u8]) -> Option<(impl Fn(u8) -> bool + '_, &[u8])> {
+    let (negate, start) = match pattern.first() {
+        Some(b'^') => (true, &pattern[1..]),
+        _ => (false, pattern),
+    };
+    let end = start.iter().position(|x| *x == b']')?;
+    let items = &start[..end];
+    let matcher = move |c: u8| {
+        let mut items = items;
+        let mut matched = false;
+        while !items.is_empty() {
+            items = match items {
+                [b'\\', x, rest @ ..] => {
+                    matched |= *x == c;
+                    rest
+                }
+                [a, b'-', b, rest @ ..] => {
+                    matched |= (*a.min(b)..=*a.max(b)).contains(&c);
+                    rest
+                }
+                [x, rest @ ..] => {
+                    matched |= *x == c;
+                    rest
+                }
+                [] => unreachable!(),
+            };
+        }
+        matched != negate
+    };
+    Some((matcher, &start[end + 1..]))
+}
+
+/// state of one client connection
+struct Connection {
+    cache: Db,
+    meta: MetaDb,
+    tx: transport::Sender,
+    protocol: u8,
+}
+
+impl Connection {
+    fn get(&self, key: &str) -> Frame {
+        if metadata::expired(&self.meta, key) {
+            return Frame::Null;
+        }
+        match self.cache.get(key) {
+            Some(x) => Frame::Bulk(encoding::to_plain(x.value())),
+            None => Frame::Null,
+        }
+    }
+
+    fn set(&self, args: &[Vec<u8>]) -> Result<Frame, Frame> {
+        let key = key(&args[0])?;
+        let mut expires_at = None;
+        let mut options = args[2..].iter();
+        while let Some(option) = options.next() {
+            let unit = match option.to_ascii_uppercase().as_slice() {
+                b"EX" => 1_000_000,
+                b"PX" => 1_000,
+                _ => return Err(Frame::error("syntax error")),
+            };
+            let amount = options
+                .next()
+                .and_then(|x| std::str::from_utf8(x).ok())
+                .ok_or_else(|| Frame::error("syntax error"))?
+                .parse::<u64>()
+                .map_err(|_| Frame::error("value is not an integer or out of range"))?;
+            let expires = amount
+                .checked_mul(unit)
+                .and_then(|x| x.checked_add(metadata::now()))
+                .filter(|_| amount > 0)
+                .ok_or_else(|| Frame::error("invalid expire time in 'set' command"))?;
+            expires_at = Some(expires);
+        }
+
+        let (value, content_type) = encoding::from_plain(args[1].clone());
+        let metadata = Metadata {
+            version: metadata::new_version(),
+            content_type,
+            expires_at,
+            ..Default::default()
+        };
+        metadata::store(&self.cache, &self.meta, &self.tx, key, value, metadata);
+        Ok(Frame::ok())
+    }
+
+    fn incr(&self, key: String) -> Result<Frame, Frame> {
+        // the entries stay locked, so concurrent increments are not lost and the newest value
+        // gets the newest version. metadata before the value, like `metadata::apply`
+        let (value, metadata) = {
+            let mut meta_entry = self.meta.entry(key.clone()).or_default();
+            let expired = meta_entry.is_expired();
+            let expires_at = meta_entry.expires_at.filter(|_| !expired);
+            let mut created = false;
+            let mut entry = self.cache.entry(key.clone()).or_insert_with(|| {
+                created = true;
+                Value::I64(0)
+            });
+            let before = if created {
+                0
+            } else {
+                cache::size(&key, entry.value())
+            };
+            let current = if expired {
+                Some(0)
+            } else {
+                encoding::plain_integer(entry.value()).and_then(|x| i64::try_from(x).ok())
+            };
+            let next = current
+                .ok_or_else(|| Frame::error("value is not an integer or out of range"))?
+                .checked_add(1)
+                .ok_or_else(|| Frame::error("increment or decrement would overflow"))?;
+            *entry = Value::I64(next);
+            self.cache.resized(before, cache::size(&key, entry.value()));
+            let metadata = Metadata {
+                version: metadata::new_version(),
+                expires_at,
+                ..Default::default()
+            };
+            *meta_entry = metadata.clone();
+            (next, metadata)
+        };
+        metadata::send(&self.tx, key, Value::I64(value), metadata);
+        Ok(Frame::Integer(value))
+    }
+
+    fn keys(&self, pattern: &[u8]) -> Frame {
+        // the cache is released before the metadata is read, see `metadata::get`
+        let keys: Vec<String> = self
+            .cache
+            .iter()
+            .filter(|x| glob(pattern, x.key().as_bytes()))
+            .map(|x| x.key().clone())
+            .collect();
+        let keys = keys
+            .into_iter()
+            .filter(|x| !metadata::expired(&self.meta, x))
+            .map(|x| Frame::Bulk(x.into_bytes()))
+            .collect();
+        Frame::Array(keys)
+    }
+
+    fn hello(&mut self, args: &[Vec<u8>]) -> Result<Frame, Frame> {
+        if let Some(version) = args.first() {
+            self.protocol = match version.as_slice() {
+                b"2" => 2,
+                b"3" => 3,
+                _ => {
+                    return Err(Frame::Error(String::from(
+                        "NOPROTO unsupported protocol version",
+                    )))
+                }
+            };
+        }
+        let field = |name: &str| Frame::Bulk(name.as_bytes().to_vec());
+        Ok(Frame::Map(vec![
+            (field("server"), field("racher")),
+            (field("version"), field(env!("CARGO_PKG_VERSION"))),
+            (field("proto"), Frame::Integer(self.protocol as i64)),
+            (field("mode"), field("standalone")),
+            (field("role"), field("master")),
+            (field("modules"), Frame::Array(Vec::new())),
+        ]))
+    }
+
+    fn execute(&mut self, name: &str, args: &[Vec<u8>]) -> Result<Frame, Frame> {
+        let arity = |valid: bool| {
+            if valid {
+                return Ok(());
+            }
+            Err(Frame::error(&format!(
+                "wrong number of arguments for '{}' command",
+                name.to_lowercase()
+            )))
+        };
+        match name {
+            "PING" => {
+                arity(args.len() <= 1)?;
+                Ok(match args.first() {
+                    Some(message) => Frame::Bulk(message.clone()),
+                    None => Frame::Simple(String::from("PONG")),
+                })
+            }
+            "GET" => {
+                arity(args.len() == 1)?;
+                Ok(self.get(&key(&args[0])?))
+            }
+            "MGET" => {
+                arity(!args.is_empty())?;
+                let values = args
+                    .iter()
+                    .map(|x| key(x).map(|key| self.get(&key)))
+                    .collect::<Result<_, _>>()?;
+                Ok(Frame::Array(values))
+            }
+            "SET" => {
+                arity(args.len() >= 2)?;
+                self.set(args)
+            }
+            "DEL" => {
+                arity(!args.is_empty())?;
+                let mut deleted = 0;
+                for arg in args {
+                    if metadata::remove(&self.cache, &self.meta, &self.tx, key(arg)?) {
+                        deleted += 1;
+                    }
+                }
+                Ok(Frame::Integer(deleted))
+            }
+            "INCR" => {
+                arity(args.len() == 1)?;
+                self.incr(key(&args[0])?)
+            }
+            "KEYS" => {
+                arity(args.len() == 1)?;
+                Ok(self.keys(&args[0]))
+            }
+            "FLUSHALL" => {
+                arity(args.len() <= 1)?;
+                metadata::purge(&self.cache, &self.meta);
+                Ok(Frame::ok())
+            }
+            // sent by clients when they connect
+            "HELLO" => self.hello(args),
+            "SELECT" => match args {
+                [db] if db.as_slice() == b"0" => Ok(Frame::ok()),
+                [_] => Err(Frame::error("DB index is out of range")),
+                _ => arity(false).map(|_| Frame::Null),
+            },
+            "CLIENT" => match args.first().map(|x| x.to_ascii_uppercase()).as_deref() {
+                Some(b"SETNAME") | Some(b"SETINFO") => Ok(Frame::ok()),
+                _ => Err(Frame::error("unknown CLIENT subcommand")),
+            },
+            "COMMAND" => Ok(Frame::Array(Vec::new())),
+            _ => Err(Frame::error(&format!("unknown command '{}'", name))),
+        }
+    }
+}
+
+/// answers the commands of one client until it disconnects or sends QUIT
+pub async fn handle<S>(stream: S, cache: Db, meta: MetaDb, tx: transport::Sender) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (reader, mut writer) = io::split(stream);
+    let mut reader = BufReader::new(reader);
+    let mut connection = Connection {
+        cache,
+        meta,
+        tx,
+        protocol: 2,
+    };
+
+    loop {
+        let mut buffer = Vec::new();
+        let args = match read_command(&mut reader).await {
+            Ok(Some(args)) => args,
+            Ok(None) => return Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                Frame::error(&format!("Protocol error: {}", e)).write(&mut buffer, 2);
+                writer.write_all(&buffer).await?;
+                return Err(e);
+            }
+            Err(e) => return Err(e),
+        };
+        let (name, args) = match args.split_first() {
+            Some((name, args)) => (String::from_utf8_lossy(name).to_uppercase(), args),
+            None => continue,
+        };
+
+        if name == "QUIT" {
+            Frame::ok().write(&mut buffer, connection.protocol);
+            writer.write_all(&buffer).await?;
+            return Ok(());
+        }
+        let frame = connection
+            .execute(&name, args)
+            .unwrap_or_else(|error| error);
+        frame.write(&mut buffer, connection.protocol);
+        writer.write_all(&buffer).await?;
+    }
+}
+
+/// accepts redis clients on the address, they share the cache and replication with the http api
+pub async fn serve(
+    address: SocketAddr,
+    cache: Db,
+    meta: MetaDb,
+    tx: transport::Sender,
+) -> io::Result<()> {
+    let listener = TcpListener::bind(address).await?;
+    info!("resp address: {}", listener.local_addr()?);
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(x) => x,
+            Err(e) => {
+                warn!(%e, "could not accept a resp connection");
+                continue;
+            }
+        };
+        let (cache, meta, tx) = (cache.clone(), meta.clone(), tx.clone());
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream, cache, meta, tx).await {
+                debug!(%e, %peer, "resp connection closed");
+            }
+        });
+    }
+}
@@ -1,7 +1,12 @@
-use crate::Db;
+use crate::metadata::Metadata;
+
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use serde_value::Value;
+use std::fmt;
+use std::net::SocketAddr;
 use url::Url;
+use warp::http::StatusCode;
 
 #[derive(Debug, Serialize, Deserialize)]
 #[non_exhaustive]
@@ -15,7 +20,18 @@ pub enum Response {
     PONG(PingResponse),
     JOIN(JoinResponse),
     FANOUT(FanoutResponse),
-    SYNC(Db),
+    VOTE(VoteResponse),
+    APPEND(AppendResponse),
+    VERSIONED(VersionedResponse),
+    INFO(InfoResponse),
+    STATUS(ClusterStatusResponse),
+    STATS(StatsResponse),
+    SLOWLOG(SlowLogResponse),
+    CONFIG(AdminConfigResponse),
+    CHANGES(ChangesResponse),
+    /// one line of the `/_internal/sync` stream
+    SYNC(Change),
+    ERROR(ErrorResponse),
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
@@ -23,6 +39,13 @@ pub struct GetResponse {
     pub data: Value,
 }
 
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VersionedResponse {
+    pub data: Value,
+    #[serde(flatten)]
+    pub meta: Metadata,
+}
+
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct SetResponse {
     pub status: Value,
@@ -58,3 +81,177 @@ pub struct JoinResponse {
 pub struct FanoutResponse {
     pub fanout: String,
 }
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct VoteResponse {
+    pub term: u64,
+    pub granted: bool,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct AppendResponse {
+    pub term: u64,
+    pub success: bool,
+    pub match_index: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InfoResponse {
+    pub identifier: String,
+    pub code: String,
+    pub address: Url,
+    pub neighbours: Vec<Url>,
+    pub keys: usize,
+    /// newest version stored on the node
+    pub version: u64,
+    pub last_backup: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MemberStatus {
+    pub identifier: Option<String>,
+    pub address: Url,
+    pub up: bool,
+    pub last_seen: Option<DateTime<Utc>>,
+    pub keys: Option<usize>,
+    /// milliseconds between the newest write the member has and the newest write of
+    /// the cluster, 0 when it has the newest write. it is not how long writes take to arrive
+    pub version_gap_ms: Option<u64>,
+    pub backup_age_secs: Option<i64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClusterStatusResponse {
+    pub members: Vec<MemberStatus>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LimitsResponse {
+    pub max_value_bytes: u64,
+    pub max_concurrent_requests: usize,
+    pub request_timeout_ms: u64,
+    pub backup_interval_secs: u64,
+    pub backup_amount: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StatsResponse {
+    pub keys: usize,
+    /// serialized size of all keys and values, the real memory usage is higher
+    pub memory_bytes: u64,
+    pub hits: u64,
+    pub misses: u64,
+    /// `None` until the first get
+    pub hit_ratio: Option<f64>,
+    pub uptime_secs: i64,
+    pub last_backup: Option<DateTime<Utc>>,
+    pub last_backup_bytes: Option<u64>,
+    pub limits: LimitsResponse,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SlowLogEntry {
+    pub time: DateTime<Utc>,
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+    pub latency_ms: f64,
+    pub peer: SocketAddr,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SlowLogResponse {
+    pub entries: Vec<SlowLogEntry>,
+}
+
+/// the settings that can be changed with `/admin/config`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AdminConfigResponse {
+    pub backup_interval: u64,
+    pub backup_amount: usize,
+}
+
+/// a key that changed, `data` is unit when the key was deleted
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Change {
+    pub key: String,
+    pub data: Value,
+    #[serde(flatten)]
+    pub meta: Metadata,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChangesResponse {
+    pub changes: Vec<Change>,
+}
+
+/// what went wrong, every error response carries one and it decides the status code
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    BadRequest,
+    /// the body could not be decoded in the format of its content type
+    InvalidBody,
+    Unauthorized,
+    Forbidden,
+    /// the key or the route does not exist
+    NotFound,
+    MethodNotAllowed,
+    LengthRequired,
+    PayloadTooLarge,
+    UnsupportedMediaType,
+    /// the client made more requests than its rate limit allows
+    TooManyRequests,
+    /// strong consistency was asked for without `--consensus`
+    ConsensusDisabled,
+    Internal,
+    /// the raft leader a request was forwarded to did not answer
+    BadGateway,
+    /// not enough nodes answered or the cluster has no leader
+    Unavailable,
+}
+
+impl ErrorCode {
+    pub fn status(self) -> StatusCode {
+        match self {
+            ErrorCode::BadRequest | ErrorCode::InvalidBody | ErrorCode::ConsensusDisabled => {
+                StatusCode::BAD_REQUEST
+            }
+            ErrorCode::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorCode::Forbidden => StatusCode::FORBIDDEN,
+            ErrorCode::NotFound => StatusCode::NOT_FOUND,
+            ErrorCode::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            ErrorCode::LengthRequired => StatusCode::LENGTH_REQUIRED,
+            ErrorCode::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorCode::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ErrorCode::TooManyRequests => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::BadGateway => StatusCode::BAD_GATEWAY,
+            ErrorCode::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+}
+
+/// the body of every error, `error` is meant for people and `code` for programs
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ErrorResponse {
+    pub error: String,
+    pub code: ErrorCode,
+}
+
+impl ErrorResponse {
+    pub fn new(code: ErrorCode, error: impl Into<String>) -> ErrorResponse {
+        ErrorResponse {
+            error: error.into(),
+            code,
+        }
+    }
+}
+
+impl fmt::Display for ErrorResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.code.status(), self.error)
+    }
+}
+
+impl std::error::Error for ErrorResponse {}
@@ -1,17 +1,34 @@
+use crate::access_log::SlowLogArc;
+use crate::client::Client;
 use crate::config::RuntimeConfigArc;
+use crate::encoding::{self, Encoding};
+use crate::metadata::{self, Metadata};
+use crate::metrics;
+use crate::quorum;
+use crate::raft::{self, Command, RaftArc, RaftError};
+use crate::responses::{ErrorCode, ErrorResponse, VersionedResponse};
 use crate::transport;
-use crate::transport::Message;
-use crate::Db;
+use crate::{Db, MetaDb};
 
+use serde::{Deserialize, Serialize};
 use serde_json::json;
 use serde_value::Value;
 use std::collections::HashSet;
 use std::convert::Infallible;
 use std::iter::FromIterator;
+use tracing::error;
 use warp::filters::BoxedFilter;
+use warp::http::header;
+use warp::hyper::body::Bytes;
+use warp::reject::{self, Rejection};
+use warp::reply::{self, Response};
 use warp::{Filter, Reply};
 
+pub mod admin;
+pub mod cluster;
 pub mod internal;
+pub mod rest;
+pub mod stats;
 pub mod utils;
 
 use crate::MAX_FILE_SIZE;
@@ -22,67 +39,425 @@ static DASHBOARD: &'static str = include_str!("../web/dist/index.html");
 #[cfg(feature = "dashboard")]
 static FAVICON: &'static [u8] = include_bytes!("../web/favicon.ico");
 
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Consistency {
+    /// answer from the local cache, replicate in the background
+    #[default]
+    One,
+    /// wait for a majority of the cluster
+    Quorum,
+    /// wait for every node in the cluster
+    All,
+    /// go through the raft log, only available in consensus mode
+    Strong,
+}
+
+impl std::str::FromStr for Consistency {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "one" => Ok(Consistency::One),
+            "quorum" => Ok(Consistency::Quorum),
+            "all" => Ok(Consistency::All),
+            "strong" => Ok(Consistency::Strong),
+            _ => Err(format!("unknown consistency '{}'", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for Consistency {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Consistency::One => "one",
+            Consistency::Quorum => "quorum",
+            Consistency::All => "all",
+            Consistency::Strong => "strong",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ConsistencyQuery {
+    #[serde(default)]
+    pub consistency: Consistency,
+    /// set when a follower forwarded the request, prevents forwarding loops
+    #[serde(default)]
+    pub forwarded: bool,
+}
 
 pub(crate) fn ok_reponse() -> warp::reply::Json {
     warp::reply::json(&json!({"status": "ok"}))
 }
 
-fn data_response(value: &Value) -> warp::reply::Json {
-    warp::reply::json(&json!({ "data": value }))
+#[derive(Serialize)]
+struct DataResponse<'a> {
+    data: &'a Value,
+}
+
+fn data_response(value: &Value, encoding: Encoding) -> Response {
+    encoding.reply(&DataResponse { data: value })
+}
+
+/// raw values are returned as they were stored, everything else in the requested encoding
+fn value_response(value: &Value, content_type: Option<&str>, encoding: Encoding) -> Response {
+    match (value, content_type) {
+        (Value::Bytes(bytes), Some(content_type)) => {
+            reply::with_header(bytes.clone(), header::CONTENT_TYPE, content_type).into_response()
+        }
+        _ => data_response(value, encoding),
+    }
 }
 
 fn delete_response(value: bool) -> warp::reply::Json {
     warp::reply::json(&json!({ "deleted": value }))
 }
 
-async fn inner_setter(
+impl Reply for ErrorResponse {
+    fn into_response(self) -> Response {
+        let status = self.code.status();
+        reply::with_status(reply::json(&self), status).into_response()
+    }
+}
+
+/// lets filters reject a request with an error that `recover` turns into its response
+impl warp::reject::Reject for ErrorResponse {}
+
+pub(crate) fn error_response(error: &str, code: ErrorCode) -> Response {
+    ErrorResponse::new(code, error).into_response()
+}
+
+/// decodes a request body in the format given by its content type
+fn decode_body(content_type: Option<&str>, body: &[u8]) -> Result<Value, ErrorResponse> {
+    let encoding = Encoding::from_content_type(content_type).ok_or_else(|| {
+        ErrorResponse::new(ErrorCode::UnsupportedMediaType, "unsupported content type")
+    })?;
+    encoding.decode(body).map_err(|e| {
+        ErrorResponse::new(ErrorCode::InvalidBody, format!("invalid request body: {}", e))
+    })
+}
+
+/// decodes a value in one of the supported encodings, the bodies of other content types
+/// are stored as they are and keep their content type
+pub(crate) fn decode_value(
+    content_type: Option<String>,
+    body: &[u8],
+) -> Result<(Value, Option<String>), ErrorResponse> {
+    match content_type {
+        Some(content_type) if Encoding::from_content_type(Some(&content_type)).is_none() => {
+            Ok((Value::Bytes(body.to_vec()), Some(content_type)))
+        }
+        content_type => Ok((decode_body(content_type.as_deref(), body)?, None)),
+    }
+}
+
+/// turns every rejection into an error response, so clients always get the same json
+pub async fn recover(rejection: Rejection) -> Result<Response, Infallible> {
+    let error = if rejection.is_not_found() {
+        ErrorResponse::new(ErrorCode::NotFound, "unknown route")
+    } else if let Some(e) = rejection.find::<ErrorResponse>() {
+        e.clone()
+    } else if let Some(e) = rejection.find::<warp::body::BodyDeserializeError>() {
+        ErrorResponse::new(ErrorCode::InvalidBody, e.to_string())
+    } else if let Some(e) = rejection.find::<reject::PayloadTooLarge>() {
+        ErrorResponse::new(ErrorCode::PayloadTooLarge, e.to_string())
+    } else if let Some(e) = rejection.find::<reject::LengthRequired>() {
+        ErrorResponse::new(ErrorCode::LengthRequired, e.to_string())
+    } else if let Some(e) = rejection.find::<reject::UnsupportedMediaType>() {
+        ErrorResponse::new(ErrorCode::UnsupportedMediaType, e.to_string())
+    } else if let Some(e) = rejection.find::<reject::InvalidQuery>() {
+        ErrorResponse::new(ErrorCode::BadRequest, e.to_string())
+    } else if let Some(e) = rejection.find::<reject::MissingHeader>() {
+        ErrorResponse::new(ErrorCode::BadRequest, e.to_string())
+    } else if let Some(e) = rejection.find::<reject::InvalidHeader>() {
+        ErrorResponse::new(ErrorCode::BadRequest, e.to_string())
+    } else if let Some(e) = rejection.find::<warp::cors::CorsForbidden>() {
+        ErrorResponse::new(ErrorCode::Forbidden, e.to_string())
+    } else if let Some(e) = rejection.find::<reject::MethodNotAllowed>() {
+        // other routes reject with this as well, so it is checked last
+        ErrorResponse::new(ErrorCode::MethodNotAllowed, e.to_string())
+    } else {
+        error!("unhandled rejection: {:?}", rejection);
+        ErrorResponse::new(ErrorCode::Internal, "internal error")
+    };
+    Ok(error.into_response())
+}
+
+fn raft_error_response(error: RaftError) -> Response {
+    error_response(&error.to_string(), ErrorCode::Unavailable)
+}
+
+async fn consensus_enabled(cfg: &RuntimeConfigArc) -> bool {
+    cfg.read().await.consensus
+}
+
+async fn strong_setter(
+    name: String,
+    simple_map: Value,
+    forwarded: bool,
+    cache: Db,
+    meta: MetaDb,
+    cfg: RuntimeConfigArc,
+    raft: RaftArc,
+) -> Response {
+    let command = Command::Set(name.clone(), simple_map.clone());
+    match raft::propose(&raft, &cfg, &cache, &meta, command).await {
+        Ok(()) => ok_reponse().into_response(),
+        Err(RaftError::NotLeader(Some(leader))) if !forwarded => {
+            let mut client = Client::from_config(&*cfg.read().await);
+            match client.strong_set(leader, &name, &simple_map).await {
+                Ok(response) => reply::json(&response).into_response(),
+                Err(e) => error_response(&e.to_string(), ErrorCode::BadGateway),
+            }
+        }
+        Err(e) => raft_error_response(e),
+    }
+}
+
+async fn strong_getter(
+    name: String,
+    forwarded: bool,
+    encoding: Encoding,
+    cache: Db,
+    meta: MetaDb,
+    cfg: RuntimeConfigArc,
+    raft: RaftArc,
+) -> Response {
+    match raft::read_barrier(&raft, &cfg, &cache, &meta).await {
+        Ok(()) => {
+            let (value, metadata) = metadata::lookup(&cache, &meta, &name);
+            metrics::count_get(!metadata.deleted);
+            if forwarded {
+                // the follower answers in the format of its client, so it needs the metadata
+                let data = encoding::raw_to_json(value, Encoding::Json);
+                return reply::json(&VersionedResponse { data, meta: metadata }).into_response();
+            }
+            value_response(&value, metadata.content_type.as_deref(), encoding)
+        }
+        // the leader counts the read
+        Err(RaftError::NotLeader(Some(leader))) if !forwarded => {
+            let mut client = Client::from_config(&*cfg.read().await);
+            match client.strong_get(leader, &name).await {
+                Ok(response) => {
+                    value_response(&response.data, response.meta.content_type.as_deref(), encoding)
+                }
+                Err(e) => error_response(&e.to_string(), ErrorCode::BadGateway),
+            }
+        }
+        Err(e) => raft_error_response(e),
+    }
+}
+
+/// checks and writes a value through the raft log
+#[allow(clippy::too_many_arguments)]
+async fn strong_write(
     name: String,
     simple_map: Value,
+    content_type: Option<String>,
+    forwarded: bool,
     cache: Db,
+    meta: MetaDb,
+    cfg: RuntimeConfigArc,
+    raft: RaftArc,
+) -> Response {
+    if !consensus_enabled(&cfg).await {
+        return error_response("consensus mode is not enabled", ErrorCode::ConsensusDisabled);
+    }
+    if content_type.is_some() {
+        return error_response(
+            "raw values cannot be written with strong consistency",
+            ErrorCode::BadRequest,
+        );
+    }
+    strong_setter(name, simple_map, forwarded, cache, meta, cfg, raft).await
+}
+
+/// writes a value with any consistency but strong, returns its metadata
+/// and whether the key existed before
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn write_value(
+    name: String,
+    simple_map: Value,
+    content_type: Option<String>,
+    consistency: Consistency,
+    cache: &Db,
+    meta: &MetaDb,
+    cfg: &RuntimeConfigArc,
+    tx: &transport::Sender,
+) -> Result<(Metadata, bool), quorum::QuorumError> {
+    let metadata = Metadata {
+        version: metadata::new_version(),
+        content_type,
+        ..Default::default()
+    };
+    let existed = metadata::write(cache, meta, name.clone(), simple_map.clone(), metadata.clone());
+
+    if consistency == Consistency::One {
+        metadata::send(tx, name, simple_map, metadata.clone());
+        return Ok((metadata, existed));
+    }
+
+    quorum::write(cfg, &name, &simple_map, &metadata, consistency)
+        .await
+        .map(|_| (metadata, existed))
+}
+
+/// the write is kept on the nodes that have it, and the newest write wins,
+/// so the client can simply try again
+pub(crate) fn write_error(error: quorum::QuorumError) -> String {
+    format!("{}, the write may still be applied", error)
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn inner_setter(
+    name: String,
+    query: ConsistencyQuery,
+    content_type: Option<String>,
+    body: Bytes,
+    cache: Db,
+    meta: MetaDb,
+    cfg: RuntimeConfigArc,
     tx: transport::Sender,
+    raft: RaftArc,
+) -> Result<impl warp::Reply, Infallible> {
+    let (simple_map, content_type) = match decode_value(content_type, &body) {
+        Ok(x) => x,
+        Err(error) => return Ok(error.into_response()),
+    };
+    if query.consistency == Consistency::Strong {
+        let forwarded = query.forwarded;
+        let response =
+            strong_write(name, simple_map, content_type, forwarded, cache, meta, cfg, raft).await;
+        return Ok(response);
+    }
+
+    let written = write_value(
+        name,
+        simple_map,
+        content_type,
+        query.consistency,
+        &cache,
+        &meta,
+        &cfg,
+        &tx,
+    )
+    .await;
+    match written {
+        Ok(_) => Ok::<_, Infallible>(ok_reponse().into_response()),
+        Err(e) => Ok(error_response(&write_error(e), ErrorCode::Unavailable)),
+    }
+}
+
+async fn inner_getter(
+    name: String,
+    query: ConsistencyQuery,
+    accept: Option<String>,
+    cache: Db,
+    meta: MetaDb,
+    cfg: RuntimeConfigArc,
+    raft: RaftArc,
 ) -> Result<impl warp::Reply, Infallible> {
-    cache.insert(name.clone(), simple_map.clone());
-    // ignore the error, this will only return if no-one is listening.
-    tx.send(Message::Created(name, simple_map)).ok();
-    Ok::<_, Infallible>(ok_reponse())
+    let encoding = Encoding::from_accept(accept.as_deref());
+    match query.consistency {
+        Consistency::Strong => {
+            if !consensus_enabled(&cfg).await {
+                return Ok(error_response(
+                    "consensus mode is not enabled",
+                    ErrorCode::ConsensusDisabled,
+                ));
+            }
+            Ok(strong_getter(name, query.forwarded, encoding, cache, meta, cfg, raft).await)
+        }
+        Consistency::One => {
+            // expired keys are removed in the background, until then they are hidden
+            let value = metadata::get(&cache, &meta, &name);
+            metrics::count_get(value.is_some());
+            let response = match value {
+                Some((value, metadata)) => {
+                    value_response(&value, metadata.content_type.as_deref(), encoding)
+                }
+                None => data_response(&Value::Unit, encoding),
+            };
+            Ok::<_, Infallible>(response)
+        }
+        consistency => match quorum::read(&cache, &meta, &cfg, &name, consistency).await {
+            Ok(newest) if newest.meta.deleted || newest.meta.is_expired() => {
+                metrics::count_get(false);
+                Ok(data_response(&Value::Unit, encoding))
+            }
+            Ok(newest) => {
+                metrics::count_get(true);
+                Ok(value_response(
+                    &newest.data,
+                    newest.meta.content_type.as_deref(),
+                    encoding,
+                ))
+            }
+            Err(e) => Ok(error_response(
+                &e.to_string(),
+                ErrorCode::Unavailable,
+            )),
+        },
+    }
 }
 
-pub fn getter(cache: Db) -> BoxedFilter<(impl Reply,)> {
+pub fn getter(
+    cache: Db,
+    meta: MetaDb,
+    cfg: RuntimeConfigArc,
+    raft: RaftArc,
+) -> BoxedFilter<(impl Reply,)> {
     warp::path!("get" / String)
-        .map(move |name| match cache.get(&name) {
-            Some(x) => data_response(x.value()),
-            None => data_response(&Value::Unit),
-        })
+        .and(warp::post())
+        .and(warp::query::<ConsistencyQuery>())
+        .and(warp::header::optional::<String>("accept"))
+        .and(utils::move_object(cache))
+        .and(utils::move_object(meta))
+        .and(utils::move_object(cfg))
+        .and(utils::move_object(raft))
+        .and_then(inner_getter)
         .boxed()
 }
 
-pub fn deleter(cache: Db, tx: transport::Sender) -> BoxedFilter<(impl Reply,)> {
+pub fn deleter(cache: Db, meta: MetaDb, tx: transport::Sender) -> BoxedFilter<(impl Reply,)> {
     warp::path!("del" / String)
-        .map(move |name| {
-            let deleted = match cache.remove(&name) {
-                Some(_) => true,
-                None => false,
-            };
-            tx.send(Message::Deleted(name)).ok();
+        .and(warp::post())
+        .map(move |name: String| {
+            let deleted = metadata::remove(&cache, &meta, &tx, name);
             delete_response(deleted)
         })
         .boxed()
 }
 
-pub fn setter(cache: Db, tx: transport::Sender) -> BoxedFilter<(impl Reply,)> {
+pub fn setter(
+    cache: Db,
+    meta: MetaDb,
+    cfg: RuntimeConfigArc,
+    tx: transport::Sender,
+    raft: RaftArc,
+) -> BoxedFilter<(impl Reply,)> {
     warp::path!("set" / String)
+        .and(warp::post())
+        .and(warp::query::<ConsistencyQuery>())
         .and(warp::body::content_length_limit(MAX_FILE_SIZE))
-        .and(warp::body::json())
+        .and(warp::header::optional::<String>("content-type"))
+        .and(warp::body::bytes())
         .and(utils::move_object(cache))
+        .and(utils::move_object(meta))
+        .and(utils::move_object(cfg))
         .and(utils::move_object(tx))
+        .and(utils::move_object(raft))
         .and_then(inner_setter)
         .boxed()
 }
 
-pub fn purge(cache: Db) -> BoxedFilter<(impl Reply,)> {
+pub fn purge(cache: Db, meta: MetaDb) -> BoxedFilter<(impl Reply,)> {
     warp::path!("purge")
+        .and(warp::post())
         .map(move || {
-            cache.clear();
+            metadata::purge(&cache, &meta);
             warp::reply::json(&json!({ "purged": true }))
         })
         .boxed()
@@ -90,40 +465,91 @@ pub fn purge(cache: Db) -> BoxedFilter<(impl Reply,)> {
 
 pub fn ping() -> BoxedFilter<(impl Reply,)> {
     warp::path!("ping")
+        .and(warp::post())
         .map(|| warp::reply::json(&json!({ "pong": true })))
         .boxed()
 }
 
-pub fn keys(cache: Db) -> BoxedFilter<(impl Reply,)> {
+pub fn keys(cache: Db, meta: MetaDb) -> BoxedFilter<(impl Reply,)> {
     warp::path!("keys")
+        .and(warp::post())
         .map(move || {
-            let keys: HashSet<String> =
-                HashSet::from_iter(cache.iter().map(|item| item.key().clone()));
+            let keys: HashSet<String> = HashSet::from_iter(metadata::live_keys(&cache, &meta));
             warp::reply::json(&json!({ "keys": keys }))
         })
         .boxed()
 }
 
-pub fn internal(cache: Db, cfg: RuntimeConfigArc) -> BoxedFilter<(impl Reply,)> {
+pub fn metrics(cache: Db) -> BoxedFilter<(impl Reply,)> {
+    warp::path!("metrics")
+        .and(warp::get())
+        .map(move || {
+            reply::with_header(
+                metrics::gather(&cache),
+                "content-type",
+                "text/plain; version=0.0.4",
+            )
+        })
+        .boxed()
+}
+
+pub fn internal(
+    cache: Db,
+    meta: MetaDb,
+    cfg: RuntimeConfigArc,
+    tx: transport::Sender,
+    raft: RaftArc,
+) -> BoxedFilter<(impl Reply,)> {
     warp::path("_internal")
+        .and(warp::post())
         .and(
             internal::join(cfg.clone())
-                .or(internal::sync(cache.clone(), cfg.clone()))
-                .or(internal::update(cache.clone()))
+                .or(internal::sync(cache.clone(), meta.clone(), cfg.clone()))
+                .or(internal::changes(cache.clone(), meta.clone(), cfg.clone()))
+                .or(internal::update(cache.clone(), meta.clone(), tx))
+                .or(internal::get(cache.clone(), meta.clone()))
+                .or(internal::info(cache.clone(), meta.clone(), cfg.clone()))
                 .or(internal::fanout(cfg.clone()))
-                .or(internal::config(cfg.clone())),
+                .or(internal::config(cfg.clone()))
+                .or(internal::raft(cache.clone(), meta, raft)),
         )
         .boxed()
 }
 
+pub fn cluster(cache: Db, meta: MetaDb, cfg: RuntimeConfigArc) -> BoxedFilter<(impl Reply,)> {
+    cluster::status(cache, meta, cfg)
+}
+
+pub fn rest(
+    cache: Db,
+    meta: MetaDb,
+    cfg: RuntimeConfigArc,
+    tx: transport::Sender,
+    raft: RaftArc,
+) -> BoxedFilter<(impl Reply,)> {
+    rest::keys(cache, meta, cfg, tx, raft)
+}
+
+pub fn stats(cache: Db, cfg: RuntimeConfigArc) -> BoxedFilter<(impl Reply,)> {
+    stats::stats(cache, cfg)
+}
+
+pub fn admin(cfg: RuntimeConfigArc, slow_log: SlowLogArc) -> BoxedFilter<(impl Reply,)> {
+    warp::path("admin")
+        .and(warp::post())
+        .and(admin::slowlog(cfg.clone(), slow_log).or(admin::config(cfg)))
+        .boxed()
+}
+
 #[cfg(feature = "dashboard")]
 pub fn web() -> BoxedFilter<(impl Reply,)> {
     warp::path!("dashboard" / ..)
+        .and(warp::get())
         .map(|| {
             warp::reply::html(DASHBOARD)
         })
         .or(
-            warp::path!("favicon.ico").map(|| {
+            warp::path!("favicon.ico").and(warp::get()).map(|| {
                 warp::http::Response::builder()
                     .body(FAVICON)
             })
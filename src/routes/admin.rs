@@ -0,0 +1,93 @@
+use crate::access_log::SlowLogArc;
+use crate::config::{base64_sha3, ConfigUpdate, RuntimeConfigArc};
+use crate::responses::{AdminConfigResponse, ErrorCode, SlowLogResponse};
+use crate::routes::error_response;
+use crate::routes::utils::move_object;
+
+use std::convert::Infallible;
+use tracing::info;
+use warp::filters::BoxedFilter;
+use warp::http::header;
+use warp::reply::{self, Response};
+use warp::{Filter, Reply};
+
+/// `None` when the request carries the admin token, otherwise the response to send instead
+async fn unauthorized(cfg: &RuntimeConfigArc, authorization: Option<String>) -> Option<Response> {
+    let token = match cfg.read().await.admin_token.clone() {
+        Some(token) => token,
+        None => {
+            return Some(error_response(
+                "the admin api is disabled, start racher with --admin-token",
+                ErrorCode::Forbidden,
+            ))
+        }
+    };
+    let given = authorization
+        .as_deref()
+        .and_then(|x| x.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    // comparing the hashes keeps the time it takes independent of the token
+    if base64_sha3(given) == base64_sha3(&token) {
+        return None;
+    }
+    let response = error_response("invalid admin token", ErrorCode::Unauthorized);
+    Some(reply::with_header(response, header::WWW_AUTHENTICATE, "Bearer").into_response())
+}
+
+async fn inner_slowlog(
+    authorization: Option<String>,
+    cfg: RuntimeConfigArc,
+    slow_log: SlowLogArc,
+) -> Result<impl warp::Reply, Infallible> {
+    if let Some(response) = unauthorized(&cfg, authorization).await {
+        return Ok(response);
+    }
+    let entries = match slow_log.lock() {
+        Ok(slow_log) => slow_log.slowest(),
+        Err(_) => Vec::new(),
+    };
+    Ok(reply::json(&SlowLogResponse { entries }).into_response())
+}
+
+async fn inner_config(
+    authorization: Option<String>,
+    update: ConfigUpdate,
+    cfg: RuntimeConfigArc,
+) -> Result<impl warp::Reply, Infallible> {
+    if let Some(response) = unauthorized(&cfg, authorization).await {
+        return Ok(response);
+    }
+    if let Err(e) = update.validate() {
+        return Ok(error_response(&e, ErrorCode::BadRequest));
+    }
+
+    let mut write_cfg = cfg.write().await;
+    if update != ConfigUpdate::default() {
+        info!(?update, "config updated");
+    }
+    write_cfg.update(&update);
+    Ok(reply::json(&AdminConfigResponse {
+        backup_interval: write_cfg.backup_interval,
+        backup_amount: write_cfg.backup_amount,
+    })
+    .into_response())
+}
+
+pub fn slowlog(cfg: RuntimeConfigArc, slow_log: SlowLogArc) -> BoxedFilter<(impl Reply,)> {
+    warp::path!("slowlog")
+        .and(warp::header::optional::<String>("authorization"))
+        .and(move_object(cfg))
+        .and(move_object(slow_log))
+        .and_then(inner_slowlog)
+        .boxed()
+}
+
+pub fn config(cfg: RuntimeConfigArc) -> BoxedFilter<(impl Reply,)> {
+    warp::path!("config")
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::body::content_length_limit(1024 * 32))
+        .and(warp::body::json())
+        .and(move_object(cfg))
+        .and_then(inner_config)
+        .boxed()
+}
@@ -0,0 +1,112 @@
+use crate::client::Client;
+use crate::config::RuntimeConfigArc;
+use crate::metadata;
+use crate::responses::{ClusterStatusResponse, InfoResponse, MemberStatus};
+use crate::routes::utils::move_object;
+use crate::{Db, MetaDb};
+
+use chrono::{DateTime, Utc};
+use std::collections::HashSet;
+use std::convert::Infallible;
+use url::Url;
+use warp::filters::BoxedFilter;
+use warp::reply;
+use warp::{Filter, Reply};
+
+fn member_status(
+    address: Url,
+    info: Option<&InfoResponse>,
+    last_seen: Option<DateTime<Utc>>,
+    newest_version: u64,
+    now: DateTime<Utc>,
+) -> MemberStatus {
+    match info {
+        Some(info) => MemberStatus {
+            identifier: Some(info.identifier.clone()),
+            address,
+            up: true,
+            last_seen: Some(now),
+            keys: Some(info.keys),
+            version_gap_ms: Some(newest_version.saturating_sub(info.version) / 1000),
+            backup_age_secs: info.last_backup.map(|time| (now - time).num_seconds()),
+        },
+        None => MemberStatus {
+            identifier: None,
+            address,
+            up: false,
+            last_seen,
+            keys: None,
+            version_gap_ms: None,
+            backup_age_secs: None,
+        },
+    }
+}
+
+async fn inner_status(
+    cache: Db,
+    meta: MetaDb,
+    cfg: RuntimeConfigArc,
+) -> Result<impl warp::Reply, Infallible> {
+    let (me, members) = {
+        let guard = cfg.read().await;
+        let me = InfoResponse {
+            identifier: guard.identifier.clone(),
+            code: guard.base_code.clone(),
+            address: guard.external_address.clone(),
+            neighbours: guard.neighbours.iter().cloned().collect(),
+            keys: cache.len(),
+            version: metadata::latest_version(&meta),
+            last_backup: guard.last_backup.as_ref().map(|backup| backup.time),
+        };
+        let mut members: HashSet<Url> = guard.neighbours.union(&guard.seeds).cloned().collect();
+        members.extend(guard.last_seen.keys().cloned());
+        members.remove(&me.address);
+        (me, members)
+    };
+
+    let mut client = Client::from_config(&*cfg.read().await);
+    let infos = client.info_all(members.clone()).await;
+    let now = Utc::now();
+    let newest_version = infos
+        .iter()
+        .map(|(_, info)| info.version)
+        .fold(me.version, u64::max);
+
+    let last_seen = {
+        let mut guard = cfg.write().await;
+        for (address, _) in infos.iter() {
+            guard.last_seen.insert(address.clone(), now);
+        }
+        guard.last_seen.clone()
+    };
+
+    let mut statuses = vec![member_status(
+        me.address.clone(),
+        Some(&me),
+        None,
+        newest_version,
+        now,
+    )];
+    let mut members: Vec<Url> = members.into_iter().collect();
+    members.sort();
+    for address in members {
+        let info = infos
+            .iter()
+            .find(|(x, _)| x == &address)
+            .map(|(_, info)| info);
+        let seen = last_seen.get(&address).cloned();
+        statuses.push(member_status(address, info, seen, newest_version, now));
+    }
+
+    Ok(reply::json(&ClusterStatusResponse { members: statuses }))
+}
+
+pub fn status(cache: Db, meta: MetaDb, cfg: RuntimeConfigArc) -> BoxedFilter<(impl Reply,)> {
+    warp::path!("cluster" / "status")
+        .and(warp::post())
+        .and(move_object(cache))
+        .and(move_object(meta))
+        .and(move_object(cfg))
+        .and_then(inner_status)
+        .boxed()
+}
@@ -1,10 +1,18 @@
 use crate::client::Client;
 use crate::config::RuntimeConfigArc;
+use crate::encoding::{self, Encoding};
+use crate::metadata::{self, Metadata};
+use crate::raft::{AppendRequest, RaftArc, VoteRequest};
+use crate::responses::{Change, ChangesResponse, ErrorCode, InfoResponse, VersionedResponse};
 use crate::routes::utils::move_object;
-use crate::Db;
+use crate::transport::{self, Message};
+use crate::{Db, MetaDb};
 
-use warp::http::StatusCode;
+use warp::http::{header, StatusCode};
+use warp::hyper::body::Bytes;
+use warp::hyper::Body;
 
+use futures::{future, stream};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use serde_value::Value;
@@ -16,10 +24,14 @@ use warp::filters::BoxedFilter;
 use warp::reply;
 use warp::{Filter, Reply};
 
+use std::collections::BinaryHeap;
 use std::iter::FromIterator;
 
 use crate::MAX_FILE_SIZE;
 
+/// amount of keys that are serialized into one chunk of the sync stream
+const SYNC_BATCH_SIZE: usize = 256;
+
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct JoinRequest {
     host: Url,
@@ -28,6 +40,26 @@ pub struct JoinRequest {
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct SyncRequest {
     code: String,
+    /// only keys that sort after this one are sent, used to resume a broken transfer
+    #[serde(default)]
+    after: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ChangesRequest {
+    code: String,
+    since: u64,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct UpdateQuery {
+    version: Option<u64>,
+    /// only set for raw values
+    content_type: Option<String>,
+    expires_at: Option<u64>,
+    flags: Option<u32>,
+    #[serde(default)]
+    deleted: bool,
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
@@ -37,7 +69,7 @@ pub struct FanoutRequest {
 }
 
 async fn fanout_to_neighbours(cfg: RuntimeConfigArc, host: Url) -> Result<(), String> {
-    let mut client = Client::new();
+    let mut client = Client::from_config(&*cfg.read().await);
     // if let Err(_) = client.ping(host.clone()).await {
     //      error!("host '{}' cannot be found", host);
     //      return Err(String::from("invalid host"))
@@ -63,47 +95,129 @@ async fn inner_join(
     cfg: RuntimeConfigArc,
 ) -> Result<impl warp::Reply, Infallible> {
     if req.host.cannot_be_a_base() {
-        return Ok(reply::with_status(
-            reply::json(&json!({ "error": "invalid host" })),
-            StatusCode::BAD_REQUEST,
-        ));
+        return Ok(super::error_response("invalid host", ErrorCode::BadRequest));
     }
 
     if let Err(txt) = fanout_to_neighbours(cfg.clone(), req.host.clone()).await {
-        return Ok(reply::with_status(
-            reply::json(&json!({ "error": txt })),
-            StatusCode::BAD_REQUEST,
-        ));
+        return Ok(super::error_response(&txt, ErrorCode::BadRequest));
     }
 
     let (code, neighbours) = {
         let mut guard = cfg.write().await;
-        guard.neighbours.insert(req.host);
+        guard.add_neighbour(req.host);
         (
             guard.base_code.clone(),
             Vec::from_iter(guard.neighbours.clone().into_iter()),
         )
     };
-    Ok(reply::with_status(
-        reply::json(&json!({ "code": code, "neighbours": neighbours })),
-        StatusCode::OK,
-    ))
+    Ok(reply::json(&json!({ "code": code, "neighbours": neighbours })).into_response())
+}
+
+/// the first `SYNC_BATCH_SIZE` keys that sort after `after`. the cache is scanned for
+/// every batch, so only the keys of one batch are held at a time
+fn next_batch(cache: &Db, after: Option<&str>) -> Vec<String> {
+    let mut batch: BinaryHeap<String> = BinaryHeap::with_capacity(SYNC_BATCH_SIZE);
+    for item in cache.iter() {
+        let key = item.key();
+        if after.is_some_and(|after| key.as_str() <= after) {
+            continue;
+        }
+        if batch.len() == SYNC_BATCH_SIZE {
+            // the heap keeps the largest key on top, it makes room for smaller ones
+            if batch.peek().is_some_and(|largest| key >= largest) {
+                continue;
+            }
+            batch.pop();
+        }
+        batch.push(key.clone());
+    }
+    batch.into_sorted_vec()
 }
 
 async fn inner_sync(
     req: SyncRequest,
+    accept: Option<String>,
     cache: Db,
+    meta: MetaDb,
     cfg: RuntimeConfigArc,
 ) -> Result<impl warp::Reply, Infallible> {
-    let guard = cfg.read().await;
-    if guard.base_code == req.code {
-        return Ok(reply::with_status(reply::json(&cache), StatusCode::OK));
+    if cfg.read().await.base_code != req.code {
+        return Ok(super::error_response("invalid code", ErrorCode::BadRequest));
     }
 
-    Ok(reply::with_status(
-        reply::json(&json!({"error": "invalid code"})),
-        StatusCode::BAD_REQUEST,
-    ))
+    let encoding = Encoding::from_accept(accept.as_deref());
+    // the keys are sent in order batch by batch while the body is sent, so the whole cache
+    // never has to fit in one buffer. `None` once the last batch was sent
+    let stream = stream::unfold(Some(req.after), move |after| {
+        let after = match after {
+            Some(after) => after,
+            None => return future::ready(None),
+        };
+        let batch = next_batch(&cache, after.as_deref());
+        let mut buffer = Vec::new();
+        for key in batch.iter() {
+            // keys deleted in the meantime are skipped
+            if let Some(value) = cache.get(key) {
+                let change = Change {
+                    key: key.clone(),
+                    data: encoding::raw_to_json(value.value().clone(), encoding),
+                    meta: meta.get(key).map(|x| x.clone()).unwrap_or_default(),
+                };
+                if let Err(e) = encoding.write_frame(&mut buffer, &change) {
+                    error!(%e, "could not serialize key '{}'", key);
+                }
+            }
+        }
+        if batch.is_empty() {
+            return future::ready(None);
+        }
+        // a batch that is not full was the last one
+        let next = match batch.len() {
+            SYNC_BATCH_SIZE => Some(batch.last().cloned()),
+            _ => None,
+        };
+        future::ready(Some((Ok::<_, Infallible>(buffer), next)))
+    });
+
+    let response = warp::http::Response::builder()
+        .header(header::CONTENT_TYPE, encoding.stream_content_type())
+        .body(Body::wrap_stream(stream));
+    Ok(match response {
+        Ok(response) => response,
+        Err(e) => super::error_response(&e.to_string(), ErrorCode::Internal),
+    })
+}
+
+async fn inner_changes(
+    req: ChangesRequest,
+    accept: Option<String>,
+    cache: Db,
+    meta: MetaDb,
+    cfg: RuntimeConfigArc,
+) -> Result<impl warp::Reply, Infallible> {
+    if cfg.read().await.base_code != req.code {
+        return Ok(super::error_response("invalid code", ErrorCode::BadRequest));
+    }
+
+    let encoding = Encoding::from_accept(accept.as_deref());
+    // deleted keys keep their metadata, so they are sent along as unit
+    let changes = meta
+        .iter()
+        .filter(|item| item.version > req.since)
+        .map(|item| {
+            let data = cache.get(item.key()).map(|x| x.value().clone());
+            let meta = Metadata {
+                deleted: data.is_none(),
+                ..item.value().clone()
+            };
+            Change {
+                key: item.key().clone(),
+                data: encoding::raw_to_json(data.unwrap_or(Value::Unit), encoding),
+                meta,
+            }
+        })
+        .collect();
+    Ok(encoding.reply(&ChangesResponse { changes }))
 }
 
 async fn inner_fanout(
@@ -126,33 +240,88 @@ async fn inner_fanout(
 
     if base_code == req.code {
         let mut guard = cfg.write().await;
-        guard.neighbours.insert(req.host);
-        return Ok(reply::with_status(
-            reply::json(&json!({"fanout": "success"})),
-            StatusCode::OK,
-        ));
+        guard.add_neighbour(req.host);
+        return Ok(reply::json(&json!({"fanout": "success"})).into_response());
     }
 
-    Ok(reply::with_status(
-        reply::json(&json!({"error": "invalid code"})),
-        StatusCode::BAD_REQUEST,
-    ))
+    Ok(super::error_response("invalid code", ErrorCode::BadRequest))
 }
 
 async fn inner_update(
     name: String,
-    simple_map: Value,
+    query: UpdateQuery,
+    content_type: Option<String>,
+    body: Bytes,
     cache: Db,
+    meta: MetaDb,
+    tx: transport::Sender,
 ) -> Result<impl warp::Reply, Infallible> {
-    match &simple_map {
-        Value::Unit => {
-            cache.remove(&name);
+    let simple_map = match super::decode_body(content_type.as_deref(), &body) {
+        Ok(x) => x,
+        Err(error) => return Ok(error.into_response()),
+    };
+    let simple_map = match Encoding::from_content_type(content_type.as_deref()) {
+        Some(encoding) => {
+            encoding::raw_from_json(simple_map, query.content_type.as_deref(), encoding)
         }
-        _ => {
-            cache.insert(name, simple_map);
+        None => simple_map,
+    };
+    // updates from nodes that do not send a version always win
+    let metadata = Metadata {
+        version: query.version.unwrap_or_else(metadata::new_version),
+        content_type: query.content_type,
+        expires_at: query.expires_at,
+        flags: query.flags,
+        deleted: query.deleted,
+    };
+    if metadata::apply(
+        &cache,
+        &meta,
+        name.clone(),
+        simple_map.clone(),
+        metadata.clone(),
+    ) {
+        // only for watchers, the node that sent it replicates it
+        tx.send(Message::Updated(name, simple_map, metadata)).ok();
+    } else {
+        debug!("ignored stale update of key '{}'", name);
+    }
+    Ok::<_, Infallible>(super::ok_reponse().into_response())
+}
+
+async fn inner_get(
+    name: String,
+    accept: Option<String>,
+    cache: Db,
+    meta: MetaDb,
+) -> Result<impl warp::Reply, Infallible> {
+    let encoding = Encoding::from_accept(accept.as_deref());
+    let (data, meta) = metadata::lookup(&cache, &meta, &name);
+    let response = VersionedResponse {
+        data: encoding::raw_to_json(data, encoding),
+        meta,
+    };
+    Ok(encoding.reply(&response))
+}
+
+async fn inner_info(
+    cache: Db,
+    meta: MetaDb,
+    cfg: RuntimeConfigArc,
+) -> Result<impl warp::Reply, Infallible> {
+    let response = {
+        let guard = cfg.read().await;
+        InfoResponse {
+            identifier: guard.identifier.clone(),
+            code: guard.base_code.clone(),
+            address: guard.external_address.clone(),
+            neighbours: Vec::from_iter(guard.neighbours.clone()),
+            keys: cache.len(),
+            version: metadata::latest_version(&meta),
+            last_backup: guard.last_backup.as_ref().map(|backup| backup.time),
         }
     };
-    Ok::<_, Infallible>(super::ok_reponse())
+    Ok(reply::json(&response))
 }
 
 pub async fn inner_config(cfg: RuntimeConfigArc) -> Result<impl warp::Reply, Infallible> {
@@ -163,6 +332,21 @@ pub async fn inner_config(cfg: RuntimeConfigArc) -> Result<impl warp::Reply, Inf
     ))
 }
 
+async fn inner_raft_vote(req: VoteRequest, raft: RaftArc) -> Result<impl warp::Reply, Infallible> {
+    let response = raft.write().await.handle_vote(req).await;
+    Ok(reply::json(&response))
+}
+
+async fn inner_raft_append(
+    req: AppendRequest,
+    cache: Db,
+    meta: MetaDb,
+    raft: RaftArc,
+) -> Result<impl warp::Reply, Infallible> {
+    let response = raft.write().await.handle_append(req, &cache, &meta).await;
+    Ok(reply::json(&response))
+}
+
 pub fn join(cfg: RuntimeConfigArc) -> BoxedFilter<(impl Reply,)> {
     warp::path!("join")
         .and(warp::body::content_length_limit(1024 * 32))
@@ -178,10 +362,11 @@ pub fn join(cfg: RuntimeConfigArc) -> BoxedFilter<(impl Reply,)> {
         .boxed()
 }
 
-pub fn sync(cache: Db, cfg: RuntimeConfigArc) -> BoxedFilter<(impl Reply,)> {
+pub fn sync(cache: Db, meta: MetaDb, cfg: RuntimeConfigArc) -> BoxedFilter<(impl Reply,)> {
     warp::path!("sync")
         .and(warp::body::content_length_limit(1024 * 32))
         .and(warp::body::json())
+        .and(warp::header::optional::<String>("accept"))
         // .map(move |req: SyncRequest| {
         //     let guard = block_on(cfg.read());
         //     if guard.base_code == req.code {
@@ -193,21 +378,47 @@ pub fn sync(cache: Db, cfg: RuntimeConfigArc) -> BoxedFilter<(impl Reply,)> {
         //     }
         // })
         .and(move_object(cache))
+        .and(move_object(meta))
         .and(move_object(cfg))
         .and_then(inner_sync)
         .with(warp::compression::gzip())
         .boxed()
 }
 
-pub fn update(cache: Db) -> BoxedFilter<(impl Reply,)> {
+pub fn changes(cache: Db, meta: MetaDb, cfg: RuntimeConfigArc) -> BoxedFilter<(impl Reply,)> {
+    warp::path!("changes")
+        .and(warp::body::content_length_limit(1024 * 32))
+        .and(warp::body::json())
+        .and(warp::header::optional::<String>("accept"))
+        .and(move_object(cache))
+        .and(move_object(meta))
+        .and(move_object(cfg))
+        .and_then(inner_changes)
+        .boxed()
+}
+
+pub fn update(cache: Db, meta: MetaDb, tx: transport::Sender) -> BoxedFilter<(impl Reply,)> {
     warp::path!("update" / String)
+        .and(warp::query::<UpdateQuery>())
         .and(warp::body::content_length_limit(MAX_FILE_SIZE))
-        .and(warp::body::json())
+        .and(warp::header::optional::<String>("content-type"))
+        .and(warp::body::bytes())
         .and(move_object(cache))
+        .and(move_object(meta))
+        .and(move_object(tx))
         .and_then(inner_update)
         .boxed()
 }
 
+pub fn get(cache: Db, meta: MetaDb) -> BoxedFilter<(impl Reply,)> {
+    warp::path!("get" / String)
+        .and(warp::header::optional::<String>("accept"))
+        .and(move_object(cache))
+        .and(move_object(meta))
+        .and_then(inner_get)
+        .boxed()
+}
+
 pub fn fanout(cfg: RuntimeConfigArc) -> BoxedFilter<(impl Reply,)> {
     warp::path!("fanout")
         .and(warp::body::json())
@@ -216,9 +427,36 @@ pub fn fanout(cfg: RuntimeConfigArc) -> BoxedFilter<(impl Reply,)> {
         .boxed()
 }
 
+pub fn info(cache: Db, meta: MetaDb, cfg: RuntimeConfigArc) -> BoxedFilter<(impl Reply,)> {
+    warp::path!("info")
+        .and(move_object(cache))
+        .and(move_object(meta))
+        .and(move_object(cfg))
+        .and_then(inner_info)
+        .boxed()
+}
+
 pub fn config(cfg: RuntimeConfigArc) -> BoxedFilter<(impl Reply,)> {
     warp::path!("config")
         .and(move_object(cfg))
         .and_then(inner_config)
         .boxed()
 }
+
+pub fn raft(cache: Db, meta: MetaDb, raft: RaftArc) -> BoxedFilter<(impl Reply,)> {
+    let vote = warp::path!("raft" / "vote")
+        .and(warp::body::content_length_limit(1024 * 32))
+        .and(warp::body::json())
+        .and(move_object(raft.clone()))
+        .and_then(inner_raft_vote);
+
+    let append = warp::path!("raft" / "append")
+        .and(warp::body::content_length_limit(MAX_FILE_SIZE))
+        .and(warp::body::json())
+        .and(move_object(cache))
+        .and(move_object(meta))
+        .and(move_object(raft))
+        .and_then(inner_raft_append);
+
+    vote.or(append).boxed()
+}
@@ -0,0 +1,283 @@
+use crate::client::Client;
+use crate::config::RuntimeConfigArc;
+use crate::encoding::Encoding;
+use crate::metadata::{self, Metadata};
+use crate::metrics;
+use crate::quorum;
+use crate::raft::{self, RaftArc, RaftError};
+use crate::responses::ErrorCode;
+use crate::routes::utils::move_object;
+use crate::routes::{Consistency, ConsistencyQuery};
+use crate::transport;
+use crate::{Db, MetaDb, MAX_FILE_SIZE};
+
+use serde_value::Value;
+use std::convert::Infallible;
+use warp::filters::BoxedFilter;
+use warp::http::header::{self, HeaderValue};
+use warp::http::StatusCode;
+use warp::hyper::body::{Body, Bytes, HttpBody};
+use warp::reply::{self, Response};
+use warp::{Filter, Reply};
+
+/// values can change at any moment, so caches have to check the etag before using them
+const CACHE_CONTROL: &str = "no-cache";
+
+fn etag(version: u64) -> String {
+    format!("\"{}\"", version)
+}
+
+/// whether one of the etags of an `If-None-Match` header matches, weak ones included
+fn none_match(if_none_match: Option<&str>, etag: &str) -> bool {
+    if_none_match
+        .into_iter()
+        .flat_map(|x| x.split(','))
+        .map(|x| x.trim())
+        .any(|x| x == "*" || x.trim_start_matches("W/") == etag)
+}
+
+/// adds the cache headers, keys without a version, like the ones of backups from before
+/// versions were kept, get no etag
+fn with_cache_headers(mut response: Response, version: u64) -> Response {
+    let headers = response.headers_mut();
+    headers.insert(
+        header::CACHE_CONTROL,
+        HeaderValue::from_static(CACHE_CONTROL),
+    );
+    if version > 0 {
+        if let Ok(etag) = HeaderValue::from_str(&etag(version)) {
+            headers.insert(header::ETAG, etag);
+        }
+    }
+    response
+}
+
+/// responses to `HEAD` keep their headers and the length the body would have had
+fn without_body(mut response: Response) -> Response {
+    if let Some(length) = response.body().size_hint().exact() {
+        response
+            .headers_mut()
+            .insert(header::CONTENT_LENGTH, HeaderValue::from(length));
+    }
+    *response.body_mut() = Body::empty();
+    response
+}
+
+/// the value itself, raw values with their content type and everything else
+/// in the requested encoding
+fn body_response(value: &Value, content_type: Option<&str>, encoding: Encoding) -> Response {
+    match (value, content_type) {
+        (Value::Bytes(bytes), Some(content_type)) => {
+            reply::with_header(bytes.clone(), header::CONTENT_TYPE, content_type).into_response()
+        }
+        _ => encoding.reply(value),
+    }
+}
+
+fn not_found() -> Response {
+    super::error_response("key not found", ErrorCode::NotFound)
+}
+
+/// the value and metadata of a key, `None` when it does not exist
+async fn lookup(
+    name: &str,
+    query: &ConsistencyQuery,
+    cache: &Db,
+    meta: &MetaDb,
+    cfg: &RuntimeConfigArc,
+    raft: &RaftArc,
+) -> Result<Option<(Value, Metadata)>, Response> {
+    match query.consistency {
+        Consistency::One => {
+            let value = metadata::get(cache, meta, name);
+            metrics::count_get(value.is_some());
+            Ok(value)
+        }
+        Consistency::Strong => {
+            if !super::consensus_enabled(cfg).await {
+                return Err(super::error_response(
+                    "consensus mode is not enabled",
+                    ErrorCode::ConsensusDisabled,
+                ));
+            }
+            match raft::read_barrier(raft, cfg, cache, meta).await {
+                Ok(()) => {
+                    let value = cache.get(name).map(|x| x.value().clone());
+                    metrics::count_get(value.is_some());
+                    let metadata = meta.get(name).map(|x| x.clone()).unwrap_or_default();
+                    Ok(value.map(|value| (value, metadata)))
+                }
+                Err(RaftError::NotLeader(Some(leader))) if !query.forwarded => {
+                    let mut client = Client::from_config(&*cfg.read().await);
+                    match client.strong_get(leader, name).await {
+                        Ok(response) if response.meta.deleted => Ok(None),
+                        Ok(response) => Ok(Some((response.data, response.meta))),
+                        Err(e) => Err(super::error_response(&e.to_string(), ErrorCode::BadGateway)),
+                    }
+                }
+                Err(e) => Err(super::raft_error_response(e)),
+            }
+        }
+        consistency => match quorum::read(cache, meta, cfg, name, consistency).await {
+            Ok(newest) if newest.meta.deleted || newest.meta.is_expired() => {
+                metrics::count_get(false);
+                Ok(None)
+            }
+            Ok(newest) => {
+                metrics::count_get(true);
+                Ok(Some((newest.data, newest.meta)))
+            }
+            Err(e) => Err(super::error_response(
+                &e.to_string(),
+                ErrorCode::Unavailable,
+            )),
+        },
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn inner_get(
+    name: String,
+    head: bool,
+    query: ConsistencyQuery,
+    accept: Option<String>,
+    if_none_match: Option<String>,
+    cache: Db,
+    meta: MetaDb,
+    cfg: RuntimeConfigArc,
+    raft: RaftArc,
+) -> Result<impl warp::Reply, Infallible> {
+    let encoding = Encoding::from_accept(accept.as_deref());
+    let response = match lookup(&name, &query, &cache, &meta, &cfg, &raft).await {
+        Ok(Some((_, metadata)))
+            if metadata.version > 0
+                && none_match(if_none_match.as_deref(), &etag(metadata.version)) =>
+        {
+            with_cache_headers(StatusCode::NOT_MODIFIED.into_response(), metadata.version)
+        }
+        Ok(Some((value, metadata))) => with_cache_headers(
+            body_response(&value, metadata.content_type.as_deref(), encoding),
+            metadata.version,
+        ),
+        Ok(None) => with_cache_headers(not_found(), 0),
+        Err(response) => response,
+    };
+    if head {
+        return Ok(without_body(response));
+    }
+    Ok(response)
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn inner_put(
+    name: String,
+    query: ConsistencyQuery,
+    content_type: Option<String>,
+    body: Bytes,
+    cache: Db,
+    meta: MetaDb,
+    cfg: RuntimeConfigArc,
+    tx: transport::Sender,
+    raft: RaftArc,
+) -> Result<impl warp::Reply, Infallible> {
+    let (simple_map, content_type) = match super::decode_value(content_type, &body) {
+        Ok(x) => x,
+        Err(error) => return Ok(error.into_response()),
+    };
+    if query.consistency == Consistency::Strong {
+        let forwarded = query.forwarded;
+        let response = super::strong_write(
+            name,
+            simple_map,
+            content_type,
+            forwarded,
+            cache,
+            meta,
+            cfg,
+            raft,
+        )
+        .await;
+        return Ok(response);
+    }
+
+    let written = super::write_value(
+        name,
+        simple_map,
+        content_type,
+        query.consistency,
+        &cache,
+        &meta,
+        &cfg,
+        &tx,
+    )
+    .await;
+    match written {
+        Ok((metadata, existed)) => {
+            let status = if existed {
+                StatusCode::NO_CONTENT
+            } else {
+                StatusCode::CREATED
+            };
+            Ok(with_cache_headers(status.into_response(), metadata.version))
+        }
+        Err(e) => Ok(super::error_response(
+            &super::write_error(e),
+            ErrorCode::Unavailable,
+        )),
+    }
+}
+
+fn inner_delete(name: String, cache: Db, meta: MetaDb, tx: transport::Sender) -> Response {
+    if metadata::remove(&cache, &meta, &tx, name) {
+        return StatusCode::NO_CONTENT.into_response();
+    }
+    not_found()
+}
+
+/// `GET`, `HEAD`, `PUT` and `DELETE` on `/keys/:name`
+pub fn keys(
+    cache: Db,
+    meta: MetaDb,
+    cfg: RuntimeConfigArc,
+    tx: transport::Sender,
+    raft: RaftArc,
+) -> BoxedFilter<(impl Reply,)> {
+    // the path comes first, so requests to other routes are not answered with a 405
+    let read = warp::path!("keys" / String)
+        .and(
+            warp::get()
+                .map(|| false)
+                .or(warp::head().map(|| true))
+                .unify(),
+        )
+        .and(warp::query::<ConsistencyQuery>())
+        .and(warp::header::optional::<String>("accept"))
+        .and(warp::header::optional::<String>("if-none-match"))
+        .and(move_object(cache.clone()))
+        .and(move_object(meta.clone()))
+        .and(move_object(cfg.clone()))
+        .and(move_object(raft.clone()))
+        .and_then(inner_get);
+
+    let write = warp::path!("keys" / String)
+        .and(warp::put())
+        .and(warp::query::<ConsistencyQuery>())
+        .and(warp::body::content_length_limit(MAX_FILE_SIZE))
+        .and(warp::header::optional::<String>("content-type"))
+        .and(warp::body::bytes())
+        .and(move_object(cache.clone()))
+        .and(move_object(meta.clone()))
+        .and(move_object(cfg))
+        .and(move_object(tx.clone()))
+        .and(move_object(raft))
+        .and_then(inner_put);
+
+    let delete = warp::path!("keys" / String)
+        .and(warp::delete())
+        .and(move_object(cache))
+        .and(move_object(meta))
+        .and(move_object(tx))
+        .map(inner_delete);
+
+    read.or(write).or(delete).boxed()
+}
@@ -0,0 +1,48 @@
+use crate::config::RuntimeConfigArc;
+use crate::metrics;
+use crate::responses::{LimitsResponse, StatsResponse};
+use crate::routes::utils::move_object;
+use crate::Db;
+
+use chrono::Utc;
+use std::convert::Infallible;
+use warp::filters::BoxedFilter;
+use warp::reply;
+use warp::{Filter, Reply};
+
+async fn inner_stats(cache: Db, cfg: RuntimeConfigArc) -> Result<impl warp::Reply, Infallible> {
+    let hits = metrics::GETS.with_label_values(&["hit"]).get();
+    let misses = metrics::GETS.with_label_values(&["miss"]).get();
+    let hit_ratio = match hits + misses {
+        0 => None,
+        total => Some(hits as f64 / total as f64),
+    };
+
+    let guard = cfg.read().await;
+    Ok(reply::json(&StatsResponse {
+        keys: cache.len(),
+        memory_bytes: cache.bytes(),
+        hits,
+        misses,
+        hit_ratio,
+        uptime_secs: (Utc::now() - guard.started).num_seconds(),
+        last_backup: guard.last_backup.as_ref().map(|backup| backup.time),
+        last_backup_bytes: guard.last_backup.as_ref().map(|backup| backup.size),
+        limits: LimitsResponse {
+            max_value_bytes: crate::MAX_FILE_SIZE,
+            max_concurrent_requests: guard.max_concurrent_requests,
+            request_timeout_ms: guard.request_timeout,
+            backup_interval_secs: guard.backup_interval,
+            backup_amount: guard.backup_amount,
+        },
+    }))
+}
+
+pub fn stats(cache: Db, cfg: RuntimeConfigArc) -> BoxedFilter<(impl Reply,)> {
+    warp::path!("stats")
+        .and(warp::post())
+        .and(move_object(cache))
+        .and(move_object(cfg))
+        .and_then(inner_stats)
+        .boxed()
+}
@@ -0,0 +1,296 @@
+use crate::client::Client;
+use crate::routes::Consistency;
+
+use std::error::Error;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use rustyline::completion::{Completer, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::{ValidationContext, ValidationResult, Validator};
+use rustyline::{Context, Editor, Helper};
+use serde_value::Value;
+use url::Url;
+
+const COMMANDS: &[&str] = &[
+    "get",
+    "set",
+    "del",
+    "keys",
+    "purge",
+    "ping",
+    "status",
+    "nodes",
+    "use",
+    "consistency",
+    "help",
+    "exit",
+    "quit",
+];
+
+const HELP: &str = "\
+get <key>              print the value of a key
+set <key> <json>       set a key, the value can span multiple lines
+del <key>              delete a key
+keys                   list all keys
+purge                  delete every key
+ping                   check if the node is reachable
+status                 print the status of the cluster
+nodes                  list the nodes this shell talks to
+use <index|address>    switch to another node
+consistency [level]    print or change the consistency (one, quorum, all, strong)
+help                   print this message
+exit, quit             leave the shell";
+
+/// keys used for completion are fetched again after this long, or after a command changed them
+const KEYS_MAX_AGE: Duration = Duration::from_secs(30);
+
+/// completes commands, key names and node addresses
+pub(crate) struct ShellHelper {
+    pub(crate) keys: Vec<String>,
+    pub(crate) nodes: Vec<Url>,
+}
+
+fn candidates<'a>(word: &str, options: impl Iterator<Item = &'a str>) -> Vec<Pair> {
+    options
+        .filter(|option| option.starts_with(word))
+        .map(|option| Pair {
+            display: option.to_string(),
+            replacement: option.to_string(),
+        })
+        .collect()
+}
+
+impl Completer for ShellHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let start = line[..pos].rfind(' ').map(|x| x + 1).unwrap_or(0);
+        let word = &line[start..pos];
+        let previous: Vec<&str> = line[..start].split_whitespace().collect();
+
+        let pairs = match previous.as_slice() {
+            [] => candidates(word, COMMANDS.iter().copied()),
+            ["get"] | ["set"] | ["del"] => candidates(word, self.keys.iter().map(|x| x.as_str())),
+            ["use"] => candidates(word, self.nodes.iter().map(|x| x.as_str())),
+            ["consistency"] => candidates(word, ["one", "quorum", "all", "strong"].iter().copied()),
+            _ => Vec::new(),
+        };
+        Ok((start, pairs))
+    }
+}
+
+impl Validator for ShellHelper {
+    /// keeps reading lines while the json value of a `set` is not finished
+    fn validate(&self, ctx: &mut ValidationContext<'_>) -> rustyline::Result<ValidationResult> {
+        let mut parts = ctx.input().trim_start().splitn(3, char::is_whitespace);
+        if let (Some("set"), Some(_), Some(value)) = (parts.next(), parts.next(), parts.next()) {
+            if let Err(e) = serde_json::from_str::<serde_json::Value>(value) {
+                if e.is_eof() {
+                    return Ok(ValidationResult::Incomplete);
+                }
+            }
+        }
+        Ok(ValidationResult::Valid(None))
+    }
+}
+
+impl Hinter for ShellHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ShellHelper {}
+
+impl Helper for ShellHelper {}
+
+fn history_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".racher_history"))
+}
+
+pub(crate) struct Shell {
+    client: Client,
+    pub(crate) nodes: Vec<Url>,
+    pub(crate) current: usize,
+    pub(crate) consistency: Consistency,
+    /// keys of the current node for completion and when they were fetched
+    keys: Option<(Vec<String>, Instant)>,
+}
+
+impl Shell {
+    pub(crate) fn new(nodes: Vec<Url>) -> Self {
+        Shell {
+            client: Client::new(),
+            nodes,
+            current: 0,
+            consistency: Consistency::default(),
+            keys: None,
+        }
+    }
+
+    fn node(&self) -> Url {
+        self.nodes[self.current].clone()
+    }
+
+    /// the keys for completion, only fetched when they are missing or old. a node that
+    /// cannot be reached gives no keys until then, so the prompt does not wait for it every time
+    pub(crate) async fn keys(&mut self) -> Vec<String> {
+        match &self.keys {
+            Some((keys, fetched)) if fetched.elapsed() < KEYS_MAX_AGE => keys.clone(),
+            _ => {
+                let node = self.node();
+                let keys = match self.client.keys(node).await {
+                    Ok(response) => response.keys,
+                    Err(_) => Vec::new(),
+                };
+                self.keys = Some((keys.clone(), Instant::now()));
+                keys
+            }
+        }
+    }
+
+    /// runs one line of input, returns false when the shell should exit
+    pub(crate) async fn execute(&mut self, line: &str) -> Result<bool, Box<dyn Error>> {
+        let result = self.run_command(line).await;
+        // commands that change keys or the node make the completion fetch them again
+        let command = line.split_whitespace().next().unwrap_or_default();
+        if ["set", "del", "purge", "use"].contains(&command) {
+            self.keys = None;
+        }
+        result
+    }
+
+    async fn run_command(&mut self, line: &str) -> Result<bool, Box<dyn Error>> {
+        let mut parts = line.trim().splitn(3, char::is_whitespace);
+        let command = parts.next().unwrap_or_default();
+        let key = parts.next().map(str::trim);
+        let rest = parts.next().map(str::trim);
+
+        match (command, key, rest) {
+            ("", _, _) => {}
+            ("get", Some(key), None) => {
+                let response = self.client.get(self.node(), key, self.consistency).await?;
+                if response.data == Value::Unit {
+                    println!("(nil)");
+                } else {
+                    println!("{}", serde_json::to_string_pretty(&response.data)?);
+                }
+            }
+            ("set", Some(key), Some(value)) => {
+                let value: Value = serde_json::from_str(value)
+                    .map_err(|e| format!("value is not valid json: {}", e))?;
+                self.client
+                    .set(self.node(), key, &value, self.consistency)
+                    .await?;
+                println!("ok");
+            }
+            ("del", Some(key), None) => {
+                let response = self.client.del(self.node(), key).await?;
+                println!("{}", if response.deleted { "deleted" } else { "(nil)" });
+            }
+            ("keys", None, None) => {
+                let mut keys = self.client.keys(self.node()).await?.keys;
+                keys.sort();
+                self.keys = Some((keys.clone(), Instant::now()));
+                for key in keys {
+                    println!("{}", key);
+                }
+            }
+            ("purge", None, None) => {
+                self.client.purge(self.node()).await?;
+                println!("purged");
+            }
+            ("ping", None, None) => {
+                self.client.ping(self.node()).await?;
+                println!("pong");
+            }
+            ("status", None, None) => crate::cli::print_status(self.node(), "table").await?,
+            ("nodes", None, None) => {
+                for (index, node) in self.nodes.iter().enumerate() {
+                    let marker = if index == self.current { "*" } else { " " };
+                    println!("{} {} {}", marker, index, node);
+                }
+            }
+            ("use", Some(node), None) => {
+                self.current = match node.parse::<usize>() {
+                    Ok(index) if index < self.nodes.len() => index,
+                    Ok(_) => return Err(format!("no node with index {}", node).into()),
+                    Err(_) => {
+                        let address: Url = node.parse()?;
+                        match self.nodes.iter().position(|x| x == &address) {
+                            Some(index) => index,
+                            None => {
+                                self.nodes.push(address);
+                                self.nodes.len() - 1
+                            }
+                        }
+                    }
+                };
+            }
+            ("consistency", None, None) => println!("{}", self.consistency),
+            ("consistency", Some(level), None) => self.consistency = level.parse()?,
+            ("help", None, None) => println!("{}", HELP),
+            ("exit", None, None) | ("quit", None, None) => return Ok(false),
+            _ => return Err(format!("invalid command '{}', try 'help'", line.trim()).into()),
+        }
+
+        Ok(true)
+    }
+}
+
+/// interactive prompt that talks to one of the given nodes at a time
+pub async fn run(nodes: Vec<Url>) -> Result<(), Box<dyn Error>> {
+    if nodes.is_empty() {
+        return Err(String::from("no address given").into());
+    }
+
+    let mut editor: Editor<ShellHelper, DefaultHistory> = Editor::new()?;
+    editor.set_helper(Some(ShellHelper {
+        keys: Vec::new(),
+        nodes: nodes.clone(),
+    }));
+    let history = history_path();
+    if let Some(path) = history.as_ref() {
+        // there is no history the first time the shell is started
+        editor.load_history(path).ok();
+    }
+
+    let mut shell = Shell::new(nodes);
+
+    loop {
+        let keys = shell.keys().await;
+        if let Some(helper) = editor.helper_mut() {
+            helper.keys = keys;
+            helper.nodes = shell.nodes.clone();
+        }
+
+        let prompt = format!("racher {}> ", shell.node());
+        let line = match tokio::task::block_in_place(|| editor.readline(&prompt)) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e.into()),
+        };
+        if !line.trim().is_empty() {
+            editor.add_history_entry(line.as_str())?;
+        }
+
+        match shell.execute(&line).await {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(e) => eprintln!("error: {}", e),
+        }
+    }
+
+    if let Some(path) = history.as_ref() {
+        editor.save_history(path)?;
+    }
+    Ok(())
+}
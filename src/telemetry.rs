@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+
+use opentelemetry::propagation::{Extractor, Injector};
+use opentelemetry::sdk::propagation::TraceContextPropagator;
+use opentelemetry::sdk::trace::{self, Tracer};
+use opentelemetry::sdk::Resource;
+use opentelemetry::trace::{TraceError, TracerProvider};
+use opentelemetry::{global, Context, KeyValue};
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use warp::http::header::{HeaderName, HeaderValue};
+use warp::http::HeaderMap;
+
+/// target of the spans that carry the trace context, they are info spans
+pub const TARGET: &str = "racher::trace";
+
+/// w3c trace context (`traceparent` and `tracestate`) of the span that caused a message
+pub type TraceContext = HashMap<String, String>;
+
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|name| name.as_str()).collect()
+    }
+}
+
+/// exports the spans to the otlp collector when an endpoint is given,
+/// otherwise the spans are only used to propagate the trace context
+pub fn tracer(otlp_endpoint: Option<&str>) -> Result<Tracer, TraceError> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    let config =
+        trace::config().with_resource(Resource::new(vec![KeyValue::new("service.name", "racher")]));
+
+    match otlp_endpoint {
+        Some(endpoint) => opentelemetry_otlp::new_pipeline()
+            .with_endpoint(endpoint)
+            .with_trace_config(config)
+            .with_tonic()
+            .install_batch(opentelemetry::runtime::Tokio),
+        None => {
+            let provider = trace::TracerProvider::builder().with_config(config).build();
+            let tracer = provider.get_tracer("racher", None);
+            // the tracer only keeps a weak reference, the global one keeps the provider alive
+            let _ = global::set_tracer_provider(provider);
+            Ok(tracer)
+        }
+    }
+}
+
+/// trace context of the current span
+pub fn current() -> TraceContext {
+    let mut context = TraceContext::new();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&Span::current().context(), &mut context)
+    });
+    context
+}
+
+/// makes the span a child of the given trace context
+pub fn follow(span: &Span, context: &TraceContext) {
+    let parent = global::get_text_map_propagator(|propagator| propagator.extract(context));
+    span.set_parent(parent);
+}
+
+/// makes the span a child of the trace context in the request headers
+pub fn follow_headers(span: &Span, headers: &HeaderMap) {
+    let parent: Context =
+        global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)));
+    span.set_parent(parent);
+}
+
+/// adds the trace context of the current span to outgoing request headers
+pub fn inject_headers(headers: &mut HeaderMap) {
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&Span::current().context(), &mut HeaderInjector(headers))
+    });
+}
+
+/// sends the spans that are still buffered to the collector
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+}
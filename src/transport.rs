@@ -1,15 +1,62 @@
+use crate::metadata::Metadata;
+use crate::telemetry::TraceContext;
+
 use serde_value::Value;
-use tokio::sync::broadcast;
+use tokio::sync::broadcast::{self, error::SendError};
 
-pub type Sender = broadcast::Sender<Message>;
 pub type Receiver = broadcast::Receiver<Message>;
 
+/// changes buffered for grpc watchers, they get every write so they need more room
+/// than the replication
+const WATCH_CAPACITY: usize = 1024;
+
+/// local writes go to the replication, every applied write goes to the watchers.
+/// writes from other nodes are only for the watchers, so they cannot crowd out local
+/// writes that still have to be replicated
+#[derive(Debug, Clone)]
+pub struct Sender {
+    replication: broadcast::Sender<Message>,
+    watchers: broadcast::Sender<Message>,
+}
+
+impl Sender {
+    #[allow(clippy::result_large_err)]
+    pub fn send(&self, message: Message) -> Result<usize, SendError<Message>> {
+        if self.watchers.receiver_count() > 0 {
+            self.watchers.send(message.clone()).ok();
+        }
+        match message {
+            Message::Updated(..) => Ok(0),
+            message => self.replication.send(message),
+        }
+    }
+
+    /// the local writes that still have to be replicated
+    pub fn subscribe(&self) -> Receiver {
+        self.replication.subscribe()
+    }
+
+    /// every write applied on this node, local or replicated
+    pub fn watch(&self) -> Receiver {
+        self.watchers.subscribe()
+    }
+}
+
 pub fn channel(size: usize) -> (Sender, Receiver) {
-    tokio::sync::broadcast::channel(size)
+    let (replication, receiver) = broadcast::channel(size);
+    let (watchers, _) = broadcast::channel(WATCH_CAPACITY);
+    let sender = Sender {
+        replication,
+        watchers,
+    };
+    (sender, receiver)
 }
 
 #[derive(Debug, Clone)]
 pub enum Message {
-    Created(String, Value),
-    Deleted(String),
+    /// key, value, metadata and the trace context of the request that set it
+    Created(String, Value, Metadata, TraceContext),
+    Deleted(String, u64, TraceContext),
+    /// a write from another node that was applied here, it is not replicated again
+    Updated(String, Value, Metadata),
 }